@@ -0,0 +1,172 @@
+use chrono::{DateTime, Local, NaiveDate};
+
+use std::collections::{HashMap, HashSet};
+
+use crate::Event;
+
+/// Longest span, in days, an event is compared over. Longer events only match on their
+/// first month.
+const MAX_DAYS: usize = 31;
+
+/// Which events `dedup` treats as the same
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DedupOptions {
+    /// Events with the same id are the same
+    pub by_id: bool,
+    /// Events whose times overlap and whose titles are at least this similar, from 0 to 1,
+    /// are the same. `None` only matches by id.
+    pub title_similarity: Option<f64>,
+}
+
+impl Default for DedupOptions {
+    fn default() -> Self {
+        Self {
+            by_id: true,
+            title_similarity: Some(0.8),
+        }
+    }
+}
+
+/// Lowercases `title` and keeps only its words, so punctuation and spacing don't matter
+fn normalize(title: &str) -> String {
+    title
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(str::to_lowercase)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// The sorted character pairs of `s`
+fn bigrams(s: &str) -> Vec<(char, char)> {
+    let chars: Vec<_> = s.chars().collect();
+    let mut out: Vec<_> = chars.windows(2).map(|w| (w[0], w[1])).collect();
+    out.sort_unstable();
+    out
+}
+
+/// Dice coefficient of two sorted bigram lists, 1 for identical titles
+fn similarity(a: &Title, b: &Title) -> f64 {
+    if a.normalized == b.normalized {
+        return 1.0;
+    }
+    if a.bigrams.is_empty() || b.bigrams.is_empty() {
+        return 0.0;
+    }
+
+    let (mut i, mut j, mut shared) = (0, 0, 0);
+    while i < a.bigrams.len() && j < b.bigrams.len() {
+        match a.bigrams[i].cmp(&b.bigrams[j]) {
+            std::cmp::Ordering::Less => i += 1,
+            std::cmp::Ordering::Greater => j += 1,
+            std::cmp::Ordering::Equal => {
+                shared += 1;
+                i += 1;
+                j += 1;
+            }
+        }
+    }
+
+    2.0 * shared as f64 / (a.bigrams.len() + b.bigrams.len()) as f64
+}
+
+/// A title prepared for comparison
+struct Title {
+    normalized: String,
+    bigrams: Vec<(char, char)>,
+}
+
+/// What a kept event is compared by
+struct Kept {
+    title: Title,
+    start: DateTime<Local>,
+    end: DateTime<Local>,
+}
+
+/// When `event` starts and ends, events without an end take no time
+fn span(event: &Event) -> Option<(DateTime<Local>, DateTime<Local>)> {
+    let start = event.start?.instant();
+    let end = event.end.map(|e| e.instant()).unwrap_or(start).max(start);
+    Some((start, end))
+}
+
+/// The days from `start` to `end` that an event touches
+fn days(start: DateTime<Local>, end: DateTime<Local>) -> impl Iterator<Item = NaiveDate> {
+    let last = end.date_naive();
+    start
+        .date_naive()
+        .iter_days()
+        .take_while(move |d| *d <= last)
+        .take(MAX_DAYS)
+}
+
+/// Whether the spans overlap. Events without a length overlap ones they fall in.
+fn overlaps(a: (DateTime<Local>, DateTime<Local>), b: (DateTime<Local>, DateTime<Local>)) -> bool {
+    a.0 == b.0 || (a.0 < b.1 && b.0 < a.1)
+}
+
+/// Drops events that are already in an earlier list, so the version from the earliest list
+/// wins. Events are only compared with events of other lists.
+/// Returns the events left in each list, in their original order.
+pub fn dedup(lists: &[&[Event]], options: &DedupOptions) -> Vec<Vec<Event>> {
+    let mut ids = HashSet::<&str>::new();
+    let mut kept = Vec::<Kept>::new();
+    let mut by_day = HashMap::<NaiveDate, Vec<usize>>::new();
+    let mut out = Vec::with_capacity(lists.len());
+
+    for list in lists {
+        let mut new_ids = Vec::new();
+        let mut new_kept = Vec::new();
+        let mut events = Vec::new();
+
+        for e in list.iter() {
+            let id = e.id.as_deref().filter(|_| options.by_id);
+            if id.is_some_and(|id| ids.contains(id)) {
+                log::debug!("Dropping {} from {:?}, same id", e.title, e.source);
+                continue;
+            }
+
+            let fuzzy = options.title_similarity.zip(span(e)).map(|(min, span)| {
+                let normalized = normalize(&e.title);
+                let title = Title {
+                    bigrams: bigrams(&normalized),
+                    normalized,
+                };
+                (min, span, title)
+            });
+
+            if let Some((min, span, title)) = &fuzzy {
+                let duplicate = days(span.0, span.1)
+                    .flat_map(|d| by_day.get(&d).into_iter().flatten())
+                    .any(|&k| {
+                        let other = &kept[k];
+                        overlaps(*span, (other.start, other.end))
+                            && similarity(title, &other.title) >= *min
+                    });
+                if duplicate {
+                    log::debug!("Dropping {} from {:?}, similar event", e.title, e.source);
+                    continue;
+                }
+            }
+
+            new_ids.extend(id);
+            if let Some((_, (start, end), title)) = fuzzy {
+                new_kept.push(Kept { title, start, end });
+            }
+            events.push(e.clone());
+        }
+
+        // Added only now, so events of the same list never match each other
+        ids.extend(new_ids);
+        for k in new_kept {
+            for d in days(k.start, k.end) {
+                by_day.entry(d).or_default().push(kept.len());
+            }
+            kept.push(k);
+        }
+
+        out.push(events);
+    }
+
+    out
+}
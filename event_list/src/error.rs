@@ -0,0 +1,66 @@
+use std::fmt::Display;
+
+/// Reasons an `EventList` can fail to produce events
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EventListError {
+    /// The source could not be reached or answered with an error status
+    Network(String),
+    /// The source answered with data that could not be understood
+    Parse(String),
+    /// The source rejected the credentials it was given
+    Auth(String),
+    /// The source did not answer in time
+    Timeout,
+    /// The source has no event with the given id
+    NotFound(String),
+    /// The user hasn't signed in to the source yet
+    Unauthenticated(String),
+    /// A local file couldn't be read or written
+    Io(String),
+}
+
+impl Display for EventListError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EventListError::Network(e) => write!(f, "Network error: {e}"),
+            EventListError::Parse(e) => write!(f, "Parse error: {e}"),
+            EventListError::Auth(e) => write!(f, "Authentication error: {e}"),
+            EventListError::Timeout => write!(f, "Timed out"),
+            EventListError::NotFound(id) => write!(f, "No event with id {id}"),
+            EventListError::Unauthenticated(e) => write!(f, "Not signed in: {e}"),
+            EventListError::Io(e) => write!(f, "File error: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for EventListError {}
+
+impl From<reqwest::Error> for EventListError {
+    /// Drops the url from the message, its query may hold an access token
+    fn from(e: reqwest::Error) -> Self {
+        let e = e.without_url();
+        match e.status() {
+            _ if e.is_timeout() => EventListError::Timeout,
+            Some(s)
+                if s == reqwest::StatusCode::UNAUTHORIZED
+                    || s == reqwest::StatusCode::FORBIDDEN =>
+            {
+                EventListError::Auth(e.to_string())
+            }
+            _ if e.is_decode() => EventListError::Parse(e.to_string()),
+            _ => EventListError::Network(e.to_string()),
+        }
+    }
+}
+
+impl From<std::string::FromUtf8Error> for EventListError {
+    fn from(e: std::string::FromUtf8Error) -> Self {
+        EventListError::Parse(e.to_string())
+    }
+}
+
+impl From<serde_json::Error> for EventListError {
+    fn from(e: serde_json::Error) -> Self {
+        EventListError::Parse(e.to_string())
+    }
+}
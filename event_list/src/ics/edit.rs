@@ -0,0 +1,297 @@
+use chrono::{Duration, Local, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc};
+use chrono_tz::Tz;
+
+use std::ops::Range;
+
+use super::{
+    tz::Zone,
+    write::{push_event, push_folded, stamp},
+};
+use crate::{Event, EventListError, EventTime};
+
+/// Properties of a series that make up its recurrence set
+const RECURRENCE_PROPERTIES: [&str; 4] = ["RRULE", "RDATE", "EXDATE", "EXRULE"];
+
+/// Whether the calendar object in `body` holds the event with `id`, or the series that `id`
+/// is an occurrence of
+pub(crate) fn holds(body: &str, id: &str) -> bool {
+    super::find_uid(body).is_some_and(|uid| {
+        id == uid
+            || id
+                .strip_prefix(uid)
+                .is_some_and(|rest| rest.starts_with('/'))
+    })
+}
+
+/// The content lines of `body`, with folded lines joined again
+fn unfold(body: &str) -> Vec<String> {
+    let mut out: Vec<String> = Vec::new();
+
+    for line in body.lines() {
+        match (line.strip_prefix([' ', '\t']), out.last_mut()) {
+            (Some(rest), Some(last)) => last.push_str(rest),
+            _ if line.is_empty() => {}
+            _ => out.push(line.to_string()),
+        }
+    }
+
+    out
+}
+
+/// The name of the property on `line`, uppercased
+fn name(line: &str) -> String {
+    line.split([';', ':'])
+        .next()
+        .unwrap_or_default()
+        .to_ascii_uppercase()
+}
+
+/// The `SEQUENCE` of the `VEVENT` made of `lines`
+fn sequence(lines: &[String]) -> u32 {
+    lines
+        .iter()
+        .find(|l| name(l) == "SEQUENCE")
+        .and_then(|l| l.split_once(':')?.1.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+/// The lines of each `VEVENT` in `lines`, from its `BEGIN` to its `END`
+fn vevents(lines: &[String]) -> Vec<Range<usize>> {
+    let mut out = Vec::new();
+    let mut begin = None;
+
+    for (i, line) in lines.iter().enumerate() {
+        if line.trim_end().eq_ignore_ascii_case("BEGIN:VEVENT") {
+            begin = Some(i);
+        } else if line.trim_end().eq_ignore_ascii_case("END:VEVENT") {
+            if let Some(begin) = begin.take() {
+                out.push(begin..i + 1);
+            }
+        }
+    }
+
+    out
+}
+
+/// `event` as the unfolded lines of a `VEVENT` with `uid`
+fn event_lines(event: &Event, uid: &str) -> Vec<String> {
+    let mut out = String::new();
+    push_event(&mut out, event, uid, &stamp());
+    unfold(&out)
+}
+
+/// Writes the `DTSTART` and `DTEND` of `new` as wall clock times in `zone`, the zone of the
+/// `DTSTART` in `master`, with the same `TZID`. The recurrence set of `master` is expanded in
+/// that zone, so UTC times would move its occurrences across DST changes.
+fn keep_zone(new: &mut [String], master: &[String], zone: &Zone, event: &Event) {
+    let Some((params, value)) = master
+        .iter()
+        .find(|l| name(l) == "DTSTART")
+        .and_then(|l| l.split_once(':'))
+    else {
+        return;
+    };
+    // UTC times already match, and dates have no zone
+    if value.trim().ends_with('Z') || !value.contains('T') {
+        return;
+    }
+    let params = &params["DTSTART".len()..];
+
+    for (property, time) in [("DTSTART", event.start), ("DTEND", event.end)] {
+        let line = new.iter_mut().find(|l| name(l) == property);
+        if let (Some(line), Some(EventTime::DateTime(d))) = (line, time) {
+            *line = format!(
+                "{property}{params}:{}",
+                zone.wall_time(d).format("%Y%m%dT%H%M%S")
+            );
+        }
+    }
+}
+
+/// A stored calendar object holding one event or series, split into lines
+struct Object<'a> {
+    body: &'a str,
+    lines: Vec<String>,
+    /// The lines of each `VEVENT` and the id of the event or override it holds
+    events: Vec<(Range<usize>, Option<String>)>,
+    /// The zone of the `DTSTART` of each of `events`
+    zones: Vec<Zone>,
+    uid: String,
+}
+
+impl<'a> Object<'a> {
+    /// Splits `body`, which has to hold `id`
+    fn parse(body: &'a str, zone: Option<Tz>, id: &str) -> Result<Self, EventListError> {
+        let uid = super::find_uid(body)
+            .filter(|_| holds(body, id))
+            .ok_or_else(|| EventListError::NotFound(id.to_string()))?
+            .to_string();
+        let lines = unfold(body);
+        let ranges = vevents(&lines);
+        let (ids, zones): (Vec<_>, Vec<_>) = super::component_ids(body, zone)?.into_iter().unzip();
+        if ranges.len() != ids.len() {
+            return Err(EventListError::Parse(format!("Unbalanced VEVENT in {uid}")));
+        }
+
+        Ok(Self {
+            body,
+            lines,
+            events: ranges.into_iter().zip(ids).collect(),
+            zones,
+            uid,
+        })
+    }
+
+    fn is_master(&self, id: &Option<String>) -> bool {
+        id.as_deref() == Some(self.uid.as_str())
+    }
+
+    /// The `RECURRENCE-ID` property naming the occurrence with `id`, which has to exist
+    fn recurrence_id(&self, id: &str, zone: Option<Tz>) -> Result<String, EventListError> {
+        let not_found = || EventListError::NotFound(id.to_string());
+        let suffix = id
+            .strip_prefix(self.uid.as_str())
+            .and_then(|rest| rest.strip_prefix('/'))
+            .ok_or_else(not_found)?;
+
+        let (property, start) = if let Ok(d) = NaiveDate::parse_from_str(suffix, "%Y%m%d") {
+            let start = Utc.from_utc_datetime(&d.and_time(NaiveTime::MIN));
+            (format!("RECURRENCE-ID;VALUE=DATE:{suffix}"), start)
+        } else if let Ok(d) = NaiveDateTime::parse_from_str(suffix, "%Y%m%dT%H%M%SZ") {
+            (format!("RECURRENCE-ID:{suffix}"), Utc.from_utc_datetime(&d))
+        } else {
+            return Err(not_found());
+        };
+
+        // Overrides may have moved the occurrence anywhere, the others are found by expanding
+        // the series around where it starts
+        let start = start.with_timezone(&Local);
+        let exists = self.events.iter().any(|(_, e)| e.as_deref() == Some(id))
+            || super::parse_events_in(
+                self.body,
+                start - Duration::days(2),
+                start + Duration::days(2),
+                zone,
+            )?
+            .iter()
+            .any(|e| e.id.as_deref() == Some(id));
+
+        exists.then_some(property).ok_or_else(not_found)
+    }
+
+    /// The object with each `VEVENT` replaced by what `f` returns for its id and lines
+    fn rewrite(&self, mut f: impl FnMut(&Option<String>, &[String]) -> Vec<String>) -> String {
+        let mut lines = Vec::new();
+        let mut next = 0;
+
+        for (range, id) in &self.events {
+            lines.extend_from_slice(&self.lines[next..range.start]);
+            lines.extend(f(id, &self.lines[range.clone()]));
+            next = range.end;
+        }
+        lines.extend_from_slice(&self.lines[next..]);
+
+        let mut out = String::new();
+        for line in lines {
+            push_folded(&mut out, &line);
+        }
+        out
+    }
+}
+
+/// The calendar object in `body` with the event `id` replaced by `event`.
+/// Updating a series keeps its recurrence set and overridden occurrences, updating an
+/// occurrence overrides just that one.
+pub(crate) fn update(
+    body: &str,
+    zone: Option<Tz>,
+    id: &str,
+    event: &Event,
+) -> Result<String, EventListError> {
+    let object = Object::parse(body, zone, id)?;
+    let mut new = event_lines(event, &object.uid);
+    let end = new.len() - 1;
+
+    if id == object.uid {
+        // The master with the highest `SEQUENCE` wins, as when reading
+        let master = object
+            .events
+            .iter()
+            .zip(&object.zones)
+            .filter(|((_, e), _)| object.is_master(e))
+            .map(|((range, _), zone)| (&object.lines[range.clone()], zone))
+            .max_by_key(|(lines, _)| sequence(lines));
+
+        if let Some((lines, zone)) = master {
+            keep_zone(&mut new, lines, zone, event);
+            let recurrence = lines
+                .iter()
+                .filter(|l| RECURRENCE_PROPERTIES.contains(&name(l).as_str()))
+                .cloned();
+            new.splice(end..end, recurrence);
+        }
+
+        let mut replaced = false;
+        return Ok(object.rewrite(|e, lines| match e {
+            e if object.is_master(e) && !replaced => {
+                replaced = true;
+                new.clone()
+            }
+            e if object.is_master(e) => Vec::new(),
+            _ => lines.to_vec(),
+        }));
+    }
+
+    // Right after `UID`
+    new.insert(2, object.recurrence_id(id, zone)?);
+    let overridden = object.events.iter().any(|(_, e)| e.as_deref() == Some(id));
+
+    let mut replaced = false;
+    Ok(object.rewrite(|e, lines| match e {
+        e if e.as_deref() == Some(id) && !replaced => {
+            replaced = true;
+            new.clone()
+        }
+        e if e.as_deref() == Some(id) => Vec::new(),
+        e if object.is_master(e) && !overridden && !replaced => {
+            replaced = true;
+            lines.iter().chain(&new).cloned().collect()
+        }
+        _ => lines.to_vec(),
+    }))
+}
+
+/// The calendar object in `body` without the event `id`, `None` if nothing is left of it.
+/// Deleting an occurrence excludes it from its series.
+pub(crate) fn delete(
+    body: &str,
+    zone: Option<Tz>,
+    id: &str,
+) -> Result<Option<String>, EventListError> {
+    let object = Object::parse(body, zone, id)?;
+    if id == object.uid {
+        return Ok(None);
+    }
+
+    let exdate = object
+        .recurrence_id(id, zone)?
+        .replacen("RECURRENCE-ID", "EXDATE", 1);
+    let mut left = 0;
+
+    let out = object.rewrite(|e, lines| match e {
+        e if e.as_deref() == Some(id) => Vec::new(),
+        e if object.is_master(e) => {
+            left += 1;
+            let end = lines.len() - 1;
+            let mut lines = lines.to_vec();
+            lines.insert(end, exdate.clone());
+            lines
+        }
+        _ => {
+            left += 1;
+            lines.to_vec()
+        }
+    });
+
+    Ok((left > 0).then_some(out))
+}
@@ -0,0 +1,551 @@
+pub(crate) mod edit;
+pub mod rrule;
+pub mod tz;
+pub mod write;
+
+use crate::{Event, EventListError, EventStatus, EventTime, Person};
+
+use chrono::{
+    DateTime, Duration, Local, NaiveDate, NaiveDateTime, NaiveTime, ParseResult, TimeZone, Utc,
+};
+
+use chrono_tz::Tz;
+use ical::{property::Property, IcalParser};
+use std::{
+    collections::{HashMap, HashSet},
+    io::BufReader,
+};
+
+use rrule::RRule;
+use tz::{TimeZones, Zone};
+
+/// Parses a DATE or DATE-TIME value into the wall clock time it names
+fn parse_naive(s: &str) -> ParseResult<NaiveDateTime> {
+    let s = s.trim_end_matches('Z');
+    match NaiveDateTime::parse_from_str(s, "%Y%m%dT%H%M%S") {
+        Ok(d) => Ok(d),
+        Err(_) => Ok(NaiveDate::parse_from_str(s, "%Y%m%d")?
+            .and_time(NaiveTime::from_hms_opt(0, 0, 0).expect("Valid Time"))),
+    }
+}
+
+/// Parses a DATE or DATE-TIME value that is expressed in `zone`
+pub fn parse_from_iso8601(s: &str, zone: &Zone) -> ParseResult<DateTime<Local>> {
+    Ok(zone.instant(parse_naive(s)?))
+}
+
+/// The zone of the date value `s`: UTC when it ends in `Z`, otherwise the zone
+/// named by `tzid` or the floating zone of `zones`
+fn value_zone(s: &str, tzid: Option<&str>, zones: &TimeZones) -> Zone {
+    match tzid {
+        _ if s.ends_with('Z') => Zone::Utc,
+        Some(id) => zones.resolve(id),
+        None => zones.floating(),
+    }
+}
+
+/// The first value of the parameter `name` on `prop`
+fn param<'a>(prop: &'a Property, name: &str) -> Option<&'a str> {
+    prop.params
+        .as_ref()?
+        .iter()
+        .find(|(n, _)| n == name)?
+        .1
+        .first()
+        .map(String::as_str)
+}
+
+/// Parses the date value `s` of `prop`, returning the time and the zone it was given in.
+/// `VALUE=DATE` values become all-day `EventTime::Date`s.
+fn parse_date(prop: &Property, s: &str, zones: &TimeZones) -> Option<(EventTime, Zone)> {
+    if param(prop, "VALUE") == Some("DATE") || (s.len() == 8 && !s.contains('T')) {
+        return match NaiveDate::parse_from_str(s, "%Y%m%d") {
+            Ok(d) => Some((EventTime::Date(d), Zone::Floating)),
+            Err(e) => {
+                log::error!("Date Parse error {e:?} {s}");
+                None
+            }
+        };
+    }
+
+    let zone = value_zone(s, param(prop, "TZID"), zones);
+    match parse_from_iso8601(s, &zone) {
+        Ok(d) => Some((EventTime::DateTime(d), zone)),
+        Err(e) => {
+            log::error!("Date Parse error {e:?} {s}");
+            None
+        }
+    }
+}
+
+/// Parses an RFC 5545 duration like `PT1H30M`, `P1D` or `-P2W`
+fn parse_duration(s: &str) -> Option<Duration> {
+    let s = s.trim();
+    let (negative, s) = match s.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, s.strip_prefix('+').unwrap_or(s)),
+    };
+    let mut rest = s.strip_prefix('P')?;
+
+    let mut out = Duration::zero();
+    let mut time = false;
+    let mut parts = 0;
+    while !rest.is_empty() {
+        if let Some(r) = rest.strip_prefix('T').filter(|_| !time) {
+            time = true;
+            rest = r;
+            continue;
+        }
+
+        let digits = rest.find(|c: char| !c.is_ascii_digit())?;
+        let n: i64 = rest[..digits].parse().ok()?;
+        let unit = rest[digits..].chars().next()?;
+        out += match (time, unit) {
+            (false, 'W') => Duration::weeks(n),
+            (false, 'D') => Duration::days(n),
+            (true, 'H') => Duration::hours(n),
+            (true, 'M') => Duration::minutes(n),
+            (true, 'S') => Duration::seconds(n),
+            _ => return None,
+        };
+        rest = &rest[digits + unit.len_utf8()..];
+        parts += 1;
+    }
+
+    (parts > 0).then_some(if negative { -out } else { out })
+}
+
+/// The length of an event, counted in whole days for all-day events
+fn length(start: EventTime, end: EventTime) -> Duration {
+    match (start, end) {
+        (EventTime::Date(s), EventTime::Date(e)) => e - s,
+        _ => end.instant() - start.instant(),
+    }
+}
+
+/// The `UID` of the first component in `body`, found without parsing the whole calendar
+pub(crate) fn find_uid(body: &str) -> Option<&str> {
+    body.lines().find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        let name = name.split(';').next()?;
+        name.eq_ignore_ascii_case("UID").then(|| value.trim())
+    })
+}
+
+fn unescape_ical(s: &str) -> String {
+    s.replace("\\,", ",")
+        .replace("\\\\", "\\")
+        .replace("\\n", "\n")
+        .replace("\\;", ";")
+}
+
+/// The id of the occurrence of the event with `uid` that originally started at `start`
+fn occurrence_id(uid: &str, start: EventTime) -> String {
+    match start {
+        EventTime::Date(d) => format!("{uid}/{}", d.format("%Y%m%d")),
+        EventTime::DateTime(d) => {
+            format!("{uid}/{}", d.with_timezone(&Utc).format("%Y%m%dT%H%M%SZ"))
+        }
+    }
+}
+
+/// Splits a comma separated list of TEXT values, as used by `CATEGORIES`
+fn split_text_list(s: &str) -> Vec<String> {
+    let mut out = Vec::new();
+    let mut current = String::new();
+    let mut escaped = false;
+
+    for c in s.chars() {
+        if c == ',' && !escaped {
+            out.push(unescape_ical(&std::mem::take(&mut current)));
+        } else {
+            current.push(c);
+        }
+        escaped = c == '\\' && !escaped;
+    }
+    out.push(unescape_ical(&current));
+
+    out.retain(|v| !v.trim().is_empty());
+    out
+}
+
+/// The person named by an `ORGANIZER` or `ATTENDEE` property
+fn person(prop: &Property) -> Person {
+    let email = prop.value.as_deref().map(|v| {
+        match v.get(..7).filter(|p| p.eq_ignore_ascii_case("mailto:")) {
+            Some(_) => v[7..].to_string(),
+            None => v.to_string(),
+        }
+    });
+
+    Person {
+        name: param(prop, "CN").map(|n| n.trim_matches('"').to_string()),
+        email,
+        response: param(prop, "PARTSTAT").map(str::to_ascii_lowercase),
+    }
+}
+
+/// A `VEVENT` before its recurrences are expanded
+struct Component {
+    event: Event,
+    /// Zone of `DTSTART`, which recurrences are expanded in
+    zone: Zone,
+    uid: Option<String>,
+    sequence: u32,
+    recurrence_id: Option<EventTime>,
+    rrule: Option<RRule>,
+    /// Extra occurrences from `RDATE`, with an explicit end for `PERIOD` values
+    rdates: Vec<(EventTime, Option<EventTime>)>,
+    exdates: Vec<DateTime<Local>>,
+}
+
+impl Component {
+    /// Expands the recurrence set into every occurrence that starts before `before`.
+    /// Occurrences in `overridden` have been replaced by a `RECURRENCE-ID` component.
+    fn expand(self, before: DateTime<Local>, overridden: &HashSet<DateTime<Local>>) -> Vec<Event> {
+        let Some(start) = self.event.start else {
+            return vec![self.event];
+        };
+
+        let length = self.event.end.map(|end| length(start, end));
+        let recurring = self.rrule.is_some() || !self.rdates.is_empty();
+
+        let mut occurrences: Vec<_> = match &self.rrule {
+            Some(rrule) => {
+                let wall = match start {
+                    EventTime::Date(d) => d.and_time(NaiveTime::MIN),
+                    EventTime::DateTime(d) => self.zone.wall_time(d),
+                };
+
+                rrule
+                    .occurrences(wall)
+                    .map(|wall| match start {
+                        EventTime::Date(_) => EventTime::Date(wall.date()),
+                        EventTime::DateTime(_) => self.zone.instant(wall).into(),
+                    })
+                    .take_while(|s| s.instant() < before)
+                    .map(|s| (s, length.map(|l| s + l)))
+                    .collect()
+            }
+            None => vec![(start, self.event.end)],
+        };
+
+        occurrences.extend(
+            self.rdates
+                .iter()
+                .filter(|(s, _)| s.instant() < before)
+                .map(|&(s, e)| (s, e.or_else(|| length.map(|l| s + l)))),
+        );
+        occurrences.sort_by_key(|(s, _)| *s);
+        occurrences.dedup_by_key(|(s, _)| *s);
+
+        occurrences
+            .into_iter()
+            .filter(|(s, _)| {
+                !self.exdates.contains(&s.instant()) && !overridden.contains(&s.instant())
+            })
+            .map(|(s, e)| Event {
+                start: Some(s),
+                end: e,
+                id: match &self.uid {
+                    Some(uid) if recurring => Some(occurrence_id(uid, s)),
+                    _ => self.event.id.clone(),
+                },
+                series_id: self.uid.clone().filter(|_| recurring),
+                ..self.event.clone()
+            })
+            .collect()
+    }
+}
+
+/// Parses a comma separated list of date or date-time values, as used by `EXDATE` and `RDATE`
+fn parse_date_list(
+    prop: &Property,
+    s: &str,
+    zones: &TimeZones,
+) -> Vec<(EventTime, Option<EventTime>)> {
+    s.split(',')
+        .filter_map(|v| {
+            let (start, end) = match v.split_once('/') {
+                Some((start, end)) => (start, Some(end)),
+                None => (v, None),
+            };
+
+            let (start, _) = parse_date(prop, start, zones)?;
+            let end = end.and_then(|e| match parse_duration(e) {
+                Some(length) => Some(start + length),
+                None => parse_date(prop, e, zones).map(|(d, _)| d),
+            });
+
+            Some((start, end))
+        })
+        .collect()
+}
+
+fn parse_component(e: &ical::parser::ical::component::IcalEvent, zones: &TimeZones) -> Component {
+    let mut out = Component {
+        event: Event::default(),
+        zone: Zone::Floating,
+        uid: None,
+        sequence: 0,
+        recurrence_id: None,
+        rrule: None,
+        rdates: Vec::new(),
+        exdates: Vec::new(),
+    };
+    let mut duration = None;
+
+    for prop in &e.properties {
+        match prop.name.as_str() {
+            "DESCRIPTION" => {
+                out.event.desc =
+                    unescape_ical(prop.value.as_deref().unwrap_or_default()).replace("\\n", "\n");
+            }
+            "SUMMARY" => {
+                out.event.title =
+                    unescape_ical(prop.value.as_deref().unwrap_or_default()).replace("\\n", "\n");
+            }
+            "DTSTART" => {
+                if let Some((start, zone)) =
+                    prop.value.as_ref().and_then(|s| parse_date(prop, s, zones))
+                {
+                    out.event.start = Some(start);
+                    out.zone = zone;
+                }
+            }
+            "DTEND" => {
+                out.event.end = prop
+                    .value
+                    .as_ref()
+                    .and_then(|s| parse_date(prop, s, zones))
+                    .map(|(d, _)| d);
+            }
+            "DTSTAMP" | "CLASS" | "CREATED" | "PRIORITY" | "GEO" | "ATTACH" | "COMMENT"
+            | "CONTACT" | "RELATED-TO" | "RESOURCES" => {}
+            "DURATION" => {
+                duration = prop.value.as_deref().and_then(|s| {
+                    let d = parse_duration(s);
+                    if d.is_none() {
+                        log::error!("Duration parse error {s}");
+                    }
+                    d
+                });
+            }
+            "STATUS" => {
+                out.event.status = prop.value.as_deref().and_then(EventStatus::parse);
+            }
+            "TRANSP" => {
+                out.event.transparent = prop
+                    .value
+                    .as_deref()
+                    .is_some_and(|v| v.trim().eq_ignore_ascii_case("TRANSPARENT"));
+            }
+            "ORGANIZER" => out.event.organizer = Some(person(prop)),
+            "ATTENDEE" => out.event.attendees.push(person(prop)),
+            "CATEGORIES" => {
+                if let Some(s) = &prop.value {
+                    out.event.categories.extend(split_text_list(s));
+                }
+            }
+            "COLOR" => out.event.color = prop.value.clone(),
+            "UID" => out.uid = prop.value.clone(),
+            "SEQUENCE" => {
+                out.sequence = prop
+                    .value
+                    .as_ref()
+                    .and_then(|s| s.trim().parse().ok())
+                    .unwrap_or(0);
+            }
+            "RECURRENCE-ID" => {
+                out.recurrence_id = prop
+                    .value
+                    .as_ref()
+                    .and_then(|s| parse_date(prop, s, zones))
+                    .map(|(d, _)| d);
+            }
+            "EXDATE" => {
+                if let Some(s) = &prop.value {
+                    out.exdates.extend(
+                        parse_date_list(prop, s, zones)
+                            .into_iter()
+                            .map(|(d, _)| d.instant()),
+                    );
+                }
+            }
+            "RDATE" => {
+                if let Some(s) = &prop.value {
+                    out.rdates.extend(parse_date_list(prop, s, zones));
+                }
+            }
+            "LAST-MODIFIED" => {
+                out.event.last_modified = prop
+                    .value
+                    .as_ref()
+                    .and_then(|s| parse_from_iso8601(s, &Zone::Utc).ok())
+                    .map(|d| d.with_timezone(&Utc));
+            }
+            "URL" => out.event.url = prop.value.clone(),
+            "LOCATION" => {
+                out.event.location = prop.value.as_deref().map(unescape_ical);
+            }
+            "RRULE" => {
+                out.rrule = prop.value.as_ref().and_then(|s| match s.parse() {
+                    Ok(r) => Some(r),
+                    Err(e) => {
+                        log::error!("{e} {s}");
+                        None
+                    }
+                });
+            }
+            e if e.starts_with("X-") => log::debug!("Ignoring extension property {e}"),
+            e => log::warn!("Ignoring unknown property {e}"),
+        }
+    }
+
+    // `DURATION` instead of `DTEND`, in whole days for all-day events
+    if let (Some(start), None, Some(duration)) = (out.event.start, out.event.end, duration) {
+        out.event.end = Some(start + duration);
+    }
+
+    // All-day events without an end last for one day
+    if let (Some(start @ EventTime::Date(_)), None) = (out.event.start, out.event.end) {
+        out.event.end = Some(start + Duration::days(1));
+    }
+
+    out.event.id = match (&out.uid, out.recurrence_id) {
+        (Some(uid), Some(id)) => Some(occurrence_id(uid, id)),
+        (uid, _) => uid.clone(),
+    };
+    if out.recurrence_id.is_some() {
+        out.event.series_id = out.uid.clone();
+    }
+
+    // A UTC `UNTIL` has to be compared against occurrences in the wall clock of `DTSTART`
+    if let Some(rrule) = out.rrule.as_mut().filter(|r| r.until_utc) {
+        rrule.until = rrule.until.map(|u| {
+            out.zone
+                .wall_time(Utc.from_utc_datetime(&u).with_timezone(&Local))
+        });
+        rrule.until_utc = false;
+    }
+
+    out
+}
+
+/// Merges the components of one calendar into its final list of events.
+/// Components sharing a `UID` are reconciled: the highest `SEQUENCE` of each master or
+/// override wins, and overrides replace the occurrence named by their `RECURRENCE-ID`.
+fn reconcile(components: Vec<Component>, before: DateTime<Local>) -> Vec<Event> {
+    let mut loose = Vec::new();
+    let mut masters = HashMap::<String, Component>::new();
+    let mut overrides = HashMap::<(String, DateTime<Local>), Component>::new();
+
+    for c in components {
+        match (c.uid.clone(), c.recurrence_id.map(|id| id.instant())) {
+            (None, _) => loose.push(c),
+            (Some(uid), None) => match masters.get(&uid) {
+                Some(old) if old.sequence > c.sequence => {}
+                _ => {
+                    masters.insert(uid, c);
+                }
+            },
+            (Some(uid), Some(id)) => match overrides.get(&(uid.clone(), id)) {
+                Some(old) if old.sequence > c.sequence => {}
+                _ => {
+                    overrides.insert((uid, id), c);
+                }
+            },
+        }
+    }
+
+    let mut overridden = HashMap::<String, HashSet<DateTime<Local>>>::new();
+    for (uid, id) in overrides.keys() {
+        overridden.entry(uid.clone()).or_default().insert(*id);
+    }
+
+    let none = HashSet::new();
+    let mut out: Vec<_> = masters
+        .into_iter()
+        .flat_map(|(uid, c)| c.expand(before, overridden.get(&uid).unwrap_or(&none)))
+        .chain(loose.into_iter().flat_map(|c| c.expand(before, &none)))
+        // Moved occurrences count where they start now, like the others
+        .chain(
+            overrides
+                .into_values()
+                .map(|c| c.event)
+                .filter(|e| e.start.is_none_or(|s| s.instant() < before)),
+        )
+        .collect();
+
+    out.sort_by_key(|e| e.start);
+
+    out
+}
+
+/// Parses the iCalendar data in `body` into events, expanding recurring events into
+/// one `Event` per occurrence that overlaps the window from `after` to `before`.
+/// Fails if `body` is not iCalendar data, e.g. the login page of an expired feed link.
+pub fn parse_events(
+    body: &str,
+    after: DateTime<Local>,
+    before: DateTime<Local>,
+) -> Result<Vec<Event>, EventListError> {
+    parse_events_in(body, after, before, None)
+}
+
+/// Like `parse_events`, but times that don't name a zone are in `zone` instead of local time
+pub fn parse_events_in(
+    body: &str,
+    after: DateTime<Local>,
+    before: DateTime<Local>,
+    zone: Option<Tz>,
+) -> Result<Vec<Event>, EventListError> {
+    Ok(parse_calendars(body, zone)?
+        .into_iter()
+        .flat_map(|components| reconcile(components, before))
+        .filter(|e| e.end.or(e.start).is_some_and(|end| end.instant() >= after))
+        .collect())
+}
+
+/// The components of each `VCALENDAR` in `body`, in the order they appear
+fn parse_calendars(body: &str, zone: Option<Tz>) -> Result<Vec<Vec<Component>>, EventListError> {
+    let body = body.replace("\r\n\t", "");
+
+    let ical = IcalParser::new(BufReader::new(stringreader::StringReader::new(&body)));
+
+    let mut out = Vec::new();
+
+    for cal in ical {
+        let cal = cal.map_err(|e| EventListError::Parse(e.to_string()))?;
+
+        let mut zones = TimeZones::new(&cal.timezones);
+        if let Some(tz) = zone {
+            zones = zones.with_floating(Zone::Iana(tz));
+        }
+        out.push(
+            cal.events
+                .iter()
+                .map(|e| parse_component(e, &zones))
+                .collect(),
+        );
+    }
+
+    if out.is_empty() {
+        return Err(EventListError::Parse("No VCALENDAR found".to_string()));
+    }
+
+    Ok(out)
+}
+
+/// The id of each `VEVENT` in `body` in the order they appear, as `Event::id` would have it
+/// for the event or override it holds, and the zone of its `DTSTART`
+pub(crate) fn component_ids(
+    body: &str,
+    zone: Option<Tz>,
+) -> Result<Vec<(Option<String>, Zone)>, EventListError> {
+    Ok(parse_calendars(body, zone)?
+        .into_iter()
+        .flatten()
+        .map(|c| (c.event.id, c.zone))
+        .collect())
+}
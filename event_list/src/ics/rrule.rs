@@ -0,0 +1,392 @@
+use std::{collections::VecDeque, fmt::Display, str::FromStr};
+
+use chrono::{Datelike, Duration, Months, NaiveDate, NaiveDateTime, NaiveTime, Weekday};
+
+/// Number of consecutive periods without an occurrence before expansion gives up.
+/// Stops rules that can never match (e.g. the 30th of February) from looping forever.
+const MAX_EMPTY_PERIODS: u32 = 1000;
+
+/// The `FREQ` part of a recurrence rule
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Frequency {
+    Secondly,
+    Minutely,
+    Hourly,
+    Daily,
+    Weekly,
+    Monthly,
+    Yearly,
+}
+
+/// A `BYDAY` entry such as `MO`, `2TU` or `-1FR`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WeekdayNum {
+    pub ordinal: Option<i32>,
+    pub weekday: Weekday,
+}
+
+/// A parsed RFC 5545 `RRULE` value
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RRule {
+    pub freq: Frequency,
+    pub interval: u32,
+    pub count: Option<u32>,
+    /// Last possible occurrence, in the same wall clock as the event's `DTSTART`
+    pub until: Option<NaiveDateTime>,
+    /// Whether `until` was given in UTC and still has to be moved into the event's time zone
+    pub until_utc: bool,
+    pub by_day: Vec<WeekdayNum>,
+    pub by_month_day: Vec<i32>,
+    pub by_month: Vec<u32>,
+    pub by_set_pos: Vec<i32>,
+    pub week_start: Weekday,
+}
+
+/// Error returned when an `RRULE` value could not be parsed
+#[derive(Clone, Debug)]
+pub struct RRuleError(String);
+
+impl Display for RRuleError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Invalid RRULE: {}", self.0)
+    }
+}
+
+impl std::error::Error for RRuleError {}
+
+fn parse_weekday(s: &str) -> Result<Weekday, RRuleError> {
+    match s {
+        "MO" => Ok(Weekday::Mon),
+        "TU" => Ok(Weekday::Tue),
+        "WE" => Ok(Weekday::Wed),
+        "TH" => Ok(Weekday::Thu),
+        "FR" => Ok(Weekday::Fri),
+        "SA" => Ok(Weekday::Sat),
+        "SU" => Ok(Weekday::Sun),
+        _ => Err(RRuleError(format!("unknown weekday {s}"))),
+    }
+}
+
+fn parse_list<T: FromStr>(key: &str, value: &str) -> Result<Vec<T>, RRuleError> {
+    value
+        .split(',')
+        .map(|v| {
+            v.parse()
+                .map_err(|_| RRuleError(format!("bad {key} value {v}")))
+        })
+        .collect()
+}
+
+/// Parses an `UNTIL` value. Date-only values include the whole day.
+fn parse_until(s: &str) -> Result<NaiveDateTime, RRuleError> {
+    let s = s.trim_end_matches('Z');
+    NaiveDateTime::parse_from_str(s, "%Y%m%dT%H%M%S")
+        .or_else(|_| {
+            NaiveDate::parse_from_str(s, "%Y%m%d")
+                .map(|d| d.and_time(NaiveTime::from_hms_opt(23, 59, 59).expect("Valid Time")))
+        })
+        .map_err(|_| RRuleError(format!("bad UNTIL value {s}")))
+}
+
+impl FromStr for RRule {
+    type Err = RRuleError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut freq = None;
+        let mut rule = RRule {
+            freq: Frequency::Daily,
+            interval: 1,
+            count: None,
+            until: None,
+            until_utc: false,
+            by_day: Vec::new(),
+            by_month_day: Vec::new(),
+            by_month: Vec::new(),
+            by_set_pos: Vec::new(),
+            week_start: Weekday::Mon,
+        };
+
+        for part in s.split(';').filter(|p| !p.is_empty()) {
+            let (key, value) = part
+                .split_once('=')
+                .ok_or_else(|| RRuleError(format!("missing value for {part}")))?;
+
+            match key {
+                "FREQ" => {
+                    freq = Some(match value {
+                        "SECONDLY" => Frequency::Secondly,
+                        "MINUTELY" => Frequency::Minutely,
+                        "HOURLY" => Frequency::Hourly,
+                        "DAILY" => Frequency::Daily,
+                        "WEEKLY" => Frequency::Weekly,
+                        "MONTHLY" => Frequency::Monthly,
+                        "YEARLY" => Frequency::Yearly,
+                        _ => return Err(RRuleError(format!("unknown FREQ {value}"))),
+                    })
+                }
+                "INTERVAL" => {
+                    rule.interval = value
+                        .parse()
+                        .ok()
+                        .filter(|i| *i > 0)
+                        .ok_or_else(|| RRuleError(format!("bad INTERVAL value {value}")))?
+                }
+                "COUNT" => {
+                    rule.count = Some(
+                        value
+                            .parse()
+                            .map_err(|_| RRuleError(format!("bad COUNT value {value}")))?,
+                    )
+                }
+                "UNTIL" => {
+                    rule.until = Some(parse_until(value)?);
+                    rule.until_utc = value.ends_with('Z');
+                }
+                "BYDAY" => {
+                    rule.by_day =
+                        value
+                            .split(',')
+                            .map(|v| {
+                                let split = v.len().saturating_sub(2);
+                                let (ordinal, day) = v.split_at(split);
+                                Ok(WeekdayNum {
+                                    ordinal: match ordinal {
+                                        "" => None,
+                                        o => Some(o.trim_start_matches('+').parse().map_err(
+                                            |_| RRuleError(format!("bad BYDAY value {v}")),
+                                        )?),
+                                    },
+                                    weekday: parse_weekday(day)?,
+                                })
+                            })
+                            .collect::<Result<_, _>>()?
+                }
+                "BYMONTHDAY" => rule.by_month_day = parse_list(key, value)?,
+                "BYMONTH" => rule.by_month = parse_list(key, value)?,
+                "BYSETPOS" => rule.by_set_pos = parse_list(key, value)?,
+                "WKST" => rule.week_start = parse_weekday(value)?,
+                _ => log::warn!("Ignoring unsupported RRULE part {part}"),
+            }
+        }
+
+        rule.freq = freq.ok_or_else(|| RRuleError("missing FREQ".to_string()))?;
+
+        Ok(rule)
+    }
+}
+
+fn days_in_month(year: i32, month: u32) -> u32 {
+    let first = NaiveDate::from_ymd_opt(year, month, 1).expect("Valid Date");
+    let next = first + Months::new(1);
+    (next - first).num_days() as u32
+}
+
+/// Whether `date` is the `ordinal`th `date.weekday()` between `first` and `last`
+fn matches_ordinal(date: NaiveDate, ordinal: i32, first: NaiveDate, last: NaiveDate) -> bool {
+    let from_start = (date - first).num_days() as i32 / 7 + 1;
+    let from_end = -((last - date).num_days() as i32 / 7 + 1);
+
+    ordinal == from_start || ordinal == from_end
+}
+
+impl RRule {
+    /// Returns an iterator over every occurrence of this rule for an event starting at `dtstart`.
+    /// `dtstart` itself is always the first occurrence.
+    pub fn occurrences(&self, dtstart: NaiveDateTime) -> Occurrences<'_> {
+        Occurrences {
+            rule: self,
+            dtstart,
+            period: 0,
+            buffer: VecDeque::from([dtstart]),
+            emitted: 0,
+            empty_periods: 0,
+            done: false,
+        }
+    }
+
+    fn matches_date(&self, date: NaiveDate, dtstart: NaiveDate) -> bool {
+        if !self.by_month.is_empty() && !self.by_month.contains(&date.month()) {
+            return false;
+        }
+
+        if !self.by_month_day.is_empty() {
+            let len = days_in_month(date.year(), date.month()) as i32;
+            let day = date.day() as i32;
+            if !self
+                .by_month_day
+                .iter()
+                .any(|&d| d == day || (d < 0 && len + d + 1 == day))
+            {
+                return false;
+            }
+        }
+
+        if !self.by_day.is_empty() {
+            return self.by_day.iter().any(|wd| {
+                if wd.weekday != date.weekday() {
+                    return false;
+                }
+                let Some(ordinal) = wd.ordinal else {
+                    return true;
+                };
+                match self.freq {
+                    Frequency::Monthly => {
+                        let first = date.with_day(1).expect("Valid Date");
+                        let last = first + Months::new(1) - Duration::days(1);
+                        matches_ordinal(date, ordinal, first, last)
+                    }
+                    Frequency::Yearly if !self.by_month.is_empty() => {
+                        let first = date.with_day(1).expect("Valid Date");
+                        let last = first + Months::new(1) - Duration::days(1);
+                        matches_ordinal(date, ordinal, first, last)
+                    }
+                    Frequency::Yearly => {
+                        let first = NaiveDate::from_ymd_opt(date.year(), 1, 1).expect("Valid Date");
+                        let last =
+                            NaiveDate::from_ymd_opt(date.year(), 12, 31).expect("Valid Date");
+                        matches_ordinal(date, ordinal, first, last)
+                    }
+                    _ => true,
+                }
+            });
+        }
+
+        if !self.by_month_day.is_empty() {
+            return true;
+        }
+
+        // Without any BYxxx day selectors the day is inherited from DTSTART
+        match self.freq {
+            Frequency::Yearly if self.by_month.is_empty() => {
+                date.month() == dtstart.month() && date.day() == dtstart.day()
+            }
+            Frequency::Yearly | Frequency::Monthly => date.day() == dtstart.day(),
+            Frequency::Weekly => date.weekday() == dtstart.weekday(),
+            _ => true,
+        }
+    }
+
+    /// Returns the sorted candidate occurrences in the `index`th period after `dtstart`,
+    /// or `None` if the period is past any representable date.
+    fn period(&self, index: u32, dtstart: NaiveDateTime) -> Option<Vec<NaiveDateTime>> {
+        let step = index.checked_mul(self.interval)?;
+        let start_date = dtstart.date();
+
+        let days = match self.freq {
+            Frequency::Yearly => {
+                let year = start_date.year().checked_add(step as i32)?;
+                let first = NaiveDate::from_ymd_opt(year, 1, 1)?;
+                let last = NaiveDate::from_ymd_opt(year, 12, 31)?;
+                first.iter_days().take_while(|d| *d <= last).collect()
+            }
+            Frequency::Monthly => {
+                let first = start_date
+                    .with_day(1)?
+                    .checked_add_months(Months::new(step))?;
+                let last = first + Months::new(1);
+                first.iter_days().take_while(|d| *d < last).collect()
+            }
+            Frequency::Weekly => {
+                let offset = (7 + start_date.weekday().num_days_from_monday()
+                    - self.week_start.num_days_from_monday())
+                    % 7;
+                let first = start_date
+                    .checked_sub_signed(Duration::days(offset as i64))?
+                    .checked_add_signed(Duration::weeks(step as i64))?;
+                first.iter_days().take(7).collect()
+            }
+            Frequency::Daily => {
+                vec![start_date.checked_add_signed(Duration::days(step as i64))?]
+            }
+            Frequency::Hourly | Frequency::Minutely | Frequency::Secondly => {
+                let unit = match self.freq {
+                    Frequency::Hourly => Duration::hours(step as i64),
+                    Frequency::Minutely => Duration::minutes(step as i64),
+                    _ => Duration::seconds(step as i64),
+                };
+                let instant = dtstart.checked_add_signed(unit)?;
+                return Some(if self.matches_date(instant.date(), start_date) {
+                    vec![instant]
+                } else {
+                    Vec::new()
+                });
+            }
+        };
+
+        let mut set: Vec<_> = days
+            .into_iter()
+            .filter(|d| self.matches_date(*d, start_date))
+            .map(|d| d.and_time(dtstart.time()))
+            .collect();
+
+        if !self.by_set_pos.is_empty() {
+            let len = set.len() as i32;
+            let mut picked: Vec<_> = self
+                .by_set_pos
+                .iter()
+                .filter_map(|&pos| match pos {
+                    p if p > 0 && p <= len => Some(set[(p - 1) as usize]),
+                    p if p < 0 && -p <= len => Some(set[(len + p) as usize]),
+                    _ => None,
+                })
+                .collect();
+            picked.sort();
+            picked.dedup();
+            set = picked;
+        }
+
+        Some(set)
+    }
+}
+
+/// Iterator over the occurrences of an `RRule`, in chronological order
+pub struct Occurrences<'a> {
+    rule: &'a RRule,
+    dtstart: NaiveDateTime,
+    period: u32,
+    buffer: VecDeque<NaiveDateTime>,
+    emitted: u32,
+    empty_periods: u32,
+    done: bool,
+}
+
+impl Iterator for Occurrences<'_> {
+    type Item = NaiveDateTime;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            if let Some(next) = self.buffer.pop_front() {
+                if self.rule.until.is_some_and(|until| next > until)
+                    || self.rule.count.is_some_and(|count| self.emitted >= count)
+                {
+                    self.done = true;
+                    break;
+                }
+
+                self.emitted += 1;
+                return Some(next);
+            }
+
+            if self.empty_periods >= MAX_EMPTY_PERIODS {
+                self.done = true;
+                break;
+            }
+
+            match self.rule.period(self.period, self.dtstart) {
+                Some(set) => {
+                    self.buffer
+                        .extend(set.into_iter().filter(|d| *d > self.dtstart));
+                    if self.buffer.is_empty() {
+                        self.empty_periods += 1;
+                    } else {
+                        self.empty_periods = 0;
+                    }
+                }
+                None => self.done = true,
+            }
+            self.period += 1;
+        }
+
+        None
+    }
+}
@@ -0,0 +1,272 @@
+use std::{collections::HashMap, sync::Arc};
+
+use chrono::{
+    DateTime, Datelike, Duration, FixedOffset, Local, LocalResult, NaiveDateTime, TimeZone, Utc,
+};
+use chrono_tz::Tz;
+use ical::{parser::ical::component::IcalTimeZone, property::Property};
+
+use super::{
+    parse_naive,
+    rrule::{Frequency, RRule},
+};
+
+/// A `STANDARD` or `DAYLIGHT` sub-component of a `VTIMEZONE`
+#[derive(Clone, Debug)]
+struct Observance {
+    /// First onset, in the wall clock of `offset_from`
+    start: NaiveDateTime,
+    offset_from: FixedOffset,
+    offset_to: FixedOffset,
+    rrule: Option<RRule>,
+    rdates: Vec<NaiveDateTime>,
+}
+
+impl Observance {
+    /// Moves the start of a plain yearly rule to just before `wall`, so zones starting
+    /// in 1601 don't have to be expanded through four centuries on every lookup
+    fn fast_forward(&self, rrule: &RRule, wall: NaiveDateTime) -> NaiveDateTime {
+        if rrule.freq != Frequency::Yearly || rrule.interval != 1 || rrule.count.is_some() {
+            return self.start;
+        }
+
+        match wall.year() - 1 {
+            year if year > self.start.year() => self.start.with_year(year).unwrap_or(self.start),
+            _ => self.start,
+        }
+    }
+
+    /// The latest onset of this observance at or before the wall clock time `wall`
+    fn last_onset(&self, wall: NaiveDateTime) -> Option<NaiveDateTime> {
+        let mut onsets: Vec<_> = match &self.rrule {
+            Some(rrule) => rrule
+                .occurrences(self.fast_forward(rrule, wall))
+                .take_while(|o| *o <= wall)
+                .last()
+                .into_iter()
+                .collect(),
+            None => [self.start].into_iter().filter(|o| *o <= wall).collect(),
+        };
+        onsets.extend(self.rdates.iter().copied().filter(|o| *o <= wall));
+
+        onsets.into_iter().max()
+    }
+}
+
+/// The values of every property called `name`
+fn values<'a>(props: &'a [Property], name: &'a str) -> impl Iterator<Item = &'a str> {
+    props
+        .iter()
+        .filter(move |p| p.name == name)
+        .filter_map(|p| p.value.as_deref())
+}
+
+/// A time zone defined by a `VTIMEZONE` component of a calendar
+#[derive(Clone, Debug)]
+pub struct VTimezone {
+    observances: Vec<Observance>,
+}
+
+/// Parses a UTC offset such as `-0700` or `+053000`
+fn parse_offset(s: &str) -> Option<FixedOffset> {
+    let s = s.trim();
+    let (sign, digits) = match s.split_at_checked(1)? {
+        ("+", d) => (1, d),
+        ("-", d) => (-1, d),
+        _ => return None,
+    };
+
+    if !digits.chars().all(|c| c.is_ascii_digit()) || !matches!(digits.len(), 4 | 6) {
+        return None;
+    }
+
+    let hours: i32 = digits[0..2].parse().ok()?;
+    let minutes: i32 = digits[2..4].parse().ok()?;
+    let seconds: i32 = digits.get(4..6).map_or(Some(0), |s| s.parse().ok())?;
+
+    FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60 + seconds))
+}
+
+impl VTimezone {
+    fn from_component(tz: &IcalTimeZone) -> Option<(String, Self)> {
+        let id = values(&tz.properties, "TZID").next()?.to_string();
+
+        let observances = tz
+            .transitions
+            .iter()
+            .filter_map(|t| {
+                let value = |name| values(&t.properties, name);
+
+                let offset_from = value("TZOFFSETFROM").next().and_then(parse_offset)?;
+                let offset_to = value("TZOFFSETTO").next().and_then(parse_offset)?;
+
+                Some(Observance {
+                    start: value("DTSTART").next().and_then(|s| parse_naive(s).ok())?,
+                    offset_from,
+                    offset_to,
+                    rrule: value("RRULE")
+                        .next()
+                        .and_then(|s| match s.parse::<RRule>() {
+                            Ok(mut r) => {
+                                // UNTIL is in UTC, onsets are in the wall clock before the change
+                                if r.until_utc {
+                                    r.until = r.until.map(|u| u + offset_from);
+                                    r.until_utc = false;
+                                }
+                                Some(r)
+                            }
+                            Err(e) => {
+                                log::warn!("Ignoring VTIMEZONE rule for {id}: {e}");
+                                None
+                            }
+                        }),
+                    rdates: value("RDATE")
+                        .flat_map(|s| s.split(','))
+                        .filter_map(|s| parse_naive(s).ok())
+                        .collect(),
+                })
+            })
+            .collect::<Vec<_>>();
+
+        if observances.is_empty() {
+            log::warn!("VTIMEZONE {id} has no usable observances");
+            return None;
+        }
+
+        Some((id, Self { observances }))
+    }
+
+    /// The UTC offset in effect at the wall clock time `wall`
+    fn offset_at(&self, wall: NaiveDateTime) -> FixedOffset {
+        self.observances
+            .iter()
+            .filter_map(|o| o.last_onset(wall).map(|onset| (onset, o.offset_to)))
+            .max_by_key(|(onset, _)| *onset)
+            .map(|(_, offset)| offset)
+            // Before the first onset the zone is in the earliest observance's "from" offset
+            .unwrap_or_else(|| {
+                self.observances
+                    .iter()
+                    .min_by_key(|o| o.start)
+                    .expect("At least one observance")
+                    .offset_from
+            })
+    }
+}
+
+/// The time zone a date or date-time value is expressed in
+#[derive(Clone, Debug, Default)]
+pub enum Zone {
+    /// Values ending in `Z`
+    Utc,
+    /// Values without a `TZID`, which float in the local time zone
+    #[default]
+    Floating,
+    /// A `TZID` naming an IANA time zone
+    Iana(Tz),
+    /// A `TZID` defined by a `VTIMEZONE` in the calendar
+    Custom(Arc<VTimezone>),
+}
+
+/// Picks an instant for a wall clock time, moving times in a DST gap forward by the gap
+fn resolve<T: TimeZone>(tz: &T, wall: NaiveDateTime) -> Option<DateTime<T>> {
+    match tz.from_local_datetime(&wall) {
+        LocalResult::Single(d) => Some(d),
+        LocalResult::Ambiguous(d, _) => Some(d),
+        LocalResult::None => tz
+            .from_local_datetime(&(wall + Duration::hours(1)))
+            .earliest(),
+    }
+}
+
+impl Zone {
+    /// Converts a wall clock time in this zone into an instant
+    pub fn instant(&self, wall: NaiveDateTime) -> DateTime<Local> {
+        let utc = match self {
+            Zone::Utc => Utc.from_utc_datetime(&wall),
+            Zone::Floating => {
+                return resolve(&Local, wall).unwrap_or_else(|| Local.from_utc_datetime(&wall))
+            }
+            Zone::Iana(tz) => resolve(tz, wall)
+                .map(|d| d.with_timezone(&Utc))
+                .unwrap_or_else(|| Utc.from_utc_datetime(&wall)),
+            Zone::Custom(vtz) => Utc.from_utc_datetime(&(wall - vtz.offset_at(wall))),
+        };
+
+        utc.with_timezone(&Local)
+    }
+
+    /// Converts an instant into the wall clock time of this zone
+    pub fn wall_time(&self, instant: DateTime<Local>) -> NaiveDateTime {
+        match self {
+            Zone::Utc => instant.naive_utc(),
+            Zone::Floating => instant.naive_local(),
+            Zone::Iana(tz) => instant.with_timezone(tz).naive_local(),
+            Zone::Custom(vtz) => {
+                let utc = instant.naive_utc();
+                vtz.observances
+                    .iter()
+                    .map(|o| utc + o.offset_to)
+                    .find(|wall| utc + vtz.offset_at(*wall) == *wall)
+                    .unwrap_or_else(|| utc + vtz.offset_at(utc))
+            }
+        }
+    }
+}
+
+/// The time zones that `TZID` parameters in one calendar can refer to
+#[derive(Clone, Debug, Default)]
+pub struct TimeZones {
+    custom: HashMap<String, Arc<VTimezone>>,
+    /// Zone of times that don't name one
+    floating: Zone,
+}
+
+impl TimeZones {
+    /// Collects the `VTIMEZONE` definitions of a calendar
+    pub fn new(timezones: &[IcalTimeZone]) -> Self {
+        Self {
+            custom: timezones
+                .iter()
+                .filter_map(VTimezone::from_component)
+                .map(|(id, vtz)| (id, Arc::new(vtz)))
+                .collect(),
+            floating: Zone::Floating,
+        }
+    }
+
+    /// Interprets times without a zone, or with an unknown one, in `zone` instead of local time
+    pub fn with_floating(mut self, zone: Zone) -> Self {
+        self.floating = zone;
+        self
+    }
+
+    /// The zone of times that don't name one
+    pub fn floating(&self) -> Zone {
+        self.floating.clone()
+    }
+
+    /// Resolves a `TZID` to a zone. IANA names win over embedded definitions, since feeds
+    /// often ship truncated `VTIMEZONE`s. Unknown ids are treated as floating.
+    pub fn resolve(&self, tzid: &str) -> Zone {
+        let tzid = tzid.trim_matches('"');
+
+        // Some producers prefix ids with a path, e.g. `/mozilla.org/20050126_1/America/Phoenix`
+        let iana = tzid.parse::<Tz>().ok().or_else(|| {
+            let parts: Vec<_> = tzid.rsplitn(3, '/').collect();
+            match parts[..] {
+                [city, region, _] => format!("{region}/{city}").parse().ok(),
+                _ => None,
+            }
+        });
+
+        if let Some(tz) = iana {
+            Zone::Iana(tz)
+        } else if let Some(vtz) = self.custom.get(tzid) {
+            Zone::Custom(vtz.clone())
+        } else {
+            log::warn!("Unknown TZID {tzid}, treating times as floating");
+            self.floating()
+        }
+    }
+}
@@ -0,0 +1,198 @@
+use chrono::Utc;
+
+use std::{
+    sync::atomic::{AtomicU64, Ordering as AtomicOrdering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{Event, EventStatus, EventTime, Person};
+
+/// Longest allowed content line, in octets, before it has to be folded
+const MAX_LINE: usize = 75;
+
+/// Escapes a TEXT value, the inverse of `unescape_ical`
+fn escape_ical(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace("\r\n", "\\n")
+        .replace('\n', "\\n")
+}
+
+/// Appends `line` to `out`, folded into lines of at most 75 octets without splitting characters
+pub(super) fn push_folded(out: &mut String, line: &str) {
+    let mut width = 0;
+
+    for c in line.chars() {
+        if width + c.len_utf8() > MAX_LINE {
+            out.push_str("\r\n ");
+            width = 1;
+        }
+        out.push(c);
+        width += c.len_utf8();
+    }
+
+    out.push_str("\r\n");
+}
+
+/// Formats a `DTSTART` or `DTEND` property. Times are written in UTC.
+fn time_property(name: &str, time: EventTime) -> String {
+    match time {
+        EventTime::DateTime(d) => {
+            format!("{name}:{}", d.with_timezone(&Utc).format("%Y%m%dT%H%M%SZ"))
+        }
+        EventTime::Date(d) => format!("{name};VALUE=DATE:{}", d.format("%Y%m%d")),
+    }
+}
+
+/// Formats an `ORGANIZER` or `ATTENDEE` property
+fn person_property(name: &str, person: &Person) -> String {
+    let mut out = name.to_string();
+
+    if let Some(cn) = &person.name {
+        out.push_str(&format!(";CN=\"{}\"", cn.replace('"', "")));
+    }
+    if let Some(response) = &person.response {
+        out.push_str(&format!(";PARTSTAT={}", response.to_ascii_uppercase()));
+    }
+    out.push_str(&format!(
+        ":mailto:{}",
+        person.email.as_deref().unwrap_or_default()
+    ));
+
+    out
+}
+
+/// 64 bit FNV-1a, which unlike `DefaultHasher` is the same across builds
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, b| {
+        (hash ^ *b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// A `UID` for `event`: its id, or else one that stays the same as long as its title and
+/// start do
+pub fn event_uid(event: &Event) -> String {
+    if let Some(id) = &event.id {
+        return id.clone();
+    }
+
+    let key = format!("{}\n{:?}", event.title, event.start.map(|s| s.instant()));
+    format!("{:016x}@event_server", fnv1a(key.as_bytes()))
+}
+
+/// A fresh `UID` for a newly created event
+pub fn new_uid() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let key = format!(
+        "{now}\n{}\n{}",
+        std::process::id(),
+        COUNTER.fetch_add(1, AtomicOrdering::Relaxed)
+    );
+
+    format!("{:016x}@event_server", fnv1a(key.as_bytes()))
+}
+
+/// Appends the start of a `VCALENDAR` to `out`
+fn push_header(out: &mut String) {
+    for line in [
+        "BEGIN:VCALENDAR",
+        "VERSION:2.0",
+        "PRODID:-//event_server//event_server//EN",
+        "CALSCALE:GREGORIAN",
+    ] {
+        push_folded(out, line);
+    }
+}
+
+/// Appends `event` as a `VEVENT` to `out`
+pub(super) fn push_event(out: &mut String, event: &Event, uid: &str, stamp: &str) {
+    push_folded(out, "BEGIN:VEVENT");
+    push_folded(out, &format!("UID:{uid}"));
+    push_folded(out, &format!("DTSTAMP:{stamp}"));
+
+    if let Some(start) = event.start {
+        push_folded(out, &time_property("DTSTART", start));
+    }
+    if let Some(end) = event.end {
+        push_folded(out, &time_property("DTEND", end));
+    }
+
+    push_folded(out, &format!("SUMMARY:{}", escape_ical(&event.title)));
+    push_folded(out, &format!("DESCRIPTION:{}", escape_ical(&event.desc)));
+
+    if let Some(location) = &event.location {
+        push_folded(out, &format!("LOCATION:{}", escape_ical(location)));
+    }
+    if let Some(status) = event.status {
+        let status = match status {
+            EventStatus::Tentative => "TENTATIVE",
+            EventStatus::Confirmed => "CONFIRMED",
+            EventStatus::Cancelled => "CANCELLED",
+        };
+        push_folded(out, &format!("STATUS:{status}"));
+    }
+    if event.transparent {
+        push_folded(out, "TRANSP:TRANSPARENT");
+    }
+    if let Some(organizer) = &event.organizer {
+        push_folded(out, &person_property("ORGANIZER", organizer));
+    }
+    for attendee in &event.attendees {
+        push_folded(out, &person_property("ATTENDEE", attendee));
+    }
+    if !event.categories.is_empty() {
+        let categories: Vec<_> = event.categories.iter().map(|c| escape_ical(c)).collect();
+        push_folded(out, &format!("CATEGORIES:{}", categories.join(",")));
+    }
+    if let Some(color) = &event.color {
+        push_folded(out, &format!("COLOR:{color}"));
+    }
+    if let Some(url) = &event.url {
+        push_folded(out, &format!("URL:{url}"));
+    }
+    if let Some(modified) = event.last_modified {
+        push_folded(
+            out,
+            &format!("LAST-MODIFIED:{}", modified.format("%Y%m%dT%H%M%SZ")),
+        );
+    }
+
+    push_folded(out, "END:VEVENT");
+}
+
+/// The current time as a `DTSTAMP` value
+pub(super) fn stamp() -> String {
+    Utc::now().format("%Y%m%dT%H%M%SZ").to_string()
+}
+
+/// Serializes `events` as an RFC 5545 `VCALENDAR`
+pub fn to_ics(events: &[Event]) -> String {
+    let mut out = String::new();
+    let stamp = stamp();
+
+    push_header(&mut out);
+    for e in events {
+        push_event(&mut out, e, &event_uid(e), &stamp);
+    }
+    push_folded(&mut out, "END:VCALENDAR");
+
+    out
+}
+
+/// Serializes `event` with `uid` as a `VCALENDAR` of its own, as stored in a CalDAV
+/// collection or a vdir
+pub fn event_to_ics(event: &Event, uid: &str) -> String {
+    let mut out = String::new();
+
+    push_header(&mut out);
+    push_event(&mut out, event, uid, &stamp());
+    push_folded(&mut out, "END:VCALENDAR");
+
+    out
+}
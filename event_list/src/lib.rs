@@ -1,18 +1,185 @@
+pub mod dedup;
+mod error;
+pub mod ics;
 pub mod lists;
 
-use std::{collections::HashMap, fmt::Display};
+pub use error::EventListError;
 
-use chrono::{DateTime, Local, NaiveDate};
+use std::{cmp::Ordering, collections::HashMap, fmt::Display, ops::Add, sync::Arc};
+
+use chrono::{DateTime, Duration, Local, NaiveDate, TimeZone, Utc};
 use serde::{Deserialize, Serialize};
 
+/// When an event starts or ends
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventTime {
+    /// A specific point in time
+    DateTime(DateTime<Local>),
+    /// A whole day, for all-day events. Ends are exclusive, like iCal's `DTEND`.
+    Date(NaiveDate),
+}
+
+/// Midnight at the start of `date` in the local time zone
+fn local_midnight(date: NaiveDate) -> DateTime<Local> {
+    let midnight = date.and_hms_opt(0, 0, 0).expect("Valid time");
+    Local
+        .from_local_datetime(&midnight)
+        .earliest()
+        .unwrap_or_else(|| Local.from_utc_datetime(&midnight))
+}
+
+impl EventTime {
+    /// The point in time this refers to. Dates start at local midnight.
+    pub fn instant(&self) -> DateTime<Local> {
+        match self {
+            EventTime::DateTime(d) => *d,
+            EventTime::Date(d) => local_midnight(*d),
+        }
+    }
+
+    /// The local date this falls on
+    pub fn date(&self) -> NaiveDate {
+        match self {
+            EventTime::DateTime(d) => d.date_naive(),
+            EventTime::Date(d) => *d,
+        }
+    }
+
+    /// Whether this is a date without a time of day
+    pub fn is_date(&self) -> bool {
+        matches!(self, EventTime::Date(_))
+    }
+}
+
+impl From<DateTime<Local>> for EventTime {
+    fn from(d: DateTime<Local>) -> Self {
+        EventTime::DateTime(d)
+    }
+}
+
+impl From<NaiveDate> for EventTime {
+    fn from(d: NaiveDate) -> Self {
+        EventTime::Date(d)
+    }
+}
+
+impl Add<Duration> for EventTime {
+    type Output = EventTime;
+
+    /// Shifts the time, dates only move by whole days
+    fn add(self, rhs: Duration) -> Self::Output {
+        match self {
+            EventTime::DateTime(d) => EventTime::DateTime(d + rhs),
+            EventTime::Date(d) => EventTime::Date(d + Duration::days(rhs.num_days())),
+        }
+    }
+}
+
+impl Ord for EventTime {
+    /// Orders by instant, with all-day entries before timed ones starting at midnight
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.instant(), !self.is_date()).cmp(&(other.instant(), !other.is_date()))
+    }
+}
+
+impl PartialOrd for EventTime {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Whether an event is going ahead
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventStatus {
+    Tentative,
+    Confirmed,
+    Cancelled,
+}
+
+impl EventStatus {
+    /// Parses an iCal `STATUS` or a Google event status
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "tentative" => Some(EventStatus::Tentative),
+            "confirmed" => Some(EventStatus::Confirmed),
+            "cancelled" => Some(EventStatus::Cancelled),
+            _ => None,
+        }
+    }
+}
+
+/// Someone organizing or attending an event
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Person {
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub email: Option<String>,
+    /// Their answer to the invitation, e.g. `accepted`, `declined`, `tentative` or `needs-action`
+    #[serde(default)]
+    pub response: Option<String>,
+}
+
 /// Some calendar event
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Event {
     pub title: String,
+    #[serde(default)]
     pub desc: String,
-    pub end: Option<DateTime<Local>>,
-    pub start: Option<DateTime<Local>>,
+    #[serde(default)]
+    pub end: Option<EventTime>,
+    pub start: Option<EventTime>,
+    #[serde(default)]
     pub class: Option<String>,
+    #[serde(default)]
+    pub location: Option<String>,
+    #[serde(default)]
+    pub status: Option<EventStatus>,
+    #[serde(default)]
+    pub organizer: Option<Person>,
+    #[serde(default)]
+    pub attendees: Vec<Person>,
+    #[serde(default)]
+    pub categories: Vec<String>,
+    /// Display color of the event, as a CSS color. Google events without one get their
+    /// calendar's color.
+    #[serde(default)]
+    pub color: Option<String>,
+    /// Doesn't take up time, e.g. a reminder marked as free
+    #[serde(default)]
+    pub transparent: bool,
+    /// Stays the same across refreshes: the iCal `UID`, followed by `/` and the original start
+    /// for occurrences of recurring events, or the Google event id
+    #[serde(default)]
+    pub id: Option<String>,
+    /// For occurrences of recurring events, the id of the whole series
+    #[serde(default)]
+    pub series_id: Option<String>,
+    /// Name of the source the event came from, as set in the config
+    #[serde(default)]
+    pub source: Option<String>,
+    /// Name of the calendar within the source, for sources with several calendars
+    #[serde(default)]
+    pub calendar: Option<String>,
+    /// Link to the event, the iCal `URL` or the Google calendar page
+    #[serde(default)]
+    pub url: Option<String>,
+    #[serde(default)]
+    pub last_modified: Option<DateTime<Utc>>,
+}
+
+impl Event {
+    /// Whether this event covers whole days rather than a time range
+    pub fn is_all_day(&self) -> bool {
+        self.start.is_some_and(|s| s.is_date())
+    }
+
+    /// Whether the event has been called off
+    pub fn is_cancelled(&self) -> bool {
+        self.status == Some(EventStatus::Cancelled)
+    }
 }
 
 /// List of all the events on a certain day
@@ -28,18 +195,26 @@ pub struct Days {
 }
 
 impl Days {
-    /// Sorts the events into `Days`
+    /// Sorts the events into `Days`.
+    /// All-day events are listed on every day they cover, events without a start are skipped.
     pub fn from_slice(list: &[Event]) -> Self {
         let mut preout = HashMap::<NaiveDate, Vec<Event>>::new();
 
         for e in list {
-            if let Some(l) = preout.get_mut(&e.start.unwrap().with_timezone(&Local).date_naive()) {
-                l.push(e.clone());
-            } else {
-                preout.insert(
-                    e.start.unwrap().with_timezone(&Local).date_naive(),
-                    vec![e.clone()],
-                );
+            let Some(start) = e.start else {
+                log::warn!("Skipping event without a start: {}", e.title);
+                continue;
+            };
+
+            let first = start.date();
+            let last = match e.end {
+                Some(EventTime::Date(end)) if start.is_date() && end > first => end.pred_opt(),
+                _ => None,
+            }
+            .unwrap_or(first);
+
+            for day in first.iter_days().take_while(|d| *d <= last) {
+                preout.entry(day).or_default().push(e.clone());
             }
         }
 
@@ -51,10 +226,7 @@ impl Days {
                 events.sort_by_key(|e| e.start);
 
                 DayList {
-                    date: DateTime::<Local>::from_local(
-                        date.and_hms_opt(0, 0, 0).expect("Valid time"),
-                        *Local::now().offset(),
-                    ),
+                    date: local_midnight(date),
                     events,
                 }
             })
@@ -88,6 +260,19 @@ impl Default for Event {
             end: None,
             start: None,
             class: None,
+            location: None,
+            status: None,
+            organizer: None,
+            attendees: Vec::new(),
+            categories: Vec::new(),
+            color: None,
+            transparent: false,
+            id: None,
+            series_id: None,
+            source: None,
+            calendar: None,
+            url: None,
+            last_modified: None,
         }
     }
 }
@@ -97,5 +282,46 @@ impl Default for Event {
 pub trait EventList {
     async fn init(&mut self);
 
-    async fn events(&self, after: DateTime<Local>) -> Vec<Event>;
+    /// Name of this source, used when reporting errors
+    fn name(&self) -> &str;
+
+    async fn events(&self, after: DateTime<Local>) -> Result<Vec<Event>, EventListError>;
+
+    /// This source as an `EventSink`, `None` if it is read-only
+    fn sink(&self) -> Option<&dyn EventSink> {
+        None
+    }
+
+    /// How the user signs in to this source, `None` if it doesn't need it
+    fn sign_in(&self) -> Option<Arc<dyn SignIn>> {
+        None
+    }
+}
+
+/// Trait for a source the user signs in to with OAuth.
+/// The source reports `EventListError::Unauthenticated` until a sign in finishes.
+#[async_trait::async_trait]
+pub trait SignIn: Send + Sync {
+    /// Starts a sign in that returns to `redirect_uri`, returning the url to send the user to
+    fn start(&self, redirect_uri: &str) -> Result<String, EventListError>;
+
+    /// Whether `state` belongs to a sign in this started
+    fn expects(&self, state: &str) -> bool;
+
+    /// Finishes the sign in that returned with `state` and the authorization `code`
+    async fn finish(&self, state: &str, code: &str) -> Result<(), EventListError>;
+}
+
+/// Trait for a source events can be written to.
+/// Ids are the ones `create` hands out: the iCal `UID`, or the Google event id.
+#[async_trait::async_trait]
+pub trait EventSink: Sync {
+    /// Adds `event` to the source and returns its id
+    async fn create(&self, event: &Event) -> Result<String, EventListError>;
+
+    /// Replaces the event with `id` by `event`
+    async fn update(&self, id: &str, event: &Event) -> Result<(), EventListError>;
+
+    /// Removes the event with `id`
+    async fn delete(&self, id: &str) -> Result<(), EventListError>;
 }
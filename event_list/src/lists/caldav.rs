@@ -0,0 +1,686 @@
+use super::{http_client, SyncError, DEFAULT_HORIZON_DAYS};
+use crate::{ics, Event, EventList, EventListError, EventSink};
+
+use chrono::{DateTime, Duration, Local, Utc};
+use chrono_tz::Tz;
+use quick_xml::{events::Event as XmlEvent, Reader};
+use reqwest::{header, Method, StatusCode, Url};
+use tokio::sync::Mutex;
+
+use std::collections::HashMap;
+
+/// Properties asked for on the configured url to find the calendars
+const DISCOVER_PROPS: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<d:propfind xmlns:d="DAV:" xmlns:c="urn:ietf:params:xml:ns:caldav">
+  <d:prop>
+    <d:resourcetype/>
+    <d:displayname/>
+    <d:current-user-principal/>
+    <c:calendar-home-set/>
+    <c:supported-calendar-component-set/>
+  </d:prop>
+</d:propfind>"#;
+
+/// Asks a collection for its current sync token
+const SYNC_TOKEN_PROPS: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<d:propfind xmlns:d="DAV:">
+  <d:prop>
+    <d:sync-token/>
+  </d:prop>
+</d:propfind>"#;
+
+/// Properties that are collected from a `DAV:multistatus` response
+#[derive(Clone, Debug, Default)]
+struct DavProps {
+    displayname: Option<String>,
+    /// Local names of the `resourcetype` children, e.g. `collection` and `calendar`
+    resource_types: Vec<String>,
+    principal: Option<String>,
+    home_set: Option<String>,
+    /// Components from `supported-calendar-component-set`, e.g. `VEVENT`
+    components: Vec<String>,
+    etag: Option<String>,
+    calendar_data: Option<String>,
+    sync_token: Option<String>,
+}
+
+impl DavProps {
+    /// Copies the properties that are set in `other`
+    fn merge(&mut self, other: DavProps) {
+        self.displayname = other.displayname.or(self.displayname.take());
+        self.resource_types.extend(other.resource_types);
+        self.principal = other.principal.or(self.principal.take());
+        self.home_set = other.home_set.or(self.home_set.take());
+        self.components.extend(other.components);
+        self.etag = other.etag.or(self.etag.take());
+        self.calendar_data = other.calendar_data.or(self.calendar_data.take());
+        self.sync_token = other.sync_token.or(self.sync_token.take());
+    }
+
+    fn is_calendar(&self) -> bool {
+        self.resource_types.iter().any(|t| t == "calendar")
+    }
+}
+
+/// One `response` of a multistatus
+#[derive(Debug, Default)]
+struct DavResponse {
+    href: String,
+    /// Status of the whole response, only set for e.g. members removed since a sync token
+    status: Option<u16>,
+    /// Properties from every `propstat` with a success status
+    props: DavProps,
+}
+
+#[derive(Debug, Default)]
+struct Multistatus {
+    responses: Vec<DavResponse>,
+    /// New token of a `sync-collection` report
+    sync_token: Option<String>,
+}
+
+/// The code of an HTTP status line like `HTTP/1.1 200 OK`
+fn status_code(line: &str) -> Option<u16> {
+    line.split_whitespace().nth(1)?.parse().ok()
+}
+
+/// Parses a `DAV:multistatus` body, matching elements by local name whatever their prefix
+fn parse_multistatus(body: &str) -> Result<Multistatus, EventListError> {
+    let xml_error =
+        |e: quick_xml::Error| EventListError::Parse(format!("Invalid DAV response: {e}"));
+
+    let mut reader = Reader::from_str(body);
+    let mut out = Multistatus::default();
+    let mut stack: Vec<String> = Vec::new();
+    let mut text = String::new();
+    let mut response = DavResponse::default();
+    let mut propstat = DavProps::default();
+    let mut propstat_status = None;
+
+    loop {
+        match reader.read_event().map_err(xml_error)? {
+            XmlEvent::Start(e) => {
+                let name = String::from_utf8_lossy(e.local_name().as_ref()).into_owned();
+                stack.push(name);
+                text.clear();
+            }
+            XmlEvent::Empty(e) => {
+                let name = String::from_utf8_lossy(e.local_name().as_ref()).into_owned();
+                match stack.last().map(String::as_str) {
+                    Some("resourcetype") => propstat.resource_types.push(name),
+                    Some("supported-calendar-component-set") if name == "comp" => {
+                        if let Some(comp) = e.try_get_attribute("name").ok().flatten() {
+                            propstat
+                                .components
+                                .push(String::from_utf8_lossy(&comp.value).into_owned());
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            XmlEvent::Text(t) => text.push_str(&t.unescape().map_err(xml_error)?),
+            XmlEvent::CData(t) => text.push_str(&String::from_utf8_lossy(&t.into_inner())),
+            XmlEvent::End(_) => {
+                let name = stack.pop().unwrap_or_default();
+                let parent = stack.last().map(String::as_str);
+                let value = std::mem::take(&mut text).trim().to_string();
+
+                match (name.as_str(), parent) {
+                    ("href", Some("response")) => response.href = value,
+                    ("href", Some("current-user-principal")) => propstat.principal = Some(value),
+                    ("href", Some("calendar-home-set")) => propstat.home_set = Some(value),
+                    ("status", Some("response")) => response.status = status_code(&value),
+                    ("status", Some("propstat")) => propstat_status = status_code(&value),
+                    ("displayname", Some("prop")) => propstat.displayname = Some(value),
+                    ("getetag", Some("prop")) => propstat.etag = Some(value),
+                    ("calendar-data", Some("prop")) => propstat.calendar_data = Some(value),
+                    ("sync-token", Some("prop")) => propstat.sync_token = Some(value),
+                    ("sync-token", Some("multistatus")) => out.sync_token = Some(value),
+                    ("propstat", _) => {
+                        let props = std::mem::take(&mut propstat);
+                        if propstat_status
+                            .take()
+                            .is_some_and(|s| (200..300).contains(&s))
+                        {
+                            response.props.merge(props);
+                        }
+                    }
+                    ("response", _) => out.responses.push(std::mem::take(&mut response)),
+                    _ => {}
+                }
+            }
+            XmlEvent::Eof => break,
+            _ => {}
+        }
+    }
+
+    Ok(out)
+}
+
+/// Escapes text for use inside an XML element
+fn escape_xml(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// A calendar object resource as last fetched
+#[derive(Clone, Debug)]
+struct Item {
+    etag: Option<String>,
+    body: String,
+}
+
+/// A calendar collection and the items it held as of `sync_token`
+#[derive(Debug)]
+struct Calendar {
+    url: Url,
+    sync_token: Option<String>,
+    /// Items by href, `None` until the first full query
+    items: Option<HashMap<String, Item>>,
+}
+
+/// Represents the calendars of a CalDAV account, or a single CalDAV calendar
+pub struct CalDavList {
+    url: Url,
+    name: String,
+    username: Option<String>,
+    password: Option<String>,
+    horizon: Duration,
+    /// Zone of times in the calendars that don't name one, local time if `None`
+    timezone: Option<Tz>,
+    client: reqwest::Client,
+    /// Calendars found at `url`, `None` until they have been discovered
+    calendars: Mutex<Option<Vec<Calendar>>>,
+}
+
+impl CalDavList {
+    /// Create a new `CalDavList` for `url`, which can be a calendar, a calendar home or a
+    /// principal. Fails if `url` isn't a valid url.
+    pub fn new(url: &str) -> Result<Self, EventListError> {
+        let url = Url::parse(url).map_err(|e| EventListError::Parse(format!("{url}: {e}")))?;
+
+        // Keep credentials and tokens in the url out of the logs
+        let mut name = url.clone();
+        let _ = name.set_username("");
+        let _ = name.set_password(None);
+        name.set_query(None);
+
+        Ok(Self {
+            name: name.to_string(),
+            url,
+            username: None,
+            password: None,
+            horizon: Duration::days(DEFAULT_HORIZON_DAYS),
+            timezone: None,
+            client: http_client(),
+            calendars: Mutex::new(None),
+        })
+    }
+
+    /// Authenticates every request with HTTP Basic auth
+    pub fn with_basic_auth(mut self, username: &str, password: Option<&str>) -> Self {
+        self.username = Some(username.to_string());
+        self.password = password.map(str::to_string);
+        self
+    }
+
+    /// Reads times in the calendars that don't name a zone as times in `tz`
+    pub fn with_timezone(mut self, tz: Tz) -> Self {
+        self.timezone = Some(tz);
+        self
+    }
+
+    /// Resolves an href from a response against the configured url
+    fn join(&self, href: &str) -> Result<Url, EventListError> {
+        self.url
+            .join(href)
+            .map_err(|e| EventListError::Parse(format!("Invalid href {href}: {e}")))
+    }
+
+    /// Sends a WebDAV request with an XML body and returns the raw response
+    async fn send(
+        &self,
+        method: &str,
+        url: &Url,
+        depth: &str,
+        body: String,
+    ) -> Result<reqwest::Response, EventListError> {
+        Ok(self
+            .request(
+                Method::from_bytes(method.as_bytes()).expect("Valid method"),
+                url,
+            )
+            .header("Depth", depth)
+            .header(header::CONTENT_TYPE, "application/xml; charset=utf-8")
+            .body(body)
+            .send()
+            .await?)
+    }
+
+    /// Starts a request to `url` with the configured credentials
+    fn request(&self, method: Method, url: &Url) -> reqwest::RequestBuilder {
+        let req = self.client.request(method, url.clone());
+
+        match &self.username {
+            Some(username) => req.basic_auth(username, self.password.as_ref()),
+            None => req,
+        }
+    }
+
+    /// Sends a WebDAV request and parses its multistatus answer
+    async fn multistatus(
+        &self,
+        method: &str,
+        url: &Url,
+        depth: &str,
+        body: String,
+    ) -> Result<Multistatus, EventListError> {
+        let resp = self
+            .send(method, url, depth, body)
+            .await?
+            .error_for_status()?;
+
+        parse_multistatus(&resp.text().await?)
+    }
+
+    /// The properties of the resource at `url` itself
+    async fn propfind(&self, url: &Url, body: &str) -> Result<DavProps, EventListError> {
+        let status = self
+            .multistatus("PROPFIND", url, "0", body.to_string())
+            .await?;
+
+        Ok(status
+            .responses
+            .into_iter()
+            .next()
+            .map(|r| r.props)
+            .unwrap_or_default())
+    }
+
+    /// Finds the event calendars at the configured url, following the principal and calendar
+    /// home when it isn't a calendar itself
+    async fn discover(&self) -> Result<Vec<Calendar>, EventListError> {
+        let calendar = |url| Calendar {
+            url,
+            sync_token: None,
+            items: None,
+        };
+
+        let props = self.propfind(&self.url, DISCOVER_PROPS).await?;
+        if props.is_calendar() {
+            return Ok(vec![calendar(self.url.clone())]);
+        }
+
+        let home = match props.home_set {
+            Some(home) => home,
+            None => {
+                let principal = props.principal.ok_or_else(|| {
+                    EventListError::Parse(format!("{} is not a CalDAV calendar", self.name))
+                })?;
+                self.propfind(&self.join(&principal)?, DISCOVER_PROPS)
+                    .await?
+                    .home_set
+                    .ok_or_else(|| {
+                        EventListError::Parse(format!("{} has no calendar home", self.name))
+                    })?
+            }
+        };
+
+        let listing = self
+            .multistatus(
+                "PROPFIND",
+                &self.join(&home)?,
+                "1",
+                DISCOVER_PROPS.to_string(),
+            )
+            .await?;
+
+        let mut out = Vec::new();
+        for r in listing.responses {
+            let events =
+                r.props.components.is_empty() || r.props.components.iter().any(|c| c == "VEVENT");
+            if r.props.is_calendar() && events {
+                log::debug!(
+                    "Found calendar {} at {}",
+                    r.props.displayname.as_deref().unwrap_or_default(),
+                    r.href
+                );
+                out.push(calendar(self.join(&r.href)?));
+            }
+        }
+
+        if out.is_empty() {
+            return Err(EventListError::Parse(format!(
+                "{} has no event calendars",
+                self.name
+            )));
+        }
+
+        Ok(out)
+    }
+
+    /// Replaces the items of `calendar` with every item that ends after `after`.
+    /// The sync token is read first so changes made during the query are synced next time.
+    async fn full_query(
+        &self,
+        calendar: &mut Calendar,
+        after: DateTime<Local>,
+    ) -> Result<(), EventListError> {
+        calendar.sync_token = match self.propfind(&calendar.url, SYNC_TOKEN_PROPS).await {
+            Ok(props) => props.sync_token,
+            Err(e) => {
+                log::debug!("{} has no sync token: {e}", calendar.url);
+                None
+            }
+        };
+
+        let body = format!(
+            r#"<?xml version="1.0" encoding="utf-8"?>
+<c:calendar-query xmlns:d="DAV:" xmlns:c="urn:ietf:params:xml:ns:caldav">
+  <d:prop>
+    <d:getetag/>
+    <c:calendar-data/>
+  </d:prop>
+  <c:filter>
+    <c:comp-filter name="VCALENDAR">
+      <c:comp-filter name="VEVENT">
+        <c:time-range start="{}"/>
+      </c:comp-filter>
+    </c:comp-filter>
+  </c:filter>
+</c:calendar-query>"#,
+            after.with_timezone(&Utc).format("%Y%m%dT%H%M%SZ")
+        );
+
+        let status = self.multistatus("REPORT", &calendar.url, "1", body).await?;
+
+        calendar.items = Some(
+            status
+                .responses
+                .into_iter()
+                .filter_map(|r| {
+                    let body = r.props.calendar_data?;
+                    Some((
+                        r.href,
+                        Item {
+                            etag: r.props.etag,
+                            body,
+                        },
+                    ))
+                })
+                .collect(),
+        );
+
+        Ok(())
+    }
+
+    /// Applies the changes since the sync token of `calendar`, fetching only changed items
+    async fn sync_collection(&self, calendar: &mut Calendar, token: &str) -> Result<(), SyncError> {
+        let body = format!(
+            r#"<?xml version="1.0" encoding="utf-8"?>
+<d:sync-collection xmlns:d="DAV:">
+  <d:sync-token>{}</d:sync-token>
+  <d:sync-level>1</d:sync-level>
+  <d:prop>
+    <d:getetag/>
+  </d:prop>
+</d:sync-collection>"#,
+            escape_xml(token)
+        );
+
+        let resp = self.send("REPORT", &calendar.url, "0", body).await?;
+        if matches!(
+            resp.status(),
+            StatusCode::FORBIDDEN | StatusCode::CONFLICT | StatusCode::GONE
+        ) {
+            return Err(SyncError::InvalidToken);
+        }
+        let status = parse_multistatus(
+            &resp
+                .error_for_status()
+                .map_err(EventListError::from)?
+                .text()
+                .await
+                .map_err(EventListError::from)?,
+        )?;
+
+        let items = calendar.items.get_or_insert_with(HashMap::new);
+        let mut changed = Vec::new();
+
+        for r in status.responses {
+            if self.join(&r.href)? == calendar.url {
+                continue;
+            }
+
+            if r.status == Some(404) {
+                items.remove(&r.href);
+            } else if items.get(&r.href).is_none_or(|i| i.etag != r.props.etag) {
+                changed.push(r.href);
+            }
+        }
+
+        if !changed.is_empty() {
+            log::debug!("{} items changed in {}", changed.len(), calendar.url);
+
+            let hrefs: String = changed
+                .iter()
+                .map(|h| format!("  <d:href>{}</d:href>\n", escape_xml(h)))
+                .collect();
+            let body = format!(
+                r#"<?xml version="1.0" encoding="utf-8"?>
+<c:calendar-multiget xmlns:d="DAV:" xmlns:c="urn:ietf:params:xml:ns:caldav">
+  <d:prop>
+    <d:getetag/>
+    <c:calendar-data/>
+  </d:prop>
+{hrefs}</c:calendar-multiget>"#
+            );
+
+            for r in self
+                .multistatus("REPORT", &calendar.url, "1", body)
+                .await?
+                .responses
+            {
+                match r.props.calendar_data {
+                    Some(body) => {
+                        items.insert(
+                            r.href,
+                            Item {
+                                etag: r.props.etag,
+                                body,
+                            },
+                        );
+                    }
+                    None => {
+                        items.remove(&r.href);
+                    }
+                }
+            }
+        }
+
+        calendar.sync_token = status.sync_token.or(Some(token.to_string()));
+
+        Ok(())
+    }
+
+    /// Brings the items of `calendar` up to date, incrementally when it has a sync token
+    async fn sync(
+        &self,
+        calendar: &mut Calendar,
+        after: DateTime<Local>,
+    ) -> Result<(), EventListError> {
+        if let (Some(token), Some(_)) = (calendar.sync_token.clone(), &calendar.items) {
+            match self.sync_collection(calendar, &token).await {
+                Ok(()) => return Ok(()),
+                Err(SyncError::InvalidToken) => {
+                    log::info!(
+                        "Sync token of {} expired, fetching everything",
+                        calendar.url
+                    )
+                }
+                Err(SyncError::Other(e)) => return Err(e),
+            }
+        }
+
+        self.full_query(calendar, after).await
+    }
+}
+
+impl CalDavList {
+    /// The url, etag and body of the item holding the event with `id`, or its series.
+    /// Calendars are synced first if it isn't among the items fetched so far.
+    async fn locate(&self, id: &str) -> Result<(Url, Option<String>, String), EventListError> {
+        let find = |calendars: &[Calendar]| {
+            calendars.iter().find_map(|c| {
+                c.items
+                    .iter()
+                    .flatten()
+                    .find(|(_, item)| ics::edit::holds(&item.body, id))
+                    .map(|(href, item)| (href.clone(), item.etag.clone(), item.body.clone()))
+            })
+        };
+
+        let mut calendars = self.calendars.lock().await;
+        if calendars.is_none() {
+            *calendars = Some(self.discover().await?);
+        }
+        let calendars = calendars.get_or_insert_with(Vec::new);
+
+        if find(calendars).is_none() {
+            for calendar in calendars.iter_mut() {
+                self.sync(calendar, Local::now()).await?;
+            }
+        }
+
+        let (href, etag, body) =
+            find(calendars).ok_or_else(|| EventListError::NotFound(id.to_string()))?;
+
+        Ok((self.join(&href)?, etag, body))
+    }
+
+    /// Sends a `PUT` or `DELETE` of the item holding `uid`
+    async fn write(&self, req: reqwest::RequestBuilder, uid: &str) -> Result<(), EventListError> {
+        let resp = req.send().await?;
+
+        match resp.status() {
+            StatusCode::NOT_FOUND | StatusCode::GONE => {
+                Err(EventListError::NotFound(uid.to_string()))
+            }
+            StatusCode::PRECONDITION_FAILED => Err(EventListError::Network(format!(
+                "{uid} was changed on the server, try again once it has been synced"
+            ))),
+            _ => {
+                resp.error_for_status()?;
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Writes to an occurrence of a recurring event override or exclude it within the item of its
+/// series. Items are guarded by their etag, changes made elsewhere since the last sync fail
+/// instead of being overwritten.
+#[async_trait::async_trait]
+impl EventSink for CalDavList {
+    /// New events are put in the first calendar
+    async fn create(&self, event: &Event) -> Result<String, EventListError> {
+        let mut calendars = self.calendars.lock().await;
+        if calendars.is_none() {
+            *calendars = Some(self.discover().await?);
+        }
+
+        let mut url = calendars
+            .iter()
+            .flatten()
+            .next()
+            .ok_or_else(|| EventListError::Parse(format!("{} has no calendars", self.name)))?
+            .url
+            .clone();
+        drop(calendars);
+
+        let uid = ics::write::new_uid();
+        url.path_segments_mut()
+            .map_err(|_| EventListError::Parse(format!("{} can't hold items", self.name)))?
+            .pop_if_empty()
+            .push(&format!("{uid}.ics"));
+
+        let req = self
+            .request(Method::PUT, &url)
+            .header(header::CONTENT_TYPE, "text/calendar; charset=utf-8")
+            .header(header::IF_NONE_MATCH, "*")
+            .body(ics::write::event_to_ics(event, &uid));
+        self.write(req, &uid).await?;
+
+        Ok(uid)
+    }
+
+    async fn update(&self, id: &str, event: &Event) -> Result<(), EventListError> {
+        let (url, etag, body) = self.locate(id).await?;
+        let body = ics::edit::update(&body, self.timezone, id, event)?;
+
+        let mut req = self
+            .request(Method::PUT, &url)
+            .header(header::CONTENT_TYPE, "text/calendar; charset=utf-8")
+            .body(body);
+        if let Some(etag) = etag {
+            req = req.header(header::IF_MATCH, etag);
+        }
+
+        self.write(req, id).await
+    }
+
+    async fn delete(&self, id: &str) -> Result<(), EventListError> {
+        let (url, etag, body) = self.locate(id).await?;
+
+        let mut req = match ics::edit::delete(&body, self.timezone, id)? {
+            Some(body) => self
+                .request(Method::PUT, &url)
+                .header(header::CONTENT_TYPE, "text/calendar; charset=utf-8")
+                .body(body),
+            None => self.request(Method::DELETE, &url),
+        };
+        if let Some(etag) = etag {
+            req = req.header(header::IF_MATCH, etag);
+        }
+
+        self.write(req, id).await
+    }
+}
+
+#[async_trait::async_trait]
+impl EventList for CalDavList {
+    async fn init(&mut self) {}
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn sink(&self) -> Option<&dyn EventSink> {
+        Some(self)
+    }
+
+    async fn events(&self, after: DateTime<Local>) -> Result<Vec<Event>, EventListError> {
+        let mut calendars = self.calendars.lock().await;
+        if calendars.is_none() {
+            *calendars = Some(self.discover().await?);
+        }
+
+        let mut out = Vec::new();
+        for calendar in calendars.iter_mut().flatten() {
+            self.sync(calendar, after).await?;
+
+            for (href, item) in calendar.items.iter().flatten() {
+                match ics::parse_events_in(&item.body, after, after + self.horizon, self.timezone) {
+                    Ok(events) => out.extend(events),
+                    // One broken item shouldn't hide the rest of the calendar
+                    Err(e) => log::warn!("Skipping {href}: {e}"),
+                }
+            }
+        }
+
+        out.sort_by_key(|e| e.start);
+
+        Ok(out)
+    }
+}
@@ -1,165 +1,603 @@
-use crate::{Event, EventList};
+use crate::{Event, EventList, EventListError, EventSink, EventStatus, EventTime, Person, SignIn};
 
-use google_calendar::Client;
+use super::{
+    google_auth::GoogleAuth, http_client, token_store::TokenStore, SyncError, DEFAULT_HORIZON_DAYS,
+};
+
+use chrono::{DateTime, Duration, Local, SecondsFormat, Utc};
+use reqwest::{StatusCode, Url};
 
 use tokio::sync::Mutex;
 
-use tokio::sync::mpsc::{self, Sender};
+use std::{collections::HashMap, sync::Arc};
 
-use hyper::service::{make_service_fn, service_fn};
-use hyper::{Body, Request, Response, Server};
-use std::collections::HashMap;
-use std::{convert::Infallible, net::SocketAddr};
+/// Converts a Google start or end into an `EventTime`.
+/// All-day events only carry a `date`, timed events a `date_time`.
+fn event_time(t: &google_calendar::types::EventDateTime) -> Option<EventTime> {
+    match (t.date_time, t.date) {
+        (Some(d), _) => Some(EventTime::DateTime(d.into())),
+        (None, Some(d)) => Some(EventTime::Date(d)),
+        (None, None) => None,
+    }
+}
 
-#[derive(Clone, Debug)]
-struct ClientInfo {
-    pub code: String,
-    pub state: String,
+/// `None` for the empty strings the google calendar client uses for missing values
+fn non_empty(s: String) -> Option<String> {
+    Some(s).filter(|s| !s.is_empty())
 }
 
-static INFO_SENDER: Mutex<Option<Sender<ClientInfo>>> = Mutex::const_new(None);
+/// Converts a Google attendee response into the iCal `PARTSTAT` spelling used by `Person`
+fn response(status: &str) -> String {
+    match status {
+        "needsAction" => "needs-action".to_string(),
+        s => s.to_string(),
+    }
+}
 
-async fn handle(req: Request<Body>) -> Result<Response<Body>, Infallible> {
-    match req.uri().path() {
-        "/auth/" => {
-            log::info!("{:?}", req);
-            let mut vars = HashMap::new();
+/// The color Google shows for an event `colorId`, `None` for the calendar's own color
+fn event_color(id: &str) -> Option<&'static str> {
+    let color = match id {
+        "1" => "#7986cb",
+        "2" => "#33b679",
+        "3" => "#8e24aa",
+        "4" => "#e67c73",
+        "5" => "#f6bf26",
+        "6" => "#f4511e",
+        "7" => "#039be5",
+        "8" => "#616161",
+        "9" => "#3f51b5",
+        "10" => "#0b8043",
+        "11" => "#d50000",
+        _ => return None,
+    };
+    Some(color)
+}
 
-            for l in req.uri().query().unwrap().split('&') {
-                let parts = l.split('=').collect::<Vec<_>>();
+/// The Google spelling of `status`
+fn google_status(status: EventStatus) -> &'static str {
+    match status {
+        EventStatus::Tentative => "tentative",
+        EventStatus::Confirmed => "confirmed",
+        EventStatus::Cancelled => "cancelled",
+    }
+}
 
-                assert_eq!(parts.len(), 2);
+/// The start and end of `event` for Google, which needs an end. Events without one last
+/// a day if they are all-day and an hour otherwise.
+fn start_end(event: &Event) -> Result<(EventTime, EventTime), EventListError> {
+    let start = event
+        .start
+        .ok_or_else(|| EventListError::Parse("Events need a start".to_string()))?;
+    let end = event.end.unwrap_or_else(|| match start {
+        EventTime::Date(_) => start + Duration::days(1),
+        EventTime::DateTime(_) => start + Duration::hours(1),
+    });
+
+    Ok((start, end))
+}
 
-                vars.insert(parts[0], parts[1]);
-            }
+/// A Google start or end, clearing the other of `date` and `dateTime` for a PATCH
+fn patch_time(t: EventTime) -> serde_json::Value {
+    match t {
+        EventTime::DateTime(d) => serde_json::json!({"date": null, "dateTime": d.to_rfc3339()}),
+        EventTime::Date(d) => serde_json::json!({"date": d.to_string(), "dateTime": null}),
+    }
+}
 
-            let code = vars.get("code").unwrap().to_string();
-            let state = vars.get("state").unwrap().to_string();
+/// The fields of a Google event that `Event` models. Updates send them as a PATCH, so the
+/// recurrence, attendees, reminders, conference data and color of the event are kept.
+fn google_patch(event: &Event) -> Result<serde_json::Value, EventListError> {
+    let (start, end) = start_end(event)?;
+
+    let mut patch = serde_json::json!({
+        "summary": event.title,
+        "description": event.desc,
+        "location": event.location.as_deref().unwrap_or_default(),
+        "start": patch_time(start),
+        "end": patch_time(end),
+        "transparency": if event.transparent { "transparent" } else { "opaque" },
+    });
+    if let Some(status) = event.status {
+        patch["status"] = google_status(status).into();
+    }
 
-            INFO_SENDER
-                .lock()
-                .await
-                .as_ref()
-                .unwrap()
-                .send(ClientInfo { code, state })
-                .await
-                .unwrap();
+    Ok(patch)
+}
 
-            log::info!("Responding");
+/// Converts an event of `calendar`
+fn to_event(e: &google_calendar::types::Event, calendar: &CalendarInfo) -> Event {
+    Event {
+        title: e.summary.clone(),
+        desc: e.description.clone(),
+        start: e.start.as_ref().and_then(event_time),
+        end: e.end.as_ref().and_then(event_time),
+        class: None,
+        location: non_empty(e.location.clone()),
+        status: EventStatus::parse(&e.status),
+        organizer: e.organizer.as_ref().map(|o| Person {
+            name: non_empty(o.display_name.clone()),
+            email: non_empty(o.email.clone()),
+            response: None,
+        }),
+        attendees: e
+            .attendees
+            .iter()
+            .map(|a| Person {
+                name: non_empty(a.display_name.clone()),
+                email: non_empty(a.email.clone()),
+                response: non_empty(a.response_status.clone()).map(|r| response(&r)),
+            })
+            .collect(),
+        categories: Vec::new(),
+        color: event_color(&e.color_id)
+            .map(str::to_string)
+            .or_else(|| calendar.color.clone()),
+        transparent: e.transparency == "transparent",
+        id: non_empty(e.id.clone()),
+        series_id: non_empty(e.recurring_event_id.clone()),
+        source: None,
+        calendar: calendar.name.clone(),
+        url: non_empty(e.html_link.clone()),
+        last_modified: e.updated,
+    }
+}
 
-            Ok(Response::new("".into()))
-        }
-        _ => Ok(Response::builder()
-            .status(404)
-            .body(Body::from(""))
-            .unwrap()),
+/// The answer of the API, or an error if it failed. Missing items are `NotFound(id)`.
+fn check(resp: reqwest::Response, id: &str) -> Result<reqwest::Response, EventListError> {
+    match resp.status() {
+        StatusCode::NOT_FOUND | StatusCode::GONE => Err(EventListError::NotFound(id.to_string())),
+        _ => Ok(resp.error_for_status()?),
+    }
+}
+
+/// The error for a `403`. Tokens that weren't granted a scope the request needs, like those
+/// from before the calendar list was read, are `Unauthenticated` so the user signs in again.
+async fn forbidden(resp: reqwest::Response) -> EventListError {
+    let challenge = resp
+        .headers()
+        .get(reqwest::header::WWW_AUTHENTICATE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+    let body = resp.text().await.unwrap_or_default();
+
+    if challenge.contains("insufficient_scope")
+        || body.contains("ACCESS_TOKEN_SCOPE_INSUFFICIENT")
+        || body.contains("insufficientPermissions")
+    {
+        return EventListError::Unauthenticated(
+            "the saved Google token lacks a permission this needs, sign in again at \
+             /auth/google/start"
+                .to_string(),
+        );
+    }
+
+    let message = serde_json::from_str::<serde_json::Value>(&body)
+        .ok()
+        .and_then(|v| v["error"]["message"].as_str().map(str::to_string))
+        .unwrap_or_else(|| StatusCode::FORBIDDEN.to_string());
+    EventListError::Auth(format!("Google refused the request: {message}"))
+}
+
+/// Events requested per page, the most the API allows
+const PAGE_SIZE: &str = "2500";
+
+/// Days a full sync fetches past the horizon, so it's only repeated about monthly
+const RESYNC_DAYS: i64 = 30;
+
+/// The events of a calendar as of `sync_token`
+#[derive(Default)]
+struct SyncedCalendar {
+    /// Token for the changes since the last sync, `None` until the first full sync
+    sync_token: Option<String>,
+    /// Events by id, without deleted ones
+    events: HashMap<String, google_calendar::types::Event>,
+    /// Title of the calendar, as the events list gives it
+    summary: Option<String>,
+    /// End of the window the last full sync fetched, later occurrences are missing
+    until: Option<DateTime<Utc>>,
+}
+
+/// The events list of a calendar, joined from all its pages
+struct Changes {
+    events: Vec<google_calendar::types::Event>,
+    sync_token: Option<String>,
+    summary: Option<String>,
+}
+
+/// The OAuth client the app signs in to Google as
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GoogleCredentials {
+    pub client_id: String,
+    pub client_secret: String,
+}
+
+impl GoogleCredentials {
+    /// Reads a client secret JSON file as downloaded from the Google Cloud console,
+    /// for either a desktop or a web client
+    pub fn from_json_file(path: &str) -> Result<Self, EventListError> {
+        let data = std::fs::read_to_string(path)
+            .map_err(|e| EventListError::Auth(format!("Can't read {path}: {e}")))?;
+        let json: serde_json::Value = serde_json::from_str(&data)?;
+
+        let client = json
+            .get("installed")
+            .or_else(|| json.get("web"))
+            .unwrap_or(&json);
+        let field = |key: &str| {
+            client[key].as_str().map(str::to_string).ok_or_else(|| {
+                EventListError::Parse(format!("{path} has no {key}, is it a client secret file?"))
+            })
+        };
+
+        Ok(Self {
+            client_id: field("client_id")?,
+            client_secret: field("client_secret")?,
+        })
+    }
+
+    /// Reads `GOOGLE_CLIENT_ID` and `GOOGLE_CLIENT_SECRET`, `None` unless both are set
+    pub fn from_env() -> Option<Self> {
+        let var = |key| std::env::var(key).ok().filter(|v| !v.is_empty());
+        Some(Self {
+            client_id: var("GOOGLE_CLIENT_ID")?,
+            client_secret: var("GOOGLE_CLIENT_SECRET")?,
+        })
     }
 }
 
 /// Represents a Client connection to a google calendar
 pub struct GoogleList {
-    calendar: Client,
+    /// The session to query with, once the user has signed in
+    auth: Arc<GoogleAuth>,
+    http: reqwest::Client,
+    /// Base url of the Calendar API
+    host: Url,
+    /// Events of each shown calendar by calendar id, updated incrementally
+    synced: Mutex<HashMap<String, SyncedCalendar>>,
+    /// Ids or names of the calendars to show. Empty shows those selected in Google Calendar.
+    selection: Vec<String>,
+    /// Id of the calendar each event was last seen in, so writes reach the right one
+    event_calendars: Mutex<HashMap<String, String>>,
+    /// How far past the requested time recurring events are expanded
+    horizon: Duration,
 }
 
-impl GoogleList {
-    /// Attempts to load the auth token from a file.
-    /// If it could not runs `new`
-    pub async fn from_file(name: &str) -> Self {
-        let data = String::from_utf8(std::fs::read(name).unwrap()).unwrap();
-
-        let google_calender = match data.split('\n').collect::<Vec<_>>()[..] {
-            [token, refresh_token] => Client::new(
-                include_str!("../../tokens/client_id.token").to_string(),
-                include_str!("../../tokens/client_secret.token").to_string(),
-                "http://localhost:3000/auth/".to_string(),
-                token.to_string(),
-                refresh_token.to_string(),
+/// A calendar to show
+#[derive(Clone)]
+struct CalendarInfo {
+    id: String,
+    /// Name from the calendar list, `None` for calendars picked by id without it
+    name: Option<String>,
+    color: Option<String>,
+}
+
+impl CalendarInfo {
+    fn new(entry: &google_calendar::types::CalendarListEntry) -> Self {
+        Self {
+            id: entry.id.clone(),
+            name: Some(
+                non_empty(entry.summary_override.clone()).unwrap_or_else(|| entry.summary.clone()),
             ),
-            _ => unreachable!(),
-        };
+            color: non_empty(entry.background_color.clone()),
+        }
+    }
 
-        match google_calender.refresh_access_token().await {
-            Err(e) => {
-                log::error!("Error Refreshing token: {e}");
-                log::error!("Starting new token generation");
-                return Self::new(name).await;
-            }
-            Ok(at) => {
-                if at.access_token != data.split('\n').collect::<Vec<_>>()[0] {
-                    std::fs::write(
-                        name,
-                        format!("{}\n{}", at.access_token, at.refresh_token,),
-                    )
-                    .unwrap();
-                }
-            }
-        };
+    /// Whether `selector` names this calendar, by id, by name ignoring case, or as `primary`
+    fn matches(&self, entry: &google_calendar::types::CalendarListEntry, selector: &str) -> bool {
+        self.id == selector
+            || self
+                .name
+                .as_ref()
+                .is_some_and(|n| n.eq_ignore_ascii_case(selector))
+            || (entry.primary && selector == "primary")
+    }
+}
 
+/// Whether `selector` is a calendar id rather than a name: `primary`, or an address like
+/// `someone@gmail.com` or `...@group.calendar.google.com`
+fn is_calendar_id(selector: &str) -> bool {
+    selector == "primary" || selector.contains('@')
+}
+
+impl GoogleList {
+    /// Signs in with the tokens saved in `store`. If there are none the list is signed out
+    /// until the user signs in, as it is once Google revokes them.
+    pub async fn from_store(store: Box<dyn TokenStore>, credentials: &GoogleCredentials) -> Self {
+        let list = Self::new(store, credentials);
+        list.auth.load().await;
+        list
+    }
+
+    /// A signed out list of the OAuth app `credentials`, saving its tokens to `store`
+    /// once the user signs in through `sign_in`
+    pub fn new(store: Box<dyn TokenStore>, credentials: &GoogleCredentials) -> Self {
         Self {
-            calendar: google_calender,
+            auth: Arc::new(GoogleAuth::new(credentials, store)),
+            http: http_client(),
+            host: Url::parse(google_calendar::DEFAULT_HOST).expect("Valid url"),
+            synced: Mutex::new(HashMap::new()),
+            selection: Vec::new(),
+            event_calendars: Mutex::new(HashMap::new()),
+            horizon: Duration::days(DEFAULT_HORIZON_DAYS),
         }
     }
 
-    /// Runs the client auth and saves it to `out_name`
-    pub async fn new(out_name: &str) -> Self {
-        let addr = SocketAddr::from(([127, 0, 0, 1], 3000));
+    /// Only shows the calendars with these ids or names, `primary` being the user's own
+    pub fn with_calendars(mut self, selection: Vec<String>) -> Self {
+        self.selection = selection;
+        self
+    }
 
-        let make_svc = make_service_fn(|_conn| async { Ok::<_, Infallible>(service_fn(handle)) });
+    /// Sends the API requests to `host` instead of Google's, e.g. a mock server
+    pub fn with_host(mut self, host: Url) -> Self {
+        self.host = host;
+        self
+    }
 
-        let (sender, mut reciever) = mpsc::channel::<ClientInfo>(16);
+    /// Exchanges codes and refresh tokens at `url` instead of Google's token endpoint
+    pub fn with_token_url(mut self, url: Url) -> Self {
+        Arc::get_mut(&mut self.auth)
+            .expect("Not shared before the list is used")
+            .token_url = url;
+        self
+    }
 
-        *INFO_SENDER.lock().await = Some(sender);
+    /// The API url of the resource at `path`
+    fn url(&self, path: &[&str]) -> Url {
+        let mut url = self.host.clone();
+        url.path_segments_mut()
+            .expect("Base url")
+            .pop_if_empty()
+            .extend(path);
+        url
+    }
 
-        let mut google_calender = Client::new(
-            include_str!("../../tokens/client_id.token").to_string(),
-            include_str!("../../tokens/client_secret.token").to_string(),
-            "http://localhost:3000/auth/".to_string(),
-            "".to_string(),
-            "".to_string(),
-        );
+    /// Every entry of the user's calendar list
+    async fn calendar_list(
+        &self,
+    ) -> Result<Vec<google_calendar::types::CalendarListEntry>, EventListError> {
+        let url = self.url(&["users", "me", "calendarList"]);
+        let mut out = Vec::new();
+        let mut page_token: Option<String> = None;
+
+        loop {
+            let mut page_url = url.clone();
+            if let Some(token) = &page_token {
+                page_url.query_pairs_mut().append_pair("pageToken", token);
+            }
+
+            let resp = check(self.get(&page_url).await?, "calendarList")?;
+            let page: google_calendar::types::CalendarList =
+                serde_json::from_str(&resp.text().await?)?;
+
+            out.extend(page.items);
+            match non_empty(page.next_page_token) {
+                Some(token) => page_token = Some(token),
+                None => return Ok(out),
+            }
+        }
+    }
 
-        let user_consent_url = google_calender
-            .user_consent_url(&["https://www.googleapis.com/auth/calendar.events".to_string()]);
+    /// The calendars to show. Ids are used as they are, only names and the default selection
+    /// need the user's calendar list, which tokens from before it was read have no scope for.
+    async fn calendars(&self) -> Result<Vec<CalendarInfo>, EventListError> {
+        if !self.selection.is_empty() && self.selection.iter().all(|s| is_calendar_id(s)) {
+            let mut out: Vec<CalendarInfo> = Vec::new();
+            for id in &self.selection {
+                if !out.iter().any(|c| c.id == *id) {
+                    out.push(CalendarInfo {
+                        id: id.clone(),
+                        name: None,
+                        color: None,
+                    });
+                }
+            }
+            return Ok(out);
+        }
 
-        let server = Server::bind(&addr).serve(make_svc);
+        let entries = self.calendar_list().await?;
 
-        match open::that(&user_consent_url) {
-            Ok(_) => {}
-            Err(e) => {
-                log::error!("Failed to open consent url \"{user_consent_url}\" for reason {e:?}")
+        if self.selection.is_empty() {
+            return Ok(entries
+                .iter()
+                .filter(|e| e.selected || e.primary)
+                .map(CalendarInfo::new)
+                .collect());
+        }
+
+        let all: Vec<_> = entries.iter().map(|e| (e, CalendarInfo::new(e))).collect();
+        let mut out: Vec<CalendarInfo> = Vec::new();
+        for selector in &self.selection {
+            match all.iter().find(|(e, c)| c.matches(e, selector)) {
+                Some((e, _)) if out.iter().any(|c| c.id == e.id) => {}
+                Some((e, _)) => out.push(CalendarInfo::new(e)),
+                None => log::warn!("No Google calendar matches {selector:?}"),
             }
-        };
+        }
+        Ok(out)
+    }
 
-        let server_proc = tokio::spawn(async { server.await });
+    /// Id of the calendar new events go to: the first one picked, or the user's own one
+    async fn new_event_calendar(&self) -> Result<String, EventListError> {
+        if self.selection.is_empty() {
+            return Ok("primary".to_string());
+        }
 
-        let client_info = reciever.recv().await;
+        self.calendars()
+            .await?
+            .into_iter()
+            .next()
+            .map(|c| c.id)
+            .ok_or_else(|| {
+                EventListError::Network("none of the picked Google calendars exist".to_string())
+            })
+    }
 
-        server_proc.abort();
+    /// Id of the calendar `id` was last seen in, the primary one for unknown events
+    async fn calendar_of(&self, id: &str) -> String {
+        self.event_calendars
+            .lock()
+            .await
+            .get(id)
+            .cloned()
+            .unwrap_or_else(|| "primary".to_string())
+    }
 
-        log::info!("Sever finisished");
+    /// GETs `url` with the access token, refreshing it once if it was rejected
+    async fn get(&self, url: &Url) -> Result<reqwest::Response, EventListError> {
+        self.send(|| self.http.get(url.clone())).await
+    }
 
-        if let Some(c) = client_info {
-            let token = google_calender
-                .get_access_token(&c.code, &c.state)
-                .await
-                .unwrap();
+    /// Sends the request made by `build` with the access token, refreshing it once if it
+    /// was rejected. A `403` is an error.
+    async fn send(
+        &self,
+        build: impl Fn() -> reqwest::RequestBuilder,
+    ) -> Result<reqwest::Response, EventListError> {
+        let send = |token: String| build().bearer_auth(token).send();
+
+        let session = self.auth.session().await?;
+        let mut resp = send(session.access_token.clone()).await?;
+        if resp.status() == StatusCode::UNAUTHORIZED {
+            log::debug!("Google access token rejected, refreshing it");
+            let session = self.auth.refresh(&session).await?;
+            resp = send(session.access_token).await?;
+        }
 
-            std::fs::write(
-                out_name,
-                format!(
-                    "{}\n{}",
-                    token.access_token,
-                    token.refresh_token,
-                ),
+        if resp.status() == StatusCode::FORBIDDEN {
+            return Err(forbidden(resp).await);
+        }
+
+        Ok(resp)
+    }
+
+    /// Every event of `calendar_id` changed since `sync_token`, or without one all of them that
+    /// overlap `window`, along with the token for the next sync
+    async fn changes(
+        &self,
+        calendar_id: &str,
+        sync_token: Option<&str>,
+        window: (DateTime<Utc>, DateTime<Utc>),
+    ) -> Result<Changes, SyncError> {
+        let url = self.url(&["calendars", calendar_id, "events"]);
+
+        let mut out = Vec::new();
+        let mut page_token: Option<String> = None;
+
+        loop {
+            let mut page_url = url.clone();
+            {
+                let mut query = page_url.query_pairs_mut();
+                query
+                    .append_pair("maxResults", PAGE_SIZE)
+                    .append_pair("showHiddenInvitations", "true")
+                    // Occurrences of recurring events one by one, as iCal sources expand them
+                    .append_pair("singleEvents", "true");
+                // The sync token remembers the window of the full sync, it can't be sent again
+                match sync_token {
+                    Some(token) => query.append_pair("syncToken", token),
+                    None => query
+                        .append_pair(
+                            "timeMin",
+                            &window.0.to_rfc3339_opts(SecondsFormat::Secs, true),
+                        )
+                        .append_pair(
+                            "timeMax",
+                            &window.1.to_rfc3339_opts(SecondsFormat::Secs, true),
+                        ),
+                };
+                if let Some(token) = &page_token {
+                    query.append_pair("pageToken", token);
+                }
+            }
+
+            let resp = self.get(&page_url).await?;
+            if resp.status() == StatusCode::GONE {
+                return Err(SyncError::InvalidToken);
+            }
+            let page: google_calendar::types::Events = serde_json::from_str(
+                &resp
+                    .error_for_status()
+                    .map_err(EventListError::from)?
+                    .text()
+                    .await
+                    .map_err(EventListError::from)?,
             )
-            .unwrap();
-        } else {
-            panic!("No client info!");
+            .map_err(EventListError::from)?;
+
+            out.extend(page.items);
+            match non_empty(page.next_page_token) {
+                Some(token) => page_token = Some(token),
+                None => {
+                    return Ok(Changes {
+                        events: out,
+                        sync_token: non_empty(page.next_sync_token),
+                        summary: non_empty(page.summary),
+                    })
+                }
+            }
         }
+    }
 
-        Self {
-            calendar: google_calender,
+    /// Brings the events of `calendar_id` after `after` up to date, incrementally when it has a
+    /// sync token and the window of its last full sync still covers the horizon
+    async fn sync(
+        &self,
+        calendar_id: &str,
+        synced: &mut SyncedCalendar,
+        after: DateTime<Local>,
+    ) -> Result<(), EventListError> {
+        let after = after.with_timezone(&Utc);
+        if synced
+            .until
+            .is_some_and(|until| until < after + self.horizon)
+        {
+            log::info!("Google calendar {calendar_id} needs events further ahead, fetching again");
+            synced.sync_token = None;
         }
+        // Fetching past the horizon means a full sync is only needed every so often
+        let window = (after, after + self.horizon + Duration::days(RESYNC_DAYS));
+
+        let mut full = synced.sync_token.is_none();
+        let fetched = match self
+            .changes(calendar_id, synced.sync_token.as_deref(), window)
+            .await
+        {
+            Err(SyncError::InvalidToken) if !full => {
+                log::info!(
+                    "Sync token of Google calendar {calendar_id} expired, fetching everything"
+                );
+                full = true;
+                self.changes(calendar_id, None, window).await
+            }
+            fetched => fetched,
+        };
+        let changes = fetched.map_err(|e| match e {
+            SyncError::InvalidToken => {
+                EventListError::Network(format!("Google calendar {calendar_id} is gone"))
+            }
+            SyncError::Other(e) => e,
+        })?;
+
+        if full {
+            synced.events.clear();
+            synced.until = Some(window.1);
+        } else {
+            log::debug!("{} events changed in {calendar_id}", changes.events.len());
+        }
+        for e in changes.events {
+            // Deleted events only come back as cancelled stubs
+            if e.status == "cancelled" {
+                synced.events.remove(&e.id);
+            } else {
+                synced.events.insert(e.id.clone(), e);
+            }
+        }
+        synced.sync_token = changes.sync_token;
+        synced.summary = changes.summary.or(synced.summary.take());
+
+        Ok(())
     }
 }
 
@@ -167,36 +605,89 @@ impl GoogleList {
 impl EventList for GoogleList {
     async fn init(&mut self) {}
 
-    async fn events(&self, after: chrono::DateTime<chrono::Local>) -> Vec<Event> {
-        self
-            .calendar
-            .events()
-            .list_all(
-                "primary",
-                "",
-                0,
-                google_calendar::types::OrderBy::Noop,
-                &[],
-                "",
-                &[],
-                false,
-                true,
-                false,
-                "",
-                &after.to_rfc3339(),
-                "",
-                "",
-            )
-            .await
-            .unwrap()
-            .into_iter()
-            .map(|e| Event {
-                title: e.summary.clone(),
-                desc: e.description.clone(),
-                start: e.start.as_ref().and_then(|m| m.date_time).map(|a| a.into()),
-                end: e.end.as_ref().and_then(|m| m.date_time).map(|a| a.into()),
-                class: None,
-            })
-            .collect::<Vec<_>>()
+    fn name(&self) -> &str {
+        "Google Calendar"
+    }
+
+    fn sink(&self) -> Option<&dyn EventSink> {
+        Some(self)
+    }
+
+    fn sign_in(&self) -> Option<Arc<dyn SignIn>> {
+        Some(self.auth.clone())
+    }
+
+    async fn events(&self, after: DateTime<Local>) -> Result<Vec<Event>, EventListError> {
+        let calendars = self.calendars().await?;
+        let mut synced = self.synced.lock().await;
+        synced.retain(|id, _| calendars.iter().any(|c| c.id == *id));
+
+        let mut out = Vec::new();
+        let mut event_calendars = HashMap::new();
+
+        for calendar in &calendars {
+            let synced = synced.entry(calendar.id.clone()).or_default();
+            self.sync(&calendar.id, synced, after).await?;
+            let calendar = &CalendarInfo {
+                name: calendar.name.clone().or_else(|| synced.summary.clone()),
+                ..calendar.clone()
+            };
+
+            for e in synced.events.values() {
+                // Events shared between the selected calendars are only shown once
+                if event_calendars.contains_key(&e.id) {
+                    continue;
+                }
+
+                let event = to_event(e, calendar);
+                let ends = event.end.or(event.start).map(|t| t.instant());
+                if ends.is_some_and(|end| end <= after) {
+                    continue;
+                }
+
+                event_calendars.insert(e.id.clone(), calendar.id.clone());
+                out.push(event);
+            }
+        }
+
+        *self.event_calendars.lock().await = event_calendars;
+        Ok(out)
+    }
+}
+
+#[async_trait::async_trait]
+impl EventSink for GoogleList {
+    async fn create(&self, event: &Event) -> Result<String, EventListError> {
+        let event = google_patch(event)?;
+        let calendar = self.new_event_calendar().await?;
+        let url = self.url(&["calendars", &calendar, "events"]);
+
+        let resp = check(
+            self.send(|| self.http.post(url.clone()).json(&event))
+                .await?,
+            &calendar,
+        )?;
+        let created: google_calendar::types::Event = serde_json::from_str(&resp.text().await?)?;
+
+        Ok(created.id)
+    }
+
+    /// Only changes the fields `Event` models, whatever else the Google event has is kept
+    async fn update(&self, id: &str, event: &Event) -> Result<(), EventListError> {
+        let patch = google_patch(event)?;
+        let url = self.url(&["calendars", &self.calendar_of(id).await, "events", id]);
+
+        check(
+            self.send(|| self.http.patch(url.clone()).json(&patch))
+                .await?,
+            id,
+        )
+        .map(|_| ())
+    }
+
+    async fn delete(&self, id: &str) -> Result<(), EventListError> {
+        let url = self.url(&["calendars", &self.calendar_of(id).await, "events", id]);
+
+        check(self.send(|| self.http.delete(url.clone())).await?, id).map(|_| ())
     }
 }
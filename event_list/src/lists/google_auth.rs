@@ -0,0 +1,352 @@
+use crate::{EventListError, SignIn};
+
+use super::{
+    google::GoogleCredentials,
+    http_client,
+    token_store::{StoredToken, TokenStore},
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, Utc};
+use ring::{digest, rand::SecureRandom};
+use serde::Deserialize;
+
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use tokio::sync::{Mutex, RwLock};
+
+const AUTH_URL: &str = "https://accounts.google.com/o/oauth2/v2/auth";
+const TOKEN_URL: &str = "https://oauth2.googleapis.com/token";
+
+/// Reading the calendar list and reading and writing events
+const SCOPES: &str = "https://www.googleapis.com/auth/calendar.readonly \
+                      https://www.googleapis.com/auth/calendar.events";
+
+/// How long a started sign in can take before its state is forgotten
+const SIGN_IN_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// How long before it expires an access token is replaced
+const EXPIRY_MARGIN: chrono::Duration = chrono::Duration::seconds(60);
+
+/// The tokens a signed in source currently uses
+#[derive(Clone)]
+pub(crate) struct Session {
+    pub access_token: String,
+    refresh_token: String,
+    expires_at: Option<DateTime<Utc>>,
+}
+
+impl Session {
+    fn new(token: StoredToken) -> Self {
+        Self {
+            access_token: token.access_token,
+            refresh_token: token.refresh_token,
+            expires_at: token.expires_at,
+        }
+    }
+
+    /// Whether the access token is about to expire
+    fn expiring(&self) -> bool {
+        self.expires_at
+            .is_some_and(|at| at - EXPIRY_MARGIN <= Utc::now())
+    }
+}
+
+/// A sign in waiting for the user to come back
+struct Pending {
+    /// PKCE code verifier, whose hash went along with the user
+    verifier: String,
+    redirect_uri: String,
+    started: Instant,
+}
+
+/// Answer of the token endpoint
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    /// Missing when refreshing, the refresh token stays the same
+    #[serde(default)]
+    refresh_token: String,
+    /// Seconds the access token is valid for
+    #[serde(default)]
+    expires_in: Option<i64>,
+}
+
+impl TokenResponse {
+    /// The tokens to keep, reusing `refresh_token` if no new one was given
+    fn into_stored(self, refresh_token: &str) -> StoredToken {
+        StoredToken {
+            expires_at: self
+                .expires_in
+                .map(|secs| Utc::now() + chrono::Duration::seconds(secs)),
+            refresh_token: if self.refresh_token.is_empty() {
+                refresh_token.to_string()
+            } else {
+                self.refresh_token
+            },
+            access_token: self.access_token,
+        }
+    }
+}
+
+/// Error answer of the token endpoint
+#[derive(Deserialize)]
+struct TokenError {
+    error: String,
+    #[serde(default)]
+    error_description: String,
+}
+
+/// `len` random bytes as unpadded base64url
+fn random_string(len: usize) -> Result<String, EventListError> {
+    let mut bytes = vec![0; len];
+    ring::rand::SystemRandom::new()
+        .fill(&mut bytes)
+        .map_err(|_| EventListError::Auth("No random numbers for the sign in".to_string()))?;
+    Ok(URL_SAFE_NO_PAD.encode(bytes))
+}
+
+/// The sign in of a Google source and the session it led to.
+/// Shared between the source and the server, which drives the sign in.
+pub(crate) struct GoogleAuth {
+    credentials: GoogleCredentials,
+    /// Where the tokens are kept between runs
+    store: Arc<dyn TokenStore>,
+    /// `None` until the user signs in
+    session: Arc<RwLock<Option<Session>>>,
+    /// Held while refreshing, so a token is refreshed once however many requests it failed
+    refreshing: Mutex<()>,
+    /// Sign ins started with `start`, by state
+    pending: std::sync::Mutex<HashMap<String, Pending>>,
+    http: reqwest::Client,
+    /// Endpoint the codes and refresh tokens are exchanged at
+    pub token_url: reqwest::Url,
+}
+
+impl GoogleAuth {
+    /// A signed out `GoogleAuth` that saves tokens to `store`
+    pub fn new(credentials: &GoogleCredentials, store: Box<dyn TokenStore>) -> Self {
+        Self {
+            credentials: credentials.clone(),
+            store: store.into(),
+            session: Arc::new(RwLock::new(None)),
+            refreshing: Mutex::new(()),
+            pending: std::sync::Mutex::new(HashMap::new()),
+            http: http_client(),
+            token_url: reqwest::Url::parse(TOKEN_URL).expect("Valid url"),
+        }
+    }
+
+    /// Signs in with the tokens saved in the store, if there are any.
+    /// They are refreshed once they expire, and forgotten if Google revoked them.
+    pub async fn load(&self) {
+        let token = match self.store.load() {
+            Ok(Some(token)) => token,
+            Ok(None) => {
+                log::info!("No Google token in {}", self.store.describe());
+                return;
+            }
+            Err(e) => {
+                log::error!("Can't read the Google token: {e}");
+                return;
+            }
+        };
+
+        *self.session.write().await = Some(Session::new(token));
+    }
+
+    /// The current session, an `Unauthenticated` error until the user signs in.
+    /// The access token is refreshed first if it is about to expire.
+    pub async fn session(&self) -> Result<Session, EventListError> {
+        let session = self.session.read().await.clone().ok_or_else(signed_out)?;
+        if session.expiring() {
+            self.refresh(&session).await
+        } else {
+            Ok(session)
+        }
+    }
+
+    /// Replaces the access token of `session`, after it expired or was rejected.
+    /// If Google revoked the refresh token the saved tokens are forgotten, and the
+    /// source waits for the user to sign in again.
+    pub async fn refresh(&self, session: &Session) -> Result<Session, EventListError> {
+        let _refreshing = self.refreshing.lock().await;
+
+        // Another request may have refreshed the token while this one waited
+        let current = self.session.read().await.clone().ok_or_else(signed_out)?;
+        if current.access_token != session.access_token {
+            return Ok(current);
+        }
+
+        let response = request_token(
+            &self.http,
+            &self.token_url,
+            &[
+                ("grant_type", "refresh_token"),
+                ("refresh_token", &current.refresh_token),
+                ("client_id", &self.credentials.client_id),
+                ("client_secret", &self.credentials.client_secret),
+            ],
+        )
+        .await;
+
+        match response {
+            Ok(token) => {
+                let token = token.into_stored(&current.refresh_token);
+                if let Err(e) = self.store.save(&token) {
+                    log::error!("Can't save the refreshed Google token: {e}");
+                }
+                let session = Session::new(token);
+                *self.session.write().await = Some(session.clone());
+                Ok(session)
+            }
+            Err(e) if e.error == "invalid_grant" => {
+                log::warn!("Google revoked the saved token, sign in again: {e}");
+                if let Err(e) = self.store.clear() {
+                    log::error!("Can't remove the revoked Google token: {e}");
+                }
+                *self.session.write().await = None;
+                Err(signed_out())
+            }
+            Err(e) if e.error == "request_failed" => Err(EventListError::Network(e.to_string())),
+            Err(e) => Err(EventListError::Auth(e.to_string())),
+        }
+    }
+}
+
+/// The error while no one is signed in
+fn signed_out() -> EventListError {
+    EventListError::Unauthenticated(
+        "sign in at /auth/google/start, or /auth/google/link without a browser".to_string(),
+    )
+}
+
+/// Saves the tokens the user signed in with and starts using them
+async fn signed_in(
+    store: &dyn TokenStore,
+    session: &RwLock<Option<Session>>,
+    token: TokenResponse,
+) {
+    let token = token.into_stored("");
+    match store.save(&token) {
+        Ok(()) => log::info!(
+            "Signed in to Google, saved the token to {}",
+            store.describe()
+        ),
+        Err(e) => log::error!("Signed in to Google, but can't save the token: {e}"),
+    }
+    *session.write().await = Some(Session::new(token));
+}
+
+/// POSTs `form` to the token endpoint at `url`
+async fn request_token(
+    http: &reqwest::Client,
+    url: &reqwest::Url,
+    form: &[(&str, &str)],
+) -> Result<TokenResponse, TokenError> {
+    let failed = |e: String| TokenError {
+        error: "request_failed".to_string(),
+        error_description: e,
+    };
+
+    let resp = http
+        .post(url.clone())
+        .form(form)
+        .send()
+        .await
+        .map_err(|e| failed(e.to_string()))?;
+    let ok = resp.status().is_success();
+    let body = resp.text().await.map_err(|e| failed(e.to_string()))?;
+
+    if ok {
+        serde_json::from_str(&body).map_err(|e| failed(e.to_string()))
+    } else {
+        Err(serde_json::from_str(&body).unwrap_or_else(|_| failed(body)))
+    }
+}
+
+impl std::fmt::Display for TokenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.error)?;
+        if !self.error_description.is_empty() {
+            write!(f, ": {}", self.error_description)?;
+        }
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl SignIn for GoogleAuth {
+    fn start(&self, redirect_uri: &str) -> Result<String, EventListError> {
+        let state = random_string(16)?;
+        let verifier = random_string(32)?;
+        let challenge =
+            URL_SAFE_NO_PAD.encode(digest::digest(&digest::SHA256, verifier.as_bytes()));
+
+        let mut pending = self.pending.lock().expect("Not poisoned");
+        pending.retain(|_, p| p.started.elapsed() < SIGN_IN_TIMEOUT);
+        pending.insert(
+            state.clone(),
+            Pending {
+                verifier,
+                redirect_uri: redirect_uri.to_string(),
+                started: Instant::now(),
+            },
+        );
+
+        let mut url = reqwest::Url::parse(AUTH_URL).expect("Valid url");
+        url.query_pairs_mut()
+            .append_pair("client_id", &self.credentials.client_id)
+            .append_pair("redirect_uri", redirect_uri)
+            .append_pair("response_type", "code")
+            .append_pair("scope", SCOPES)
+            .append_pair("access_type", "offline")
+            .append_pair("prompt", "consent")
+            .append_pair("state", &state)
+            .append_pair("code_challenge", &challenge)
+            .append_pair("code_challenge_method", "S256");
+
+        Ok(url.to_string())
+    }
+
+    fn expects(&self, state: &str) -> bool {
+        self.pending
+            .lock()
+            .expect("Not poisoned")
+            .get(state)
+            .is_some_and(|p| p.started.elapsed() < SIGN_IN_TIMEOUT)
+    }
+
+    async fn finish(&self, state: &str, code: &str) -> Result<(), EventListError> {
+        let pending = self
+            .pending
+            .lock()
+            .expect("Not poisoned")
+            .remove(state)
+            .filter(|p| p.started.elapsed() < SIGN_IN_TIMEOUT)
+            .ok_or_else(|| EventListError::Auth("Unknown or expired sign in".to_string()))?;
+
+        let token = request_token(
+            &self.http,
+            &self.token_url,
+            &[
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("code_verifier", &pending.verifier),
+                ("redirect_uri", &pending.redirect_uri),
+                ("client_id", &self.credentials.client_id),
+                ("client_secret", &self.credentials.client_secret),
+            ],
+        )
+        .await
+        .map_err(|e| EventListError::Auth(e.to_string()))?;
+
+        signed_in(&*self.store, &self.session, token).await;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,227 @@
+use super::DEFAULT_HORIZON_DAYS;
+use crate::{ics, Event, EventList, EventListError, EventSink};
+
+use chrono::{DateTime, Duration, Local};
+use chrono_tz::Tz;
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+/// A file as of when it was last read. Size is kept too, since mtimes can be coarse.
+struct CachedFile {
+    modified: SystemTime,
+    len: u64,
+    body: Arc<str>,
+}
+
+/// The files of a `FileList`, shared with the blocking tasks that read and write them
+struct Files {
+    path: PathBuf,
+    /// Contents of each file, only read again when it changes
+    cache: Mutex<HashMap<PathBuf, CachedFile>>,
+}
+
+/// Represents a local ical file, or a directory of them as kept by vdirsyncer
+pub struct FileList {
+    files: Arc<Files>,
+    name: String,
+    horizon: Duration,
+    /// Zone of times in the files that don't name one, local time if `None`
+    timezone: Option<Tz>,
+}
+
+impl FileList {
+    /// Create a new `FileList` reading the `.ics` file or directory at `path`
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+
+        Self {
+            name: path.display().to_string(),
+            files: Arc::new(Files {
+                path,
+                cache: Mutex::new(HashMap::new()),
+            }),
+            horizon: Duration::days(DEFAULT_HORIZON_DAYS),
+            timezone: None,
+        }
+    }
+
+    /// Reads times in the files that don't name a zone as times in `tz`
+    pub fn with_timezone(mut self, tz: Tz) -> Self {
+        self.timezone = Some(tz);
+        self
+    }
+
+    /// Runs `f` on a thread that may block, so file access doesn't hold up the runtime
+    async fn blocking<T, F>(&self, f: F) -> Result<T, EventListError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Files) -> Result<T, EventListError> + Send + 'static,
+    {
+        let files = self.files.clone();
+        tokio::task::spawn_blocking(move || f(&files))
+            .await
+            .map_err(|e| EventListError::Io(e.to_string()))?
+    }
+}
+
+impl Files {
+    /// The files to read: `path` itself, or the `.ics` files directly inside it
+    fn list(&self) -> Result<Vec<PathBuf>, EventListError> {
+        if !self.path.is_dir() {
+            return Ok(vec![self.path.clone()]);
+        }
+
+        let mut out: Vec<_> = std::fs::read_dir(&self.path)
+            .map_err(|e| io_error(&self.path, e))?
+            .filter_map(|entry| Some(entry.ok()?.path()))
+            .filter(|p| p.is_file() && p.extension().is_some_and(|e| e == "ics"))
+            .collect();
+        out.sort();
+
+        Ok(out)
+    }
+
+    /// The contents of `path`, read again only if its modification time or size changed
+    fn read(&self, path: &Path) -> Result<Arc<str>, EventListError> {
+        let meta = std::fs::metadata(path).map_err(|e| io_error(path, e))?;
+        let modified = meta.modified().map_err(|e| io_error(path, e))?;
+
+        if let Some(file) = self.cache.lock().expect("Cache lock").get(path) {
+            if file.modified == modified && file.len == meta.len() {
+                return Ok(file.body.clone());
+            }
+        }
+
+        log::debug!("Reading {}", path.display());
+        let body: Arc<str> = std::fs::read_to_string(path)
+            .map_err(|e| io_error(path, e))?
+            .into();
+        self.cache.lock().expect("Cache lock").insert(
+            path.to_path_buf(),
+            CachedFile {
+                modified,
+                len: meta.len(),
+                body: body.clone(),
+            },
+        );
+
+        Ok(body)
+    }
+
+    /// The file in the directory holding the event with `id`, or its series
+    fn locate(&self, id: &str) -> Result<(PathBuf, Arc<str>), EventListError> {
+        for file in self.list()? {
+            let body = self.read(&file)?;
+            if ics::edit::holds(&body, id) {
+                return Ok((file, body));
+            }
+        }
+
+        Err(EventListError::NotFound(id.to_string()))
+    }
+}
+
+/// Replaces `path` by `body` in one step, so readers never see half a file
+fn write_atomic(path: &Path, body: &str) -> Result<(), EventListError> {
+    let tmp = path.with_extension("ics.tmp");
+    std::fs::write(&tmp, body)
+        .and_then(|_| std::fs::rename(&tmp, path))
+        .map_err(|e| io_error(path, e))
+}
+
+fn io_error(path: &Path, e: std::io::Error) -> EventListError {
+    EventListError::Io(format!("{}: {e}", path.display()))
+}
+
+#[async_trait::async_trait]
+impl EventList for FileList {
+    async fn init(&mut self) {}
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn events(&self, after: DateTime<Local>) -> Result<Vec<Event>, EventListError> {
+        let (horizon, timezone) = (self.horizon, self.timezone);
+
+        self.blocking(move |files| {
+            let list = files.list()?;
+            let is_dir = files.path.is_dir();
+
+            // Forget files that were deleted
+            files
+                .cache
+                .lock()
+                .expect("Cache lock")
+                .retain(|p, _| list.contains(p));
+
+            let mut out = Vec::new();
+            for file in &list {
+                let body = files.read(file)?;
+
+                match ics::parse_events_in(&body, after, after + horizon, timezone) {
+                    Ok(events) => out.extend(events),
+                    // One broken item shouldn't hide the rest of a directory
+                    Err(e) if is_dir => log::warn!("Skipping {}: {e}", file.display()),
+                    Err(e) => return Err(e),
+                }
+            }
+
+            if is_dir {
+                out.sort_by_key(|e| e.start);
+            }
+
+            Ok(out)
+        })
+        .await
+    }
+
+    /// Only directories can be written to, one event per file like vdirsyncer keeps them
+    fn sink(&self) -> Option<&dyn EventSink> {
+        self.files.path.is_dir().then_some(self as &dyn EventSink)
+    }
+}
+
+#[async_trait::async_trait]
+impl EventSink for FileList {
+    async fn create(&self, event: &Event) -> Result<String, EventListError> {
+        let uid = ics::write::new_uid();
+        let body = ics::write::event_to_ics(event, &uid);
+
+        self.blocking({
+            let uid = uid.clone();
+            move |files| write_atomic(&files.path.join(format!("{uid}.ics")), &body)
+        })
+        .await?;
+
+        Ok(uid)
+    }
+
+    async fn update(&self, id: &str, event: &Event) -> Result<(), EventListError> {
+        let (id, event, timezone) = (id.to_string(), event.clone(), self.timezone);
+
+        self.blocking(move |files| {
+            let (path, body) = files.locate(&id)?;
+            write_atomic(&path, &ics::edit::update(&body, timezone, &id, &event)?)
+        })
+        .await
+    }
+
+    async fn delete(&self, id: &str) -> Result<(), EventListError> {
+        let (id, timezone) = (id.to_string(), self.timezone);
+
+        self.blocking(move |files| {
+            let (path, body) = files.locate(&id)?;
+            match ics::edit::delete(&body, timezone, &id)? {
+                Some(body) => write_atomic(&path, &body),
+                None => std::fs::remove_file(&path).map_err(|e| io_error(&path, e)),
+            }
+        })
+        .await
+    }
+}
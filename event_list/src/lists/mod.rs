@@ -1,2 +1,38 @@
+pub mod caldav;
+#[cfg(feature = "google")]
 pub mod google;
-pub mod remoteical;
\ No newline at end of file
+#[cfg(feature = "google")]
+mod google_auth;
+pub mod localical;
+pub mod remoteical;
+#[cfg(feature = "google")]
+pub mod token_store;
+
+use crate::EventListError;
+
+/// How far past `after` recurring events of iCalendar sources are expanded
+const DEFAULT_HORIZON_DAYS: i64 = 365;
+
+/// How long to wait for a server before giving up
+const REQUEST_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// A client for the HTTP requests of the sources, which gives up on slow servers
+fn http_client() -> reqwest::Client {
+    reqwest::Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .build()
+        .expect("Valid client config")
+}
+
+/// Why an incremental sync didn't work
+enum SyncError {
+    /// The server no longer accepts the sync token, a full sync is needed
+    InvalidToken,
+    Other(EventListError),
+}
+
+impl From<EventListError> for SyncError {
+    fn from(e: EventListError) -> Self {
+        SyncError::Other(e)
+    }
+}
@@ -1,121 +1,147 @@
-use crate::{Event, EventList};
+use super::{http_client, DEFAULT_HORIZON_DAYS};
+use crate::{ics, Event, EventList, EventListError};
 
-use chrono::{DateTime, FixedOffset, Local, NaiveDate, NaiveDateTime, NaiveTime, ParseResult, Utc};
+use chrono::{DateTime, Duration, Local};
+use chrono_tz::Tz;
+use reqwest::{header, StatusCode};
 
-use ical::IcalParser;
-use std::io::BufReader;
+use std::{
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+/// The last feed that parsed, with what is needed to ask the server if it changed
+#[derive(Clone)]
+struct CachedFeed {
+    body: Arc<str>,
+    etag: Option<String>,
+    last_modified: Option<String>,
+    /// Until when `Cache-Control: max-age` allows reusing `body` without asking
+    fresh_until: Option<Instant>,
+}
 
 /// Represents an ical file at a remote location
 pub struct RemoteList {
     url: String,
+    name: String,
+    horizon: Duration,
+    /// Zone of times in the feed that don't name one, local time if `None`
+    timezone: Option<Tz>,
+    client: reqwest::Client,
+    cache: Mutex<Option<CachedFeed>>,
+}
+
+/// How long the response may be reused for according to its `Cache-Control` header
+fn max_age(headers: &header::HeaderMap) -> Option<std::time::Duration> {
+    let value = headers.get(header::CACHE_CONTROL)?.to_str().ok()?;
+    let mut age = None;
+
+    for directive in value.split(',').map(str::trim) {
+        match directive.split_once('=') {
+            Some(("max-age", secs)) => age = secs.trim_matches('"').parse().ok(),
+            _ if directive == "no-cache" || directive == "no-store" => return None,
+            _ => {}
+        }
+    }
+
+    age.map(std::time::Duration::from_secs)
+}
+
+/// The value of `name` in `headers`, if it is there and valid text
+fn header_string(headers: &header::HeaderMap, name: header::HeaderName) -> Option<String> {
+    Some(headers.get(name)?.to_str().ok()?.to_string())
 }
 
 impl RemoteList {
     /// Create a new `RemoteList` with `url`
     pub fn new(url: &str) -> Self {
-        Self { url: url.to_string() }
+        // Feed links often carry an access token in the query, keep it out of the logs
+        let name = url.split('?').next().unwrap_or(url).to_string();
+
+        Self {
+            url: url.to_string(),
+            name,
+            horizon: Duration::days(DEFAULT_HORIZON_DAYS),
+            timezone: None,
+            client: http_client(),
+            cache: Mutex::new(None),
+        }
     }
-}
 
-fn parse_from_iso8601(s: &str) -> ParseResult<DateTime<Local>> {
-    let naive = match NaiveDateTime::parse_from_str(s, "%Y%m%dT%H%M%S%Z") {
-        Ok(d) => d,
-        Err(_) => NaiveDate::parse_from_str(s, "%Y%m%d")?
-            .and_time(NaiveTime::from_hms_opt(0, 0, 0).expect("Valid Time")),
-    };
-
-    Ok(DateTime::<Local>::from_utc(
-        naive,
-        FixedOffset::east_opt(0).expect("Valid Offset"),
-    ))
-}
+    /// Reads times in the feed that don't name a zone as times in `tz`
+    pub fn with_timezone(mut self, tz: Tz) -> Self {
+        self.timezone = Some(tz);
+        self
+    }
 
-fn unescape_ical(s: &str) -> String {
-    s.replace("\\,", ",")
-        .replace("\\\\", "\\")
-        .replace("\\n", "\n")
-        .replace("\\;", ";")
+    /// Returns the feed, from the cache if the server allows it or says it hasn't changed
+    async fn fetch(&self) -> Result<CachedFeed, EventListError> {
+        let cached = self.cache.lock().expect("Cache lock").clone();
+
+        if let Some(feed) = &cached {
+            if feed.fresh_until.is_some_and(|t| Instant::now() < t) {
+                return Ok(feed.clone());
+            }
+        }
+
+        let mut req = self.client.get(&self.url);
+        if let Some(feed) = &cached {
+            if let Some(etag) = &feed.etag {
+                req = req.header(header::IF_NONE_MATCH, etag);
+            }
+            if let Some(last_modified) = &feed.last_modified {
+                req = req.header(header::IF_MODIFIED_SINCE, last_modified);
+            }
+        }
+
+        let resp = req.send().await?;
+        let fresh_until = max_age(resp.headers()).map(|age| Instant::now() + age);
+
+        if resp.status() == StatusCode::NOT_MODIFIED {
+            let Some(feed) = cached else {
+                return Err(EventListError::Network(format!(
+                    "{} answered 304 Not Modified to an unconditional request",
+                    self.name
+                )));
+            };
+            log::debug!("{} has not changed", self.name);
+
+            return Ok(CachedFeed {
+                fresh_until,
+                etag: header_string(resp.headers(), header::ETAG).or(feed.etag),
+                ..feed
+            });
+        }
+
+        let resp = resp.error_for_status()?;
+        let etag = header_string(resp.headers(), header::ETAG);
+        let last_modified = header_string(resp.headers(), header::LAST_MODIFIED);
+        let body = String::from_utf8(resp.bytes().await?.to_vec())?;
+
+        Ok(CachedFeed {
+            body: body.into(),
+            etag,
+            last_modified,
+            fresh_until,
+        })
+    }
 }
 
 #[async_trait::async_trait]
 impl EventList for RemoteList {
     async fn init(&mut self) {}
 
-    async fn events(&self, after: DateTime<Local>) -> Vec<Event> {
-        let resp = reqwest::get(&self.url).await.unwrap();
-
-        let body = String::from_utf8(resp.bytes().await.unwrap().to_vec())
-            .unwrap()
-            .replace("\r\n\t", "");
-
-        let ical = IcalParser::new(BufReader::new(stringreader::StringReader::new(&body)));
-
-        ical
-            .filter_map(|r| match r {
-                Ok(cal) => Some(cal),
-                Err(e) => {
-                    log::error!("{:?}", e);
-                    None
-                }
-            })
-            .flat_map(|cal| {
-                cal.events
-                    .iter()
-                    .map(|e| {
-                        let mut out_event = Event::default();
-                        for prop in &e.properties {
-                            match prop.name.as_str() {
-                                "DESCRIPTION" => {
-                                    out_event.desc =
-                                        unescape_ical(prop.value.as_ref().expect("No value"))
-                                            .replace("\\n", "\n");
-                                }
-                                "SUMMARY" => {
-                                    out_event.title =
-                                        unescape_ical(prop.value.as_ref().expect("No value"))
-                                            .replace("\\n", "\n");
-                                }
-                                "DTSTART" => {
-                                    out_event.start =
-                                        prop.value.as_ref().and_then(
-                                            |s| match parse_from_iso8601(s) {
-                                                Ok(d) => Some(d),
-                                                Err(e) => {
-                                                    log::error!("Date Parse error {e:?} {s}");
-                                                    None
-                                                }
-                                            },
-                                        );
-                                }
-                                "DTEND" => {
-                                    out_event.end =
-                                        prop.value.as_ref().and_then(
-                                            |s| match parse_from_iso8601(s) {
-                                                Ok(d) => Some(d),
-                                                Err(e) => {
-                                                    log::error!("Date Parse error {e:?} {s}");
-                                                    None
-                                                }
-                                            },
-                                        );
-                                }
-                                "DTSTAMP" => {}
-                                "CLASS" => {}
-                                "UID" => {}
-                                "SEQUENCE" => {}
-                                "LAST-MODIFIED" => {}
-                                "LOCATION" => out_event.class = prop.value.clone(),
-                                "RRULE" => {}
-                                e => {
-                                    panic!("Unimplemented property {e}");
-                                }
-                            }
-                        }
-                        out_event
-                    })
-                    .collect::<Vec<_>>()
-            })
-            .filter(|e| DateTime::<Utc>::from(e.end.unwrap()) >= after)
-            .collect::<Vec<_>>()
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn events(&self, after: DateTime<Local>) -> Result<Vec<Event>, EventListError> {
+        let feed = self.fetch().await?;
+        let events = ics::parse_events_in(&feed.body, after, after + self.horizon, self.timezone)?;
+
+        // Only remember feeds that parsed, so a broken one is downloaded again next time
+        *self.cache.lock().expect("Cache lock") = Some(feed);
+
+        Ok(events)
     }
 }
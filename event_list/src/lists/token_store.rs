@@ -0,0 +1,265 @@
+use crate::EventListError;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{DateTime, Utc};
+use ring::{aead, pbkdf2, rand::SecureRandom};
+use serde::{Deserialize, Serialize};
+
+use std::{
+    io::Write,
+    num::NonZeroU32,
+    path::{Path, PathBuf},
+};
+
+/// First line of an encrypted token file
+const ENCRYPTED_HEADER: &str = "event_list encrypted token v1";
+
+const SALT_LEN: usize = 16;
+
+const PBKDF2_ITERATIONS: u32 = 100_000;
+
+/// Tokens of a signed in Google account
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoredToken {
+    pub access_token: String,
+    pub refresh_token: String,
+    /// When `access_token` stops working, if known
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// Where a Google source keeps its tokens between runs
+pub trait TokenStore: Send + Sync {
+    /// The saved tokens, `None` if none were saved
+    fn load(&self) -> Result<Option<StoredToken>, EventListError>;
+
+    /// Replaces the saved tokens by `token`
+    fn save(&self, token: &StoredToken) -> Result<(), EventListError>;
+
+    /// Forgets the saved tokens, e.g. after they were revoked
+    fn clear(&self) -> Result<(), EventListError>;
+
+    /// Where the tokens are kept, for messages
+    fn describe(&self) -> String;
+}
+
+/// Keeps the tokens in a file only its owner can read, optionally encrypted with a passphrase
+pub struct FileStore {
+    path: PathBuf,
+    passphrase: Option<String>,
+}
+
+impl FileStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            passphrase: None,
+        }
+    }
+
+    /// Encrypts the tokens with a key derived from `passphrase`.
+    /// Unencrypted files are still read, and encrypted on the next save.
+    pub fn with_passphrase(mut self, passphrase: &str) -> Self {
+        self.passphrase = Some(passphrase.to_string());
+        self
+    }
+
+    fn error(&self, e: impl std::fmt::Display) -> EventListError {
+        EventListError::Auth(format!("{}: {e}", self.path.display()))
+    }
+
+    fn encrypt(&self, passphrase: &str, plain: &[u8]) -> Result<String, EventListError> {
+        let mut salt = [0; SALT_LEN];
+        let mut nonce = [0; aead::NONCE_LEN];
+        let rng = ring::rand::SystemRandom::new();
+        rng.fill(&mut salt)
+            .and_then(|_| rng.fill(&mut nonce))
+            .map_err(|_| self.error("no random numbers to encrypt with"))?;
+
+        let mut data = plain.to_vec();
+        key(passphrase, &salt)
+            .seal_in_place_append_tag(
+                aead::Nonce::assume_unique_for_key(nonce),
+                aead::Aad::empty(),
+                &mut data,
+            )
+            .map_err(|_| self.error("encrypting failed"))?;
+
+        let sealed: Vec<u8> = salt.iter().chain(&nonce).chain(&data).copied().collect();
+        Ok(format!("{ENCRYPTED_HEADER}\n{}\n", STANDARD.encode(sealed)))
+    }
+
+    fn decrypt(&self, body: &str) -> Result<Vec<u8>, EventListError> {
+        let passphrase = self
+            .passphrase
+            .as_deref()
+            .ok_or_else(|| self.error("the tokens are encrypted, but no passphrase was given"))?;
+        let mut sealed = STANDARD
+            .decode(body.trim())
+            .map_err(|e| self.error(format!("invalid encrypted tokens, {e}")))?;
+        if sealed.len() < SALT_LEN + aead::NONCE_LEN + aead::CHACHA20_POLY1305.tag_len() {
+            return Err(self.error("invalid encrypted tokens, too short"));
+        }
+
+        let mut data = sealed.split_off(SALT_LEN + aead::NONCE_LEN);
+        let (salt, nonce) = sealed.split_at(SALT_LEN);
+        let nonce = aead::Nonce::try_assume_unique_for_key(nonce).expect("Nonce length");
+        let plain = key(passphrase, salt)
+            .open_in_place(nonce, aead::Aad::empty(), &mut data)
+            .map_err(|_| self.error("wrong passphrase, or the tokens were changed"))?;
+
+        Ok(plain.to_vec())
+    }
+}
+
+/// The encryption key for `passphrase` and `salt`
+fn key(passphrase: &str, salt: &[u8]) -> aead::LessSafeKey {
+    let mut key = [0; 32];
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        NonZeroU32::new(PBKDF2_ITERATIONS).expect("Not zero"),
+        salt,
+        passphrase.as_bytes(),
+        &mut key,
+    );
+    aead::LessSafeKey::new(
+        aead::UnboundKey::new(&aead::CHACHA20_POLY1305, &key).expect("Key length"),
+    )
+}
+
+/// Replaces `path` by `body` in one step, so a crash never leaves half a token file behind.
+/// The file is only readable by its owner.
+fn write_private(path: &Path, body: &[u8]) -> std::io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+
+    // A file left behind by a crash may be readable by others, so it's never reused
+    match std::fs::remove_file(&tmp) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+    let mut file = options.open(&tmp)?;
+    file.write_all(body)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp, path)
+}
+
+impl TokenStore for FileStore {
+    fn load(&self) -> Result<Option<StoredToken>, EventListError> {
+        let data = match std::fs::read_to_string(&self.path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(self.error(e)),
+        };
+
+        #[cfg(unix)]
+        if let Ok(meta) = std::fs::metadata(&self.path) {
+            use std::os::unix::fs::PermissionsExt;
+            if meta.permissions().mode() & 0o077 != 0 {
+                log::warn!(
+                    "{} can be read by other users, it is made private on the next save",
+                    self.path.display()
+                );
+            }
+        }
+
+        let plain = match data.split_once('\n') {
+            Some((ENCRYPTED_HEADER, body)) => String::from_utf8(self.decrypt(body)?)?,
+            _ => data,
+        };
+
+        if plain.trim_start().starts_with('{') {
+            return serde_json::from_str(&plain)
+                .map(Some)
+                .map_err(|e| self.error(e));
+        }
+
+        // The old format, the access and the refresh token on a line each
+        match plain.trim_end().split_once('\n') {
+            Some((access_token, refresh_token)) => Ok(Some(StoredToken {
+                access_token: access_token.to_string(),
+                refresh_token: refresh_token.to_string(),
+                expires_at: None,
+            })),
+            None => Err(self.error("not a token file")),
+        }
+    }
+
+    fn save(&self, token: &StoredToken) -> Result<(), EventListError> {
+        let json = serde_json::to_string(token)?;
+        let body = match &self.passphrase {
+            Some(passphrase) => self.encrypt(passphrase, json.as_bytes())?,
+            None => json,
+        };
+
+        write_private(&self.path, body.as_bytes()).map_err(|e| self.error(e))
+    }
+
+    fn clear(&self) -> Result<(), EventListError> {
+        match std::fs::remove_file(&self.path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(self.error(e)),
+            _ => Ok(()),
+        }
+    }
+
+    fn describe(&self) -> String {
+        self.path.display().to_string()
+    }
+}
+
+/// Keeps the tokens in the keyring of the OS: the Keychain on macOS, the Credential Manager
+/// on Windows and the Secret Service on Linux, which outlives reboots unlike the kernel keyring
+#[cfg(feature = "keyring")]
+pub struct KeyringStore {
+    entry: keyring::Entry,
+    account: String,
+}
+
+#[cfg(feature = "keyring")]
+impl KeyringStore {
+    /// The tokens saved for `account` under the `service` name
+    pub fn new(service: &str, account: &str) -> Result<Self, EventListError> {
+        Ok(Self {
+            entry: keyring::Entry::new(service, account)
+                .map_err(|e| EventListError::Auth(format!("Keyring: {e}")))?,
+            account: format!("{service}/{account}"),
+        })
+    }
+
+    fn error(&self, e: keyring::Error) -> EventListError {
+        EventListError::Auth(format!("Keyring entry {}: {e}", self.account))
+    }
+}
+
+#[cfg(feature = "keyring")]
+impl TokenStore for KeyringStore {
+    fn load(&self) -> Result<Option<StoredToken>, EventListError> {
+        match self.entry.get_password() {
+            Ok(json) => Ok(Some(serde_json::from_str(&json)?)),
+            Err(keyring::Error::NoEntry) => Ok(None),
+            Err(e) => Err(self.error(e)),
+        }
+    }
+
+    fn save(&self, token: &StoredToken) -> Result<(), EventListError> {
+        self.entry
+            .set_password(&serde_json::to_string(token)?)
+            .map_err(|e| self.error(e))
+    }
+
+    fn clear(&self) -> Result<(), EventListError> {
+        match self.entry.delete_credential() {
+            Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+            Err(e) => Err(self.error(e)),
+        }
+    }
+
+    fn describe(&self) -> String {
+        format!("keyring entry {}", self.account)
+    }
+}
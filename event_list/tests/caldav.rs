@@ -0,0 +1,383 @@
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpListener,
+    sync::{Arc, Mutex},
+    thread,
+};
+
+use chrono::{Local, TimeZone};
+
+use event_list::{lists::caldav::CalDavList, Event, EventList, EventListError};
+
+/// A request as seen by the mock server
+#[derive(Clone, Debug)]
+struct Request {
+    method: String,
+    path: String,
+    authorization: Option<String>,
+    /// The `If-Match` or `If-None-Match` header line
+    precondition: Option<String>,
+    body: String,
+}
+
+/// Serves every request with `handler` until the test ends, recording them in the returned list
+fn serve(
+    handler: impl Fn(&Request) -> (u16, String) + Send + 'static,
+) -> (String, Arc<Mutex<Vec<Request>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/dav/", listener.local_addr().unwrap());
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let seen = requests.clone();
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(&stream);
+
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let mut parts = line.split_whitespace();
+            let (method, path) = (parts.next().unwrap(), parts.next().unwrap());
+
+            let mut len = 0;
+            let mut authorization = None;
+            let mut precondition = None;
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+                let header = header.trim();
+                if header.is_empty() {
+                    break;
+                }
+                let (name, value) = header.split_once(": ").unwrap();
+                match name.to_lowercase().as_str() {
+                    "content-length" => len = value.parse().unwrap(),
+                    "authorization" => authorization = Some(value.to_string()),
+                    "if-match" | "if-none-match" => precondition = Some(header.to_string()),
+                    _ => {}
+                }
+            }
+            let mut body = vec![0; len];
+            reader.read_exact(&mut body).unwrap();
+
+            let request = Request {
+                method: method.to_string(),
+                path: path.to_string(),
+                authorization,
+                precondition,
+                body: String::from_utf8(body).unwrap(),
+            };
+            let (status, body) = handler(&request);
+            seen.lock().unwrap().push(request);
+
+            write!(
+                stream,
+                "HTTP/1.1 {status} Status\r\nContent-Type: application/xml\r\n\
+                 Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            )
+            .unwrap();
+        }
+    });
+
+    (url, requests)
+}
+
+fn multistatus(responses: &str) -> String {
+    format!(
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<D:multistatus xmlns:D="DAV:" xmlns:C="urn:ietf:params:xml:ns:caldav">{responses}</D:multistatus>"#
+    )
+}
+
+fn response(href: &str, props: &str) -> String {
+    format!(
+        "<D:response><D:href>{href}</D:href><D:propstat><D:prop>{props}</D:prop>\
+         <D:status>HTTP/1.1 200 OK</D:status></D:propstat></D:response>"
+    )
+}
+
+fn event(uid: &str, title: &str) -> String {
+    format!(
+        "<C:calendar-data>BEGIN:VCALENDAR&#13;\nVERSION:2.0&#13;\nBEGIN:VEVENT&#13;\n\
+         UID:{uid}&#13;\nDTSTART:20230601T090000Z&#13;\nDTEND:20230601T100000Z&#13;\n\
+         SUMMARY:{title}&#13;\nEND:VEVENT&#13;\nEND:VCALENDAR&#13;\n</C:calendar-data>"
+    )
+}
+
+/// An account with a principal, a calendar home, an event calendar, a task list and an
+/// address book. `sync` answers `sync-collection` reports.
+fn account(sync: fn(&str) -> (u16, String)) -> impl Fn(&Request) -> (u16, String) {
+    move |r| {
+        let body = match (r.method.as_str(), r.path.as_str()) {
+            ("PROPFIND", "/dav/") => response(
+                "/dav/",
+                "<D:resourcetype><D:collection/></D:resourcetype>\
+                 <D:current-user-principal><D:href>/dav/principals/alice/</D:href>\
+                 </D:current-user-principal>",
+            ),
+            ("PROPFIND", "/dav/principals/alice/") => response(
+                "/dav/principals/alice/",
+                "<C:calendar-home-set><D:href>/dav/calendars/alice/</D:href></C:calendar-home-set>",
+            ),
+            ("PROPFIND", "/dav/calendars/alice/") => [
+                response(
+                    "/dav/calendars/alice/",
+                    "<D:resourcetype><D:collection/></D:resourcetype>",
+                ),
+                response(
+                    "/dav/calendars/alice/work/",
+                    "<D:resourcetype><D:collection/><C:calendar/></D:resourcetype>\
+                     <D:displayname>Work</D:displayname><C:supported-calendar-component-set>\
+                     <C:comp name=\"VEVENT\"/></C:supported-calendar-component-set>",
+                ),
+                response(
+                    "/dav/calendars/alice/tasks/",
+                    "<D:resourcetype><D:collection/><C:calendar/></D:resourcetype>\
+                     <C:supported-calendar-component-set><C:comp name=\"VTODO\"/>\
+                     </C:supported-calendar-component-set>",
+                ),
+                response(
+                    "/dav/calendars/alice/contacts/",
+                    "<D:resourcetype><D:collection/><addressbook xmlns=\"urn:ietf:params:xml:ns:carddav\"/>\
+                     </D:resourcetype>",
+                ),
+            ]
+            .concat(),
+            ("PROPFIND", "/dav/calendars/alice/work/") => response(
+                "/dav/calendars/alice/work/",
+                "<D:sync-token>http://example.com/sync/1</D:sync-token>",
+            ),
+            ("REPORT", "/dav/calendars/alice/work/") if r.body.contains("calendar-query") => [
+                response(
+                    "/dav/calendars/alice/work/a.ics",
+                    &format!("<D:getetag>\"a1\"</D:getetag>{}", event("a", "Planning")),
+                ),
+                response(
+                    "/dav/calendars/alice/work/b.ics",
+                    &format!("<D:getetag>\"b1\"</D:getetag>{}", event("b", "Review")),
+                ),
+            ]
+            .concat(),
+            ("REPORT", "/dav/calendars/alice/work/") if r.body.contains("sync-collection") => {
+                return sync(&r.body);
+            }
+            ("REPORT", "/dav/calendars/alice/work/") if r.body.contains("calendar-multiget") => {
+                response(
+                    "/dav/calendars/alice/work/b.ics",
+                    &format!("<D:getetag>\"b2\"</D:getetag>{}", event("b", "Review moved")),
+                )
+            }
+            ("PUT", path) if path.starts_with("/dav/calendars/alice/work/") => {
+                return (201, String::new())
+            }
+            ("DELETE", path) if path.starts_with("/dav/calendars/alice/work/") => {
+                return (204, String::new())
+            }
+            _ => return (404, String::new()),
+        };
+
+        (207, multistatus(&body))
+    }
+}
+
+/// Removes a.ics and changes b.ics since the first token
+fn changes(body: &str) -> (u16, String) {
+    if !body.contains("http://example.com/sync/1") {
+        return (403, String::new());
+    }
+
+    (
+        207,
+        multistatus(&format!(
+            "<D:response><D:href>/dav/calendars/alice/work/a.ics</D:href>\
+             <D:status>HTTP/1.1 404 Not Found</D:status></D:response>{}\
+             <D:sync-token>http://example.com/sync/2</D:sync-token>",
+            response(
+                "/dav/calendars/alice/work/b.ics",
+                "<D:getetag>\"b2\"</D:getetag>"
+            )
+        )),
+    )
+}
+
+fn titles(events: &[event_list::Event]) -> Vec<&str> {
+    let mut out: Vec<_> = events.iter().map(|e| e.title.as_str()).collect();
+    out.sort();
+    out
+}
+
+#[tokio::test]
+async fn discovers_and_syncs_incrementally() {
+    let (url, requests) = serve(account(changes));
+    let list = CalDavList::new(&url)
+        .unwrap()
+        .with_basic_auth("alice", Some("secret"));
+    let after = Local.with_ymd_and_hms(2023, 1, 1, 0, 0, 0).unwrap();
+
+    let first = list.events(after).await.unwrap();
+    assert_eq!(titles(&first), ["Planning", "Review"]);
+
+    let second = list.events(after).await.unwrap();
+    assert_eq!(titles(&second), ["Review moved"]);
+
+    let requests = requests.lock().unwrap();
+    assert!(requests
+        .iter()
+        .all(|r| r.authorization.as_deref() == Some("Basic YWxpY2U6c2VjcmV0")));
+
+    let query = requests
+        .iter()
+        .find(|r| r.body.contains("calendar-query"))
+        .unwrap();
+    assert!(query.body.contains("time-range start=\"20230101T"));
+
+    // Only the changed item is fetched again, the task list and address book are never queried
+    assert_eq!(
+        requests
+            .iter()
+            .filter(|r| r.body.contains("calendar-query"))
+            .count(),
+        1
+    );
+    assert!(requests
+        .iter()
+        .any(|r| r.body.contains("calendar-multiget") && r.body.contains("b.ics")));
+    assert!(requests.iter().all(|r| !r.path.contains("tasks")));
+}
+
+#[tokio::test]
+async fn expired_sync_token_falls_back_to_a_full_query() {
+    let (url, requests) = serve(account(|_| (403, String::new())));
+    let list = CalDavList::new(&url).unwrap();
+    let after = Local.with_ymd_and_hms(2023, 1, 1, 0, 0, 0).unwrap();
+
+    list.events(after).await.unwrap();
+    let second = list.events(after).await.unwrap();
+
+    assert_eq!(titles(&second), ["Planning", "Review"]);
+    assert_eq!(
+        requests
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|r| r.body.contains("calendar-query"))
+            .count(),
+        2
+    );
+}
+
+#[tokio::test]
+async fn rejected_credentials_are_an_auth_error() {
+    let (url, _) = serve(|_| (401, String::new()));
+    let list = CalDavList::new(&url)
+        .unwrap()
+        .with_basic_auth("alice", Some("wrong"));
+    let after = Local.with_ymd_and_hms(2023, 1, 1, 0, 0, 0).unwrap();
+
+    assert!(matches!(
+        list.events(after).await,
+        Err(EventListError::Auth(_))
+    ));
+}
+
+#[tokio::test]
+async fn writes_are_guarded_by_etags() {
+    let (url, requests) = serve(account(changes));
+    let list = CalDavList::new(&url).unwrap();
+    let sink = list.sink().unwrap();
+    let deadline = Event {
+        title: "Deadline".to_string(),
+        desc: String::new(),
+        start: Some(Local.with_ymd_and_hms(2023, 6, 2, 17, 0, 0).unwrap().into()),
+        end: None,
+        class: None,
+        ..Default::default()
+    };
+
+    let uid = sink.create(&deadline).await.unwrap();
+    sink.update("b", &deadline).await.unwrap();
+    sink.delete("a").await.unwrap();
+    assert!(matches!(
+        sink.delete("missing").await,
+        Err(EventListError::NotFound(_))
+    ));
+
+    let requests = requests.lock().unwrap();
+    let writes: Vec<_> = requests
+        .iter()
+        .filter(|r| r.method == "PUT" || r.method == "DELETE")
+        .collect();
+    assert_eq!(writes.len(), 3);
+
+    assert_eq!(
+        writes[0].path,
+        format!("/dav/calendars/alice/work/{uid}.ics")
+    );
+    assert_eq!(writes[0].precondition.as_deref(), Some("if-none-match: *"));
+    assert!(writes[0].body.contains(&format!("UID:{uid}")));
+    assert!(writes[0].body.contains("SUMMARY:Deadline"));
+
+    assert_eq!(writes[1].path, "/dav/calendars/alice/work/b.ics");
+    assert_eq!(writes[1].precondition.as_deref(), Some("if-match: \"b1\""));
+    assert!(writes[1].body.contains("UID:b\r\n"));
+
+    assert_eq!(writes[2].method, "DELETE");
+    assert_eq!(writes[2].path, "/dav/calendars/alice/work/a.ics");
+    assert_eq!(writes[2].precondition.as_deref(), Some("if-match: \"a1\""));
+}
+
+#[tokio::test]
+async fn occurrence_writes_put_their_series() {
+    let account = account(changes);
+    let (url, requests) = serve(move |r| {
+        if r.method == "REPORT" && r.body.contains("calendar-query") {
+            let series = "<C:calendar-data>BEGIN:VCALENDAR&#13;\nVERSION:2.0&#13;\n\
+                          BEGIN:VEVENT&#13;\nUID:s&#13;\nDTSTART:20230601T090000Z&#13;\n\
+                          DTEND:20230601T100000Z&#13;\nRRULE:FREQ=DAILY;COUNT=3&#13;\n\
+                          SUMMARY:Standup&#13;\nEND:VEVENT&#13;\nEND:VCALENDAR&#13;\n\
+                          </C:calendar-data>";
+            return (
+                207,
+                multistatus(&response(
+                    "/dav/calendars/alice/work/s.ics",
+                    &format!("<D:getetag>\"s1\"</D:getetag>{series}"),
+                )),
+            );
+        }
+        account(r)
+    });
+    let list = CalDavList::new(&url).unwrap();
+    let sink = list.sink().unwrap();
+    let standup = Event {
+        title: "Standup (long)".to_string(),
+        desc: String::new(),
+        start: Some(Local.with_ymd_and_hms(2023, 6, 1, 11, 0, 0).unwrap().into()),
+        end: None,
+        class: None,
+        ..Default::default()
+    };
+
+    sink.delete("s/20230602T090000Z").await.unwrap();
+    sink.update("s", &standup).await.unwrap();
+    assert!(matches!(
+        sink.delete("s/20230609T090000Z").await,
+        Err(EventListError::NotFound(_))
+    ));
+
+    let requests = requests.lock().unwrap();
+    let writes: Vec<_> = requests
+        .iter()
+        .filter(|r| r.method == "PUT" || r.method == "DELETE")
+        .collect();
+    assert_eq!(writes.len(), 2);
+    for write in &writes {
+        assert_eq!(write.method, "PUT");
+        assert_eq!(write.path, "/dav/calendars/alice/work/s.ics");
+        assert_eq!(write.precondition.as_deref(), Some("if-match: \"s1\""));
+    }
+    assert!(writes[0].body.contains("EXDATE:20230602T090000Z\r\n"));
+    assert!(writes[0].body.contains("SUMMARY:Standup\r\n"));
+    assert!(writes[1].body.contains("RRULE:FREQ=DAILY;COUNT=3\r\n"));
+    assert!(writes[1].body.contains("SUMMARY:Standup (long)\r\n"));
+}
@@ -0,0 +1,74 @@
+use chrono::{Local, NaiveDate, TimeZone};
+
+use event_list::{Days, Event, EventTime};
+
+fn date(y: i32, m: u32, d: u32) -> NaiveDate {
+    NaiveDate::from_ymd_opt(y, m, d).unwrap()
+}
+
+fn events() -> Vec<Event> {
+    vec![
+        Event {
+            title: "Conference".to_string(),
+            start: Some(date(2023, 11, 20).into()),
+            end: Some(date(2023, 11, 23).into()),
+            ..Default::default()
+        },
+        Event {
+            title: "Talk".to_string(),
+            start: Some(
+                Local
+                    .with_ymd_and_hms(2023, 11, 21, 10, 0, 0)
+                    .unwrap()
+                    .into(),
+            ),
+            end: Some(
+                Local
+                    .with_ymd_and_hms(2023, 11, 21, 11, 0, 0)
+                    .unwrap()
+                    .into(),
+            ),
+            ..Default::default()
+        },
+        Event {
+            title: "No start".to_string(),
+            ..Default::default()
+        },
+    ]
+}
+
+#[test]
+fn all_day_events_cover_each_day() {
+    let json: serde_json::Value =
+        serde_json::from_str(&Days::from_slice(&events()).to_json().unwrap()).unwrap();
+    let days = json.as_array().unwrap();
+
+    // The end date is exclusive, so the conference covers the 20th to the 22nd
+    assert_eq!(days.len(), 3);
+    for day in days {
+        assert_eq!(day["events"][0]["title"], "Conference");
+    }
+
+    // All-day events sort before timed events on the same day
+    assert_eq!(days[1]["events"][1]["title"], "Talk");
+}
+
+#[test]
+fn all_day_serializes_as_date() {
+    let json: serde_json::Value =
+        serde_json::from_str(&Days::from_slice(&events()).to_json().unwrap()).unwrap();
+
+    assert_eq!(json[0]["events"][0]["start"]["date"], "2023-11-20");
+    assert!(json[1]["events"][1]["start"]["date_time"].is_string());
+}
+
+#[test]
+fn event_time_round_trips() {
+    for time in [
+        EventTime::Date(date(2024, 2, 29)),
+        EventTime::DateTime(Local.with_ymd_and_hms(2024, 2, 29, 8, 30, 0).unwrap()),
+    ] {
+        let json = serde_json::to_string(&time).unwrap();
+        assert_eq!(serde_json::from_str::<EventTime>(&json).unwrap(), time);
+    }
+}
@@ -0,0 +1,138 @@
+use chrono::{Local, NaiveDate, TimeZone};
+
+use event_list::{
+    dedup::{dedup, DedupOptions},
+    Event, EventTime,
+};
+
+fn at(d: u32, h: u32, min: u32) -> EventTime {
+    Local
+        .with_ymd_and_hms(2023, 10, d, h, min, 0)
+        .unwrap()
+        .into()
+}
+
+fn event(source: &str, id: &str, title: &str, start: EventTime, end: EventTime) -> Event {
+    Event {
+        title: title.to_string(),
+        start: Some(start),
+        end: Some(end),
+        id: Some(id.to_string()),
+        source: Some(source.to_string()),
+        ..Default::default()
+    }
+}
+
+fn sources(lists: &[Vec<Event>]) -> Vec<Vec<(&str, &str)>> {
+    lists
+        .iter()
+        .map(|l| {
+            l.iter()
+                .map(|e| (e.source.as_deref().unwrap(), e.title.as_str()))
+                .collect()
+        })
+        .collect()
+}
+
+#[test]
+fn same_id_keeps_the_earlier_list() {
+    let d2l = [event(
+        "D2L",
+        "a1@d2l",
+        "Essay",
+        at(2, 23, 59),
+        at(2, 23, 59),
+    )];
+    let google = [event(
+        "Google",
+        "a1@d2l",
+        "Essay (copy)",
+        at(3, 9, 0),
+        at(3, 10, 0),
+    )];
+
+    let out = dedup(&[&google, &d2l], &DedupOptions::default());
+    assert_eq!(sources(&out), [vec![("Google", "Essay (copy)")], vec![]]);
+
+    let out = dedup(&[&d2l, &google], &DedupOptions::default());
+    assert_eq!(sources(&out), [vec![("D2L", "Essay")], vec![]]);
+}
+
+#[test]
+fn similar_titles_at_overlapping_times() {
+    let d2l = [
+        event(
+            "D2L",
+            "1",
+            "Assignment 1 - Due",
+            at(2, 23, 59),
+            at(2, 23, 59),
+        ),
+        event("D2L", "2", "Quiz 3", at(5, 10, 0), at(5, 11, 0)),
+        event("D2L", "3", "Lab report", at(6, 14, 0), at(6, 15, 0)),
+    ];
+    let day = NaiveDate::from_ymd_opt(2023, 10, 2).unwrap();
+    let google = [
+        // An all-day copy covers the deadline
+        event(
+            "Google",
+            "g1",
+            "assignment 1: due",
+            day.into(),
+            day.succ_opt().unwrap().into(),
+        ),
+        // Same title, but another time
+        event("Google", "g2", "Quiz 3", at(12, 10, 0), at(12, 11, 0)),
+        // Same time, but another event
+        event("Google", "g3", "Dentist", at(6, 14, 30), at(6, 15, 0)),
+    ];
+
+    let out = dedup(&[&d2l, &google], &DedupOptions::default());
+    assert_eq!(
+        sources(&out),
+        [
+            vec![
+                ("D2L", "Assignment 1 - Due"),
+                ("D2L", "Quiz 3"),
+                ("D2L", "Lab report")
+            ],
+            vec![("Google", "Quiz 3"), ("Google", "Dentist")],
+        ]
+    );
+}
+
+#[test]
+fn options_turn_matching_off() {
+    let d2l = [event("D2L", "a", "Essay", at(2, 9, 0), at(2, 10, 0))];
+    let google = [
+        event("Google", "a", "Other", at(9, 9, 0), at(9, 10, 0)),
+        event("Google", "b", "Essay", at(2, 9, 0), at(2, 10, 0)),
+    ];
+
+    let ids_only = DedupOptions {
+        by_id: true,
+        title_similarity: None,
+    };
+    let out = dedup(&[&d2l, &google], &ids_only);
+    assert_eq!(out[1].len(), 1);
+    assert_eq!(out[1][0].title, "Essay");
+
+    let titles_only = DedupOptions {
+        by_id: false,
+        title_similarity: Some(0.8),
+    };
+    let out = dedup(&[&d2l, &google], &titles_only);
+    assert_eq!(out[1].len(), 1);
+    assert_eq!(out[1][0].title, "Other");
+}
+
+#[test]
+fn events_of_one_list_are_kept() {
+    let list = [
+        event("D2L", "a", "Lecture", at(2, 9, 0), at(2, 10, 0)),
+        event("D2L", "a", "Lecture", at(2, 9, 0), at(2, 10, 0)),
+    ];
+
+    let out = dedup(&[&list], &DedupOptions::default());
+    assert_eq!(out[0].len(), 2);
+}
@@ -0,0 +1,48 @@
+use chrono::{Local, TimeZone};
+
+use event_list::{ics, EventListError};
+
+#[test]
+fn unknown_properties_are_ignored() {
+    let events = ics::parse_events(
+        include_str!("fixtures/unknown_properties.ics"),
+        Local.with_ymd_and_hms(2023, 1, 1, 0, 0, 0).unwrap(),
+        Local.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap(),
+    )
+    .unwrap();
+
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].title, "Meeting");
+}
+
+#[test]
+fn people_are_kept() {
+    let events = ics::parse_events(
+        include_str!("fixtures/unknown_properties.ics"),
+        Local.with_ymd_and_hms(2023, 1, 1, 0, 0, 0).unwrap(),
+        Local.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap(),
+    )
+    .unwrap();
+
+    let organizer = events[0].organizer.as_ref().unwrap();
+    assert_eq!(organizer.name.as_deref(), Some("Prof"));
+    assert_eq!(organizer.email.as_deref(), Some("prof@example.com"));
+
+    assert_eq!(events[0].attendees.len(), 1);
+    assert_eq!(
+        events[0].attendees[0].email.as_deref(),
+        Some("student@example.com")
+    );
+    assert_eq!(events[0].attendees[0].response.as_deref(), Some("accepted"));
+}
+
+#[test]
+fn non_calendar_body_is_a_parse_error() {
+    let result = ics::parse_events(
+        "<html><body>Your session has expired</body></html>",
+        Local.with_ymd_and_hms(2023, 1, 1, 0, 0, 0).unwrap(),
+        Local.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap(),
+    );
+
+    assert!(matches!(result, Err(EventListError::Parse(_))));
+}
@@ -0,0 +1,92 @@
+use chrono::{Local, NaiveDate, TimeZone};
+
+use event_list::{ics, Event, EventStatus, EventTime, Person};
+
+fn events() -> Vec<Event> {
+    let start = Local.with_ymd_and_hms(2023, 5, 1, 9, 30, 0).unwrap();
+    let day = NaiveDate::from_ymd_opt(2023, 5, 2).unwrap();
+
+    vec![
+        Event {
+            title: "Standup; daily, with \\ team".to_string(),
+            desc: "Line one\nLine two".to_string(),
+            start: Some(EventTime::DateTime(start)),
+            end: Some(EventTime::DateTime(start + chrono::Duration::minutes(15))),
+            location: Some("Room 4".to_string()),
+            status: Some(EventStatus::Confirmed),
+            organizer: Some(Person {
+                name: Some("Prof, Ada".to_string()),
+                email: Some("ada@example.com".to_string()),
+                response: None,
+            }),
+            attendees: vec![Person {
+                name: None,
+                email: Some("student@example.com".to_string()),
+                response: Some("accepted".to_string()),
+            }],
+            categories: vec!["Work".to_string(), "Daily, mostly".to_string()],
+            color: Some("teal".to_string()),
+            transparent: true,
+            ..Default::default()
+        },
+        Event {
+            title: "Holiday".to_string(),
+            desc: "Très long ".repeat(20).trim_end().to_string(),
+            start: Some(EventTime::Date(day)),
+            end: Some(EventTime::Date(day.succ_opt().unwrap())),
+            class: None,
+            ..Default::default()
+        },
+    ]
+}
+
+#[test]
+fn exported_events_parse_back() {
+    let events = events();
+    let parsed = ics::parse_events(
+        &ics::write::to_ics(&events),
+        Local.with_ymd_and_hms(2023, 1, 1, 0, 0, 0).unwrap(),
+        Local.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap(),
+    )
+    .unwrap();
+
+    assert_eq!(parsed.len(), 2);
+    for (parsed, event) in parsed.iter().zip(&events) {
+        assert_eq!(parsed.title, event.title);
+        assert_eq!(parsed.desc, event.desc);
+        assert_eq!(parsed.start, event.start);
+        assert_eq!(parsed.end, event.end);
+        assert_eq!(parsed.location, event.location);
+        assert_eq!(parsed.status, event.status);
+        assert_eq!(parsed.organizer, event.organizer);
+        assert_eq!(parsed.attendees, event.attendees);
+        assert_eq!(parsed.categories, event.categories);
+        assert_eq!(parsed.color, event.color);
+        assert_eq!(parsed.transparent, event.transparent);
+    }
+}
+
+#[test]
+fn exported_lines_are_folded() {
+    let out = ics::write::to_ics(&events());
+
+    assert!(out.ends_with("END:VCALENDAR\r\n"));
+    for line in out.split("\r\n") {
+        assert!(line.len() <= 75, "{line:?} is too long");
+    }
+}
+
+#[test]
+fn uids_are_stable() {
+    let uids = |out: String| -> Vec<String> {
+        out.split("\r\n")
+            .filter(|l| l.starts_with("UID:"))
+            .map(str::to_string)
+            .collect()
+    };
+
+    let first = uids(ics::write::to_ics(&events()));
+    assert_eq!(first.len(), 2);
+    assert_ne!(first[0], first[1]);
+    assert_eq!(first, uids(ics::write::to_ics(&events())));
+}
@@ -0,0 +1,284 @@
+use std::path::PathBuf;
+
+use chrono::{DateTime, Local, TimeZone};
+
+use event_list::{ics, lists::localical::FileList, Event, EventList, EventListError};
+
+fn after() -> DateTime<Local> {
+    Local.with_ymd_and_hms(2023, 1, 1, 0, 0, 0).unwrap()
+}
+
+fn fixture(name: &str) -> PathBuf {
+    [env!("CARGO_MANIFEST_DIR"), "tests", "fixtures", name]
+        .iter()
+        .collect()
+}
+
+/// A fresh directory for one test
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("event_list_{name}_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[tokio::test]
+async fn file_matches_remote_parsing() {
+    let events = FileList::new(fixture("weekly.ics"))
+        .events(after())
+        .await
+        .unwrap();
+    let parsed = ics::parse_events(
+        include_str!("fixtures/weekly.ics"),
+        after(),
+        after() + chrono::Duration::days(365),
+    )
+    .unwrap();
+
+    assert!(!events.is_empty());
+    assert_eq!(events, parsed);
+}
+
+#[tokio::test]
+async fn directory_reads_every_ics_file() {
+    let dir = temp_dir("vdir");
+    std::fs::copy(fixture("weekly.ics"), dir.join("weekly.ics")).unwrap();
+    std::fs::copy(fixture("monthly.ics"), dir.join("monthly.ics")).unwrap();
+    std::fs::write(dir.join("broken.ics"), "not a calendar").unwrap();
+    std::fs::write(dir.join("notes.txt"), "ignored").unwrap();
+
+    let events = FileList::new(&dir).events(after()).await.unwrap();
+
+    let weekly = FileList::new(fixture("weekly.ics"))
+        .events(after())
+        .await
+        .unwrap();
+    let monthly = FileList::new(fixture("monthly.ics"))
+        .events(after())
+        .await
+        .unwrap();
+    assert_eq!(events.len(), weekly.len() + monthly.len());
+    assert!(events.windows(2).all(|w| w[0].start <= w[1].start));
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn changed_file_is_read_again() {
+    let dir = temp_dir("changes");
+    let file = dir.join("calendar.ics");
+    std::fs::copy(fixture("weekly.ics"), &file).unwrap();
+
+    let list = FileList::new(&file);
+    let before = list.events(after()).await.unwrap();
+
+    std::fs::copy(fixture("yearly.ics"), &file).unwrap();
+    let changed = list.events(after()).await.unwrap();
+
+    assert_ne!(before, changed);
+    assert_eq!(
+        changed,
+        FileList::new(fixture("yearly.ics"))
+            .events(after())
+            .await
+            .unwrap()
+    );
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn missing_file_is_an_error() {
+    let result = FileList::new(fixture("missing.ics")).events(after()).await;
+
+    assert!(matches!(result, Err(EventListError::Io(e)) if e.contains("missing.ics")));
+}
+
+#[tokio::test]
+async fn only_directories_can_be_written() {
+    let dir = temp_dir("writes");
+    let list = FileList::new(&dir);
+    let sink = list.sink().unwrap();
+    let mut deadline = Event {
+        title: "Deadline".to_string(),
+        desc: "Hand in the report".to_string(),
+        start: Some(Local.with_ymd_and_hms(2023, 6, 2, 17, 0, 0).unwrap().into()),
+        end: None,
+        class: None,
+        ..Default::default()
+    };
+
+    let uid = sink.create(&deadline).await.unwrap();
+    assert!(dir.join(format!("{uid}.ics")).is_file());
+
+    deadline.id = Some(uid.clone());
+    assert_eq!(list.events(after()).await.unwrap(), [deadline.clone()]);
+
+    deadline.title = "Deadline moved".to_string();
+    sink.update(&uid, &deadline).await.unwrap();
+    assert_eq!(list.events(after()).await.unwrap(), [deadline.clone()]);
+
+    sink.delete(&uid).await.unwrap();
+    assert!(list.events(after()).await.unwrap().is_empty());
+    assert!(matches!(
+        sink.delete(&uid).await,
+        Err(EventListError::NotFound(_))
+    ));
+
+    assert!(FileList::new(fixture("weekly.ics")).sink().is_none());
+}
+
+#[tokio::test]
+async fn occurrences_are_written_within_their_series() {
+    let dir = temp_dir("series");
+    std::fs::copy(fixture("overrides.ics"), dir.join("seminar.ics")).unwrap();
+    let list = FileList::new(&dir);
+    let sink = list.sink().unwrap();
+    let titles = || async {
+        list.events(after())
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|e| (e.id.unwrap(), e.title))
+            .collect::<Vec<_>>()
+    };
+
+    let events = list.events(after()).await.unwrap();
+    assert!(events
+        .iter()
+        .all(|e| e.series_id.as_deref() == Some("seminar@example.com")));
+
+    // Overriding one occurrence leaves the rest of the series alone
+    let mut first = events[0].clone();
+    first.title = "Seminar (first)".to_string();
+    sink.update("seminar@example.com/20231002T150000Z", &first)
+        .await
+        .unwrap();
+    // Overriding it again replaces the override
+    first.title = "Seminar (intro)".to_string();
+    sink.update("seminar@example.com/20231002T150000Z", &first)
+        .await
+        .unwrap();
+    sink.delete("seminar@example.com/20231023T150000Z")
+        .await
+        .unwrap();
+    assert_eq!(
+        titles().await,
+        [
+            ("seminar@example.com/20231002T150000Z", "Seminar (intro)"),
+            ("seminar@example.com/20231012T150000Z", "Seminar"),
+            ("seminar@example.com/20231013T150000Z", "Seminar"),
+            ("seminar@example.com/20231016T150000Z", "Seminar (moved)"),
+        ]
+        .map(|(id, title)| (id.to_string(), title.to_string()))
+    );
+
+    // Updating the series keeps its recurrence and overrides
+    let mut series = events[0].clone();
+    series.title = "Reading group".to_string();
+    sink.update("seminar@example.com", &series).await.unwrap();
+    assert_eq!(
+        titles().await,
+        [
+            ("seminar@example.com/20231002T150000Z", "Seminar (intro)"),
+            ("seminar@example.com/20231012T150000Z", "Reading group"),
+            ("seminar@example.com/20231013T150000Z", "Reading group"),
+            ("seminar@example.com/20231016T150000Z", "Seminar (moved)"),
+        ]
+        .map(|(id, title)| (id.to_string(), title.to_string()))
+    );
+
+    for id in [
+        "seminar@example.com/20231009T150000Z",
+        "seminar@example.com/20231030T150000Z",
+        "seminar@example.com/tomorrow",
+        "seminar@example.com.old",
+    ] {
+        assert!(
+            matches!(sink.delete(id).await, Err(EventListError::NotFound(_))),
+            "{id}"
+        );
+    }
+
+    sink.delete("seminar@example.com").await.unwrap();
+    assert!(list.events(after()).await.unwrap().is_empty());
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+/// Mondays at 09:00 in Paris, across the end of DST on October 29th, without the 23rd
+const PARIS_SERIES: &str = "BEGIN:VCALENDAR\r
+VERSION:2.0\r
+PRODID:-//Example//EN\r
+BEGIN:VEVENT\r
+UID:standup@example.com\r
+DTSTAMP:20231001T000000Z\r
+DTSTART;TZID=Europe/Paris:20231016T090000\r
+DTEND;TZID=Europe/Paris:20231016T091500\r
+RRULE:FREQ=WEEKLY;COUNT=4\r
+EXDATE;TZID=Europe/Paris:20231023T090000\r
+SUMMARY:Standup\r
+END:VEVENT\r
+END:VCALENDAR\r
+";
+
+#[tokio::test]
+async fn series_updates_keep_their_time_zone() {
+    let dir = temp_dir("paris");
+    std::fs::write(dir.join("standup.ics"), PARIS_SERIES).unwrap();
+    let list = FileList::new(&dir);
+    let starts = || async {
+        list.events(after())
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|e| {
+                (
+                    e.id.unwrap(),
+                    e.title,
+                    e.start.unwrap().instant().to_rfc3339(),
+                )
+            })
+            .collect::<Vec<_>>()
+    };
+    let expected = |title: &str| {
+        [
+            ("20231016T070000Z", "2023-10-16T07:00:00+00:00"),
+            ("20231030T080000Z", "2023-10-30T08:00:00+00:00"),
+            ("20231106T080000Z", "2023-11-06T08:00:00+00:00"),
+        ]
+        .map(|(id, start)| {
+            (
+                format!("standup@example.com/{id}"),
+                title.to_string(),
+                chrono::DateTime::parse_from_rfc3339(start)
+                    .unwrap()
+                    .with_timezone(&Local)
+                    .to_rfc3339(),
+            )
+        })
+    };
+    assert_eq!(starts().await, expected("Standup"));
+
+    let mut series = list.events(after()).await.unwrap()[0].clone();
+    series.title = "Daily".to_string();
+    list.sink()
+        .unwrap()
+        .update("standup@example.com", &series)
+        .await
+        .unwrap();
+
+    // Still 09:00 in Paris after the change, and the 23rd is still left out
+    assert_eq!(starts().await, expected("Daily"));
+    let body = std::fs::read_to_string(dir.join("standup.ics")).unwrap();
+    assert!(
+        body.contains("DTSTART;TZID=Europe/Paris:20231016T090000"),
+        "{body}"
+    );
+    assert!(
+        body.contains("DTEND;TZID=Europe/Paris:20231016T091500"),
+        "{body}"
+    );
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
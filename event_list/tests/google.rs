@@ -0,0 +1,812 @@
+#![cfg(feature = "google")]
+
+use std::{
+    collections::HashMap,
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpListener,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    thread,
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{DateTime, Duration, Local, TimeZone, Utc};
+use event_list::{
+    lists::{
+        google::{GoogleCredentials, GoogleList},
+        token_store::{FileStore, StoredToken, TokenStore},
+    },
+    Event, EventList, EventListError,
+};
+use reqwest::Url;
+use ring::digest;
+
+/// A request as seen by the mock server
+#[derive(Clone, Debug)]
+struct Request {
+    method: String,
+    /// Path and query
+    path: String,
+    authorization: Option<String>,
+    body: String,
+}
+
+/// Serves every request with `handler` until the test ends, recording them in the returned list
+fn serve(
+    handler: impl Fn(&Request) -> (u16, String) + Send + 'static,
+) -> (Url, Arc<Mutex<Vec<Request>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/calendar/v3", listener.local_addr().unwrap());
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let seen = requests.clone();
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(&stream);
+
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let mut parts = line.split_whitespace();
+            let (method, path) = (parts.next().unwrap(), parts.next().unwrap());
+
+            let mut len = 0;
+            let mut authorization = None;
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+                let header = header.trim();
+                if header.is_empty() {
+                    break;
+                }
+                let (name, value) = header.split_once(": ").unwrap();
+                match name.to_lowercase().as_str() {
+                    "content-length" => len = value.parse().unwrap(),
+                    "authorization" => authorization = Some(value.to_string()),
+                    _ => {}
+                }
+            }
+            let mut body = vec![0; len];
+            reader.read_exact(&mut body).unwrap();
+
+            let request = Request {
+                method: method.to_string(),
+                path: path.to_string(),
+                authorization,
+                body: String::from_utf8(body).unwrap(),
+            };
+            let (status, body) = handler(&request);
+            seen.lock().unwrap().push(request);
+
+            write!(
+                stream,
+                "HTTP/1.1 {status} Status\r\nContent-Type: application/json\r\n\
+                 Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            )
+            .unwrap();
+        }
+    });
+
+    (Url::parse(&url).unwrap(), requests)
+}
+
+fn fixture(name: &str) -> String {
+    [env!("CARGO_MANIFEST_DIR"), "tests", "fixtures", name]
+        .iter()
+        .collect::<PathBuf>()
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// A token file path of its own for `test`
+fn temp_token(test: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("event_list_{test}_{}.token", std::process::id()))
+}
+
+fn token() -> StoredToken {
+    StoredToken {
+        access_token: "ya29.access".to_string(),
+        refresh_token: "1//refresh".to_string(),
+        expires_at: Some(Utc.with_ymd_and_hms(2024, 3, 1, 12, 0, 0).unwrap()),
+    }
+}
+
+#[test]
+fn credentials_from_client_secret_file() {
+    let credentials = GoogleCredentials::from_json_file(&fixture("client_secret.json")).unwrap();
+
+    assert_eq!(
+        credentials,
+        GoogleCredentials {
+            client_id: "1234-abcd.apps.googleusercontent.com".to_string(),
+            client_secret: "GOCSPX-secret".to_string(),
+        }
+    );
+}
+
+#[test]
+fn credentials_file_errors() {
+    assert!(matches!(
+        GoogleCredentials::from_json_file(&fixture("missing.json")),
+        Err(EventListError::Auth(_))
+    ));
+
+    // Valid JSON, but not a client secret file
+    let path = std::env::temp_dir().join(format!("event_list_token_{}.json", std::process::id()));
+    std::fs::write(&path, r#"{"access_token": "abc"}"#).unwrap();
+    let result = GoogleCredentials::from_json_file(&path.to_string_lossy());
+    std::fs::remove_file(&path).unwrap();
+    assert!(matches!(result, Err(EventListError::Parse(e)) if e.contains("client_id")));
+}
+
+#[test]
+fn file_store_round_trip() {
+    let path = temp_token("round_trip");
+    let store = FileStore::new(&path);
+    assert_eq!(store.load().unwrap(), None);
+
+    store.save(&token()).unwrap();
+    assert_eq!(store.load().unwrap(), Some(token()));
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    store.clear().unwrap();
+    assert!(!path.exists());
+    assert_eq!(store.load().unwrap(), None);
+}
+
+#[cfg(unix)]
+#[test]
+fn file_store_replaces_stale_temp_files() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let path = temp_token("stale");
+    let tmp = path.with_extension("token.tmp");
+    std::fs::write(&tmp, "left by a crash").unwrap();
+    std::fs::set_permissions(&tmp, std::fs::Permissions::from_mode(0o644)).unwrap();
+
+    let store = FileStore::new(&path);
+    store.save(&token()).unwrap();
+    let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+    let loaded = store.load();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(mode & 0o777, 0o600);
+    assert_eq!(loaded.unwrap(), Some(token()));
+    assert!(!tmp.exists());
+}
+
+#[test]
+fn file_store_reads_two_line_tokens() {
+    let path = temp_token("two_lines");
+    std::fs::write(&path, "ya29.access\n1//refresh").unwrap();
+    let loaded = FileStore::new(&path).load();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(
+        loaded.unwrap(),
+        Some(StoredToken {
+            expires_at: None,
+            ..token()
+        })
+    );
+}
+
+#[test]
+fn encrypted_file_store() {
+    let path = temp_token("encrypted");
+    FileStore::new(&path)
+        .with_passphrase("correct horse")
+        .save(&token())
+        .unwrap();
+
+    let data = std::fs::read_to_string(&path).unwrap();
+    let loaded = FileStore::new(&path)
+        .with_passphrase("correct horse")
+        .load();
+    let wrong = FileStore::new(&path)
+        .with_passphrase("battery staple")
+        .load();
+    let missing = FileStore::new(&path).load();
+    std::fs::remove_file(&path).unwrap();
+
+    assert!(!data.contains("ya29.access") && !data.contains("1//refresh"));
+    assert_eq!(loaded.unwrap(), Some(token()));
+    assert!(matches!(wrong, Err(EventListError::Auth(e)) if e.contains("wrong passphrase")));
+    assert!(matches!(missing, Err(EventListError::Auth(e)) if e.contains("no passphrase")));
+}
+
+fn credentials() -> GoogleCredentials {
+    GoogleCredentials {
+        client_id: "1234-abcd.apps.googleusercontent.com".to_string(),
+        client_secret: "GOCSPX-secret".to_string(),
+    }
+}
+
+/// A list signed in with a token that doesn't expire, querying the API at `host`
+async fn signed_in(test: &str, host: Url) -> GoogleList {
+    signed_in_to(test, host, Vec::new()).await
+}
+
+/// Like `signed_in`, showing the calendars in `selection`
+async fn signed_in_to(test: &str, host: Url, selection: Vec<&str>) -> GoogleList {
+    let (list, path) = signed_in_with(
+        test,
+        host,
+        StoredToken {
+            expires_at: None,
+            ..token()
+        },
+    )
+    .await;
+    std::fs::remove_file(&path).unwrap();
+    list.with_calendars(selection.into_iter().map(str::to_string).collect())
+}
+
+/// A list signed in with `token`, exchanging tokens at the mock server too, and the file
+/// it keeps its tokens in
+async fn signed_in_with(test: &str, host: Url, token: StoredToken) -> (GoogleList, PathBuf) {
+    let path = temp_token(test);
+    let store = FileStore::new(&path);
+    store.save(&token).unwrap();
+
+    let list = GoogleList::from_store(Box::new(store), &credentials())
+        .await
+        .with_token_url(host.join("/token").unwrap())
+        .with_host(host);
+    (list, path)
+}
+
+/// Only the user's own calendar
+const CALENDAR_LIST: &str = r##"{"items": [{"id": "me@example.com", "summary": "Me",
+    "primary": true, "selected": true, "backgroundColor": "#9fe1e7"}]}"##;
+
+/// An occurrence of the `standup` series, on the `day`th of June 2030
+fn standup(day: u32) -> String {
+    format!(
+        r#"{{"id": "standup_203006{day:02}T090000Z", "recurringEventId": "standup",
+        "status": "confirmed", "summary": "Standup",
+        "start": {{"dateTime": "2030-06-{day:02}T09:00:00Z"}},
+        "end": {{"dateTime": "2030-06-{day:02}T09:15:00Z"}}}}"#
+    )
+}
+
+/// The whole calendar, then the first occurrence deleted, then an expired sync token
+fn events(r: &Request) -> (u16, String) {
+    let page = |items: &[String], token: &str| {
+        format!(
+            r#"{{"items": [{}], "nextSyncToken": "{token}"}}"#,
+            items.join(",")
+        )
+    };
+
+    if r.path.contains("syncToken=s1") {
+        let deleted = r#"{"id": "standup_20300603T090000Z", "status": "cancelled"}"#;
+        (200, page(&[deleted.to_string()], "s2"))
+    } else if r.path.contains("syncToken=s2") {
+        (
+            410,
+            r#"{"error": {"code": 410, "message": "Sync token is no longer valid"}}"#.to_string(),
+        )
+    } else {
+        let past = r#"{"id": "kickoff", "status": "confirmed", "summary": "Kickoff",
+            "start": {"date": "2029-12-01"}, "end": {"date": "2029-12-02"}}"#;
+        (200, page(&[past.to_string(), standup(3), standup(4)], "s1"))
+    }
+}
+
+fn ids(events: &[Event]) -> Vec<&str> {
+    let mut out: Vec<_> = events.iter().filter_map(|e| e.id.as_deref()).collect();
+    out.sort();
+    out
+}
+
+#[tokio::test]
+async fn syncs_occurrences_incrementally() {
+    let (host, requests) = serve(|r| match r.path.as_str() {
+        "/calendar/v3/users/me/calendarList" => (200, CALENDAR_LIST.to_string()),
+        p if p.starts_with("/calendar/v3/calendars/me@example.com/events?") => events(r),
+        _ => (404, String::new()),
+    });
+    let list = signed_in("sync", host).await;
+    let after = Local.with_ymd_and_hms(2030, 1, 1, 0, 0, 0).unwrap();
+
+    let first = list.events(after).await.unwrap();
+    assert_eq!(
+        ids(&first),
+        ["standup_20300603T090000Z", "standup_20300604T090000Z"]
+    );
+    assert!(first
+        .iter()
+        .all(|e| e.series_id.as_deref() == Some("standup")
+            && e.calendar.as_deref() == Some("Me")
+            && e.color.as_deref() == Some("#9fe1e7")));
+
+    let changed = list.events(after).await.unwrap();
+    assert_eq!(ids(&changed), ["standup_20300604T090000Z"]);
+
+    // The expired token falls back to a full sync
+    let full = list.events(after).await.unwrap();
+    assert_eq!(ids(&full), ids(&first));
+
+    // Past the window of the last full sync the calendar is fetched again
+    let later = Local.with_ymd_and_hms(2030, 3, 1, 0, 0, 0).unwrap();
+    list.events(later).await.unwrap();
+
+    let requests = requests.lock().unwrap();
+    let syncs: Vec<_> = requests
+        .iter()
+        .filter(|r| r.path.contains("/events?"))
+        .collect();
+    assert_eq!(syncs.len(), 5);
+    assert!(syncs.iter().all(|r| r.path.contains("singleEvents=true")
+        && r.authorization.as_deref() == Some("Bearer ya29.access")));
+    // Full syncs only fetch the horizon, the sync token remembers it
+    let window = |r: &Request| {
+        let query = pairs(r.path.split_once('?').unwrap().1);
+        let bound = |key| {
+            query.get(key).map(|t| {
+                DateTime::parse_from_rfc3339(t)
+                    .unwrap()
+                    .with_timezone(&Local)
+            })
+        };
+        (bound("timeMin"), bound("timeMax"))
+    };
+    let full_window = |from: DateTime<Local>| (Some(from), Some(from + Duration::days(395)));
+    assert!(!syncs[0].path.contains("syncToken"));
+    assert_eq!(window(syncs[0]), full_window(after));
+    assert!(syncs[1].path.contains("syncToken=s1"));
+    assert_eq!(window(syncs[1]), (None, None));
+    assert!(syncs[2].path.contains("syncToken=s2"));
+    assert!(!syncs[3].path.contains("syncToken"));
+    assert_eq!(window(syncs[3]), full_window(after));
+    assert!(!syncs[4].path.contains("syncToken"));
+    assert_eq!(window(syncs[4]), full_window(later));
+}
+
+#[tokio::test]
+async fn writes_go_to_the_calendar_of_the_event() {
+    let (host, requests) = serve(|r| match (r.method.as_str(), r.path.as_str()) {
+        ("GET", "/calendar/v3/users/me/calendarList") => (200, CALENDAR_LIST.to_string()),
+        ("GET", p) if p.contains("/events?") => events(r),
+        ("POST", "/calendar/v3/calendars/primary/events") => {
+            (200, r#"{"id": "created"}"#.to_string())
+        }
+        ("PATCH", "/calendar/v3/calendars/me@example.com/events/standup_20300603T090000Z") => {
+            (200, standup(3))
+        }
+        _ => (404, String::new()),
+    });
+    let list = signed_in("writes", host).await;
+    let sink = list.sink().unwrap();
+    list.events(Local.with_ymd_and_hms(2030, 1, 1, 0, 0, 0).unwrap())
+        .await
+        .unwrap();
+    let planning = Event {
+        title: "Planning".to_string(),
+        desc: String::new(),
+        start: Some(Local.with_ymd_and_hms(2030, 6, 3, 9, 0, 0).unwrap().into()),
+        end: None,
+        class: None,
+        ..Default::default()
+    };
+
+    assert_eq!(sink.create(&planning).await.unwrap(), "created");
+    sink.update("standup_20300603T090000Z", &planning)
+        .await
+        .unwrap();
+    assert!(matches!(
+        sink.delete("missing").await,
+        Err(EventListError::NotFound(id)) if id == "missing"
+    ));
+
+    let requests = requests.lock().unwrap();
+    let patch = requests.iter().find(|r| r.method == "PATCH").unwrap();
+    let body: serde_json::Value = serde_json::from_str(&patch.body).unwrap();
+    assert_eq!(body["summary"], "Planning");
+    // Fields `Event` doesn't model aren't sent, so Google keeps them
+    assert!(body.get("recurrence").is_none() && body.get("attendees").is_none());
+    assert!(requests
+        .iter()
+        .any(|r| r.method == "DELETE" && r.path.ends_with("/calendars/primary/events/missing")));
+}
+
+#[tokio::test]
+async fn new_events_go_to_the_first_picked_calendar() {
+    let (host, requests) = serve(|r| match (r.method.as_str(), r.path.as_str()) {
+        ("POST", "/calendar/v3/calendars/team@group.calendar.google.com/events") => {
+            (200, r#"{"id": "created"}"#.to_string())
+        }
+        _ => (404, String::new()),
+    });
+    let list = signed_in_to(
+        "create",
+        host,
+        vec!["team@group.calendar.google.com", "primary"],
+    )
+    .await;
+    let planning = Event {
+        title: "Planning".to_string(),
+        start: Some(Local.with_ymd_and_hms(2030, 6, 3, 9, 0, 0).unwrap().into()),
+        ..Default::default()
+    };
+
+    assert_eq!(
+        list.sink().unwrap().create(&planning).await.unwrap(),
+        "created"
+    );
+    assert_eq!(requests.lock().unwrap().len(), 1);
+}
+
+/// The events of a calendar called `summary`: a meeting, and a lunch with a color of its own
+fn meetings(summary: &str) -> String {
+    format!(
+        r#"{{"summary": "{summary}", "nextSyncToken": "s1", "items": [
+        {{"id": "meeting-{summary}", "status": "confirmed", "summary": "Meeting",
+          "start": {{"dateTime": "2030-06-03T10:00:00Z"}},
+          "end": {{"dateTime": "2030-06-03T11:00:00Z"}}}},
+        {{"id": "lunch-{summary}", "status": "confirmed", "summary": "Lunch", "colorId": "2",
+          "start": {{"dateTime": "2030-06-03T12:00:00Z"}},
+          "end": {{"dateTime": "2030-06-03T13:00:00Z"}}}}]}}"#
+    )
+}
+
+/// The calendar, name and color of each event, sorted
+fn tags(events: &[Event]) -> Vec<(&str, &str, &str)> {
+    let mut out: Vec<_> = events
+        .iter()
+        .map(|e| {
+            (
+                e.id.as_deref().unwrap_or_default(),
+                e.calendar.as_deref().unwrap_or_default(),
+                e.color.as_deref().unwrap_or_default(),
+            )
+        })
+        .collect();
+    out.sort();
+    out
+}
+
+/// Calendars of an account with its own calendar, a team calendar and a hidden one
+fn account(r: &Request) -> (u16, String) {
+    match r.path.split('?').next().unwrap() {
+        "/calendar/v3/users/me/calendarList" => (
+            200,
+            r##"{"items": [
+            {"id": "me@example.com", "summary": "me@example.com", "primary": true,
+             "selected": true, "backgroundColor": "#9fe1e7"},
+            {"id": "team@group.calendar.google.com", "summary": "Engineering",
+             "summaryOverride": "Team", "selected": true, "backgroundColor": "#fad165"},
+            {"id": "holidays@group.v.calendar.google.com", "summary": "Holidays",
+             "selected": false}]}"##
+                .to_string(),
+        ),
+        "/calendar/v3/calendars/primary/events" => (200, meetings("me@example.com")),
+        "/calendar/v3/calendars/me@example.com/events" => (200, meetings("me@example.com")),
+        "/calendar/v3/calendars/team@group.calendar.google.com/events" => {
+            (200, meetings("Engineering"))
+        }
+        _ => (404, String::new()),
+    }
+}
+
+#[tokio::test]
+async fn picks_calendars_by_id_without_the_calendar_list() {
+    let (host, requests) = serve(|r| match r.path.as_str() {
+        // As for tokens that were only granted the events scope
+        "/calendar/v3/users/me/calendarList" => (
+            403,
+            r#"{"error": {"code": 403, "message": "Request had insufficient authentication
+            scopes.", "errors": [{"reason": "insufficientPermissions"}]}}"#
+                .to_string(),
+        ),
+        _ => account(r),
+    });
+    let list = signed_in_to(
+        "by_id",
+        host,
+        vec!["primary", "team@group.calendar.google.com", "primary"],
+    )
+    .await;
+
+    let events = list
+        .events(Local.with_ymd_and_hms(2030, 1, 1, 0, 0, 0).unwrap())
+        .await
+        .unwrap();
+
+    // Named as the events list names them, without the colors of the calendar list
+    assert_eq!(
+        tags(&events),
+        [
+            ("lunch-Engineering", "Engineering", "#33b679"),
+            ("lunch-me@example.com", "me@example.com", "#33b679"),
+            ("meeting-Engineering", "Engineering", ""),
+            ("meeting-me@example.com", "me@example.com", ""),
+        ]
+    );
+    assert!(!requests
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|r| r.path.contains("calendarList")));
+}
+
+#[tokio::test]
+async fn picks_calendars_by_name_from_the_calendar_list() {
+    let (host, requests) = serve(account);
+    let list = signed_in_to("by_name", host, vec!["TEAM", "primary", "Missing"]).await;
+
+    let events = list
+        .events(Local.with_ymd_and_hms(2030, 1, 1, 0, 0, 0).unwrap())
+        .await
+        .unwrap();
+
+    assert_eq!(
+        tags(&events),
+        [
+            ("lunch-Engineering", "Team", "#33b679"),
+            ("lunch-me@example.com", "me@example.com", "#33b679"),
+            ("meeting-Engineering", "Team", "#fad165"),
+            ("meeting-me@example.com", "me@example.com", "#9fe1e7"),
+        ]
+    );
+    assert!(!requests
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|r| r.path.contains("holidays")));
+
+    // Without a selection, those shown in Google Calendar
+    let (host, _) = serve(account);
+    let list = signed_in("selected", host).await;
+    let events = list
+        .events(Local.with_ymd_and_hms(2030, 1, 1, 0, 0, 0).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(events.len(), 4);
+}
+
+#[tokio::test]
+async fn missing_scope_needs_a_new_sign_in() {
+    let (host, _) = serve(|r| match r.path.as_str() {
+        "/calendar/v3/users/me/calendarList" => (
+            403,
+            r#"{"error": {"code": 403, "message": "Request had insufficient authentication
+            scopes.", "details": [{"reason": "ACCESS_TOKEN_SCOPE_INSUFFICIENT"}]}}"#
+                .to_string(),
+        ),
+        _ => (
+            403,
+            r#"{"error": {"code": 403, "message": "Calendar usage limits exceeded."}}"#.to_string(),
+        ),
+    });
+    let after = Local.with_ymd_and_hms(2030, 1, 1, 0, 0, 0).unwrap();
+
+    let by_name = signed_in_to("scope", host.clone(), vec!["Team"]).await;
+    assert!(matches!(
+        by_name.events(after).await,
+        Err(EventListError::Unauthenticated(e)) if e.contains("/auth/google/start")
+    ));
+
+    let by_id = signed_in_to("limits", host, vec!["primary"]).await;
+    assert!(matches!(
+        by_id.events(after).await,
+        Err(EventListError::Auth(e)) if e.contains("usage limits exceeded")
+    ));
+}
+
+/// An empty `primary` calendar for the new access token, the old one being rejected
+fn refreshed(r: &Request) -> (u16, String) {
+    match r.path.as_str() {
+        "/token" => (
+            200,
+            r#"{"access_token": "ya29.new", "expires_in": 3599, "token_type": "Bearer"}"#
+                .to_string(),
+        ),
+        p if p.starts_with("/calendar/v3/calendars/primary/events?") => {
+            if r.authorization.as_deref() == Some("Bearer ya29.new") {
+                (200, r#"{"items": [], "nextSyncToken": "s1"}"#.to_string())
+            } else {
+                (
+                    401,
+                    r#"{"error": {"code": 401, "message": "Invalid Credentials"}}"#.to_string(),
+                )
+            }
+        }
+        _ => (404, String::new()),
+    }
+}
+
+/// The method and path of each request, with the refresh token sent to the token endpoint
+fn calls(requests: &Mutex<Vec<Request>>) -> Vec<String> {
+    requests
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|r| match r.path.as_str() {
+            "/token" => {
+                assert!(r.body.contains("grant_type=refresh_token"), "{}", r.body);
+                assert!(r.body.contains("client_id=1234-abcd"), "{}", r.body);
+                let refresh = r.body.contains("refresh_token=1%2F%2Frefresh");
+                format!("{} /token refresh={refresh}", r.method)
+            }
+            p => format!(
+                "{} {} {}",
+                r.method,
+                p.split('?').next().unwrap(),
+                r.authorization.as_deref().unwrap_or_default()
+            ),
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn refreshes_expired_tokens() {
+    let (host, requests) = serve(refreshed);
+    let (list, path) = signed_in_with("expired", host, token()).await;
+    let list = list.with_calendars(vec!["primary".to_string()]);
+    let after = Local.with_ymd_and_hms(2030, 1, 1, 0, 0, 0).unwrap();
+
+    let events = list.events(after).await;
+    let saved = FileStore::new(&path).load();
+    std::fs::remove_file(&path).unwrap();
+
+    assert!(events.unwrap().is_empty());
+    assert_eq!(
+        calls(&requests),
+        [
+            "POST /token refresh=true",
+            "GET /calendar/v3/calendars/primary/events Bearer ya29.new"
+        ]
+    );
+    let saved = saved.unwrap().unwrap();
+    assert_eq!(saved.access_token, "ya29.new");
+    assert_eq!(saved.refresh_token, "1//refresh");
+    assert!(saved.expires_at.unwrap() > Utc::now());
+}
+
+#[tokio::test]
+async fn refreshes_rejected_tokens() {
+    let (host, requests) = serve(refreshed);
+    let (list, path) = signed_in_with(
+        "rejected",
+        host,
+        StoredToken {
+            expires_at: None,
+            ..token()
+        },
+    )
+    .await;
+    let list = list.with_calendars(vec!["primary".to_string()]);
+    let after = Local.with_ymd_and_hms(2030, 1, 1, 0, 0, 0).unwrap();
+
+    let events = list.events(after).await;
+    let saved = FileStore::new(&path).load();
+    std::fs::remove_file(&path).unwrap();
+
+    assert!(events.unwrap().is_empty());
+    assert_eq!(
+        calls(&requests),
+        [
+            "GET /calendar/v3/calendars/primary/events Bearer ya29.access",
+            "POST /token refresh=true",
+            "GET /calendar/v3/calendars/primary/events Bearer ya29.new"
+        ]
+    );
+    assert_eq!(saved.unwrap().unwrap().access_token, "ya29.new");
+}
+
+#[tokio::test]
+async fn revoked_tokens_are_forgotten() {
+    let (host, requests) = serve(|r| match r.path.as_str() {
+        "/token" => (
+            400,
+            r#"{"error": "invalid_grant", "error_description": "Token has been revoked."}"#
+                .to_string(),
+        ),
+        _ => refreshed(r),
+    });
+    let (list, path) = signed_in_with("revoked", host, token()).await;
+    let list = list.with_calendars(vec!["primary".to_string()]);
+    let after = Local.with_ymd_and_hms(2030, 1, 1, 0, 0, 0).unwrap();
+
+    assert!(matches!(
+        list.events(after).await,
+        Err(EventListError::Unauthenticated(e)) if e.contains("/auth/google/start")
+    ));
+    assert!(!path.exists());
+    assert_eq!(FileStore::new(&path).load().unwrap(), None);
+
+    // Signed out now, without asking Google again
+    assert!(matches!(
+        list.events(after).await,
+        Err(EventListError::Unauthenticated(_))
+    ));
+    assert_eq!(calls(&requests), ["POST /token refresh=true"]);
+}
+
+/// The pairs of a query string or form body
+fn pairs(query: &str) -> HashMap<String, String> {
+    Url::parse(&format!("http://localhost/?{query}"))
+        .unwrap()
+        .query_pairs()
+        .into_owned()
+        .collect()
+}
+
+#[tokio::test]
+async fn sign_in_uses_pkce_and_its_state_once() {
+    let (host, requests) = serve(|r| match r.path.as_str() {
+        "/token" => (
+            200,
+            r#"{"access_token": "ya29.first", "refresh_token": "1//first", "expires_in": 3599}"#
+                .to_string(),
+        ),
+        _ => (404, String::new()),
+    });
+    let path = temp_token("sign_in");
+    let list = GoogleList::new(Box::new(FileStore::new(&path)), &credentials())
+        .with_token_url(host.join("/token").unwrap())
+        .with_host(host);
+    let sign_in = list.sign_in().unwrap();
+    let callback = "http://localhost:3001/auth/google/callback";
+
+    let start = pairs(
+        Url::parse(&sign_in.start(callback).unwrap())
+            .unwrap()
+            .query()
+            .unwrap(),
+    );
+    assert_eq!(start["code_challenge_method"], "S256");
+    assert_eq!(start["redirect_uri"], callback);
+    let state = &start["state"];
+    assert!(sign_in.expects(state));
+
+    sign_in.finish(state, "4/code").await.unwrap();
+    let saved = FileStore::new(&path).load();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(saved.unwrap().unwrap().refresh_token, "1//first");
+
+    // The code is only redeemed with the verifier the challenge was made from
+    let token = pairs(&requests.lock().unwrap()[0].body);
+    assert_eq!(token["grant_type"], "authorization_code");
+    assert_eq!(token["code"], "4/code");
+    assert_eq!(token["redirect_uri"], callback);
+    let challenge = digest::digest(&digest::SHA256, token["code_verifier"].as_bytes());
+    assert_eq!(URL_SAFE_NO_PAD.encode(challenge), start["code_challenge"]);
+
+    // A state finishes one sign in only
+    assert!(!sign_in.expects(state));
+    assert!(matches!(
+        sign_in.finish(state, "4/code").await,
+        Err(EventListError::Auth(e)) if e.contains("Unknown or expired")
+    ));
+    assert_eq!(requests.lock().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn sign_in_rejects_unknown_states() {
+    let (host, requests) = serve(|_| (500, String::new()));
+    let list = GoogleList::new(
+        Box::new(FileStore::new(temp_token("forged"))),
+        &credentials(),
+    )
+    .with_token_url(host.join("/token").unwrap());
+    let sign_in = list.sign_in().unwrap();
+    sign_in
+        .start("http://localhost:3001/auth/google/callback")
+        .unwrap();
+
+    assert!(!sign_in.expects("forged"));
+    assert!(matches!(
+        sign_in.finish("forged", "4/code").await,
+        Err(EventListError::Auth(e)) if e.contains("Unknown or expired")
+    ));
+    assert!(requests.lock().unwrap().is_empty());
+}
@@ -0,0 +1,350 @@
+use chrono::{DateTime, Local, NaiveDate, TimeZone, Utc};
+
+use event_list::{ics, Event, EventTime};
+
+fn utc(y: i32, m: u32, d: u32, h: u32, min: u32) -> DateTime<Local> {
+    Utc.with_ymd_and_hms(y, m, d, h, min, 0)
+        .unwrap()
+        .with_timezone(&Local)
+}
+
+fn date(y: i32, m: u32, d: u32) -> EventTime {
+    EventTime::Date(NaiveDate::from_ymd_opt(y, m, d).unwrap())
+}
+
+/// Start times of every event titled `title`, in UTC
+fn starts(events: &[Event], title: &str) -> Vec<DateTime<Utc>> {
+    let mut out: Vec<_> = events
+        .iter()
+        .filter(|e| e.title == title)
+        .map(|e| e.start.unwrap().instant().with_timezone(&Utc))
+        .collect();
+    out.sort();
+    out
+}
+
+#[test]
+fn weekly_byday_until() {
+    let events = ics::parse_events(
+        include_str!("fixtures/weekly.ics"),
+        utc(2023, 9, 1, 0, 0),
+        utc(2024, 9, 1, 0, 0),
+    )
+    .unwrap();
+
+    let lectures = starts(&events, "CSC 352 Lecture");
+    let days: Vec<_> = lectures
+        .iter()
+        .map(|d| d.format("%d").to_string())
+        .collect();
+    assert_eq!(
+        days,
+        ["04", "06", "08", "11", "13", "15", "18", "20", "22", "25", "27", "29"]
+    );
+
+    let first = events
+        .iter()
+        .find(|e| e.title == "CSC 352 Lecture")
+        .unwrap();
+    assert_eq!(
+        first.end.unwrap().instant() - first.start.unwrap().instant(),
+        chrono::Duration::minutes(50)
+    );
+}
+
+#[test]
+fn weekly_interval_count() {
+    let events = ics::parse_events(
+        include_str!("fixtures/weekly.ics"),
+        utc(2023, 9, 1, 0, 0),
+        utc(2024, 9, 1, 0, 0),
+    )
+    .unwrap();
+
+    assert_eq!(
+        starts(&events, "CSC 352 Lab"),
+        [
+            utc(2023, 9, 5, 18, 0),
+            utc(2023, 9, 19, 18, 0),
+            utc(2023, 10, 3, 18, 0),
+        ]
+    );
+}
+
+#[test]
+fn window_limits_occurrences() {
+    let events = ics::parse_events(
+        include_str!("fixtures/weekly.ics"),
+        utc(2023, 9, 20, 0, 0),
+        utc(2023, 9, 23, 0, 0),
+    )
+    .unwrap();
+
+    assert_eq!(
+        starts(&events, "CSC 352 Lecture"),
+        [utc(2023, 9, 20, 16, 0), utc(2023, 9, 22, 16, 0)]
+    );
+    assert_eq!(starts(&events, "CSC 352 Lab"), [] as [DateTime<Utc>; 0]);
+}
+
+#[test]
+fn monthly_bysetpos() {
+    let events = ics::parse_events(
+        include_str!("fixtures/monthly.ics"),
+        utc(2023, 1, 1, 0, 0),
+        utc(2025, 1, 1, 0, 0),
+    )
+    .unwrap();
+
+    assert_eq!(
+        starts(&events, "Timesheet due"),
+        [
+            utc(2023, 9, 29, 17, 0),
+            utc(2023, 10, 31, 17, 0),
+            utc(2023, 11, 30, 17, 0),
+            utc(2023, 12, 29, 17, 0),
+        ]
+    );
+}
+
+#[test]
+fn monthly_negative_bymonthday() {
+    let events = ics::parse_events(
+        include_str!("fixtures/monthly.ics"),
+        utc(2023, 1, 1, 0, 0),
+        utc(2025, 1, 1, 0, 0),
+    )
+    .unwrap();
+
+    assert_eq!(
+        starts(&events, "Report"),
+        [
+            utc(2023, 1, 31, 12, 0),
+            utc(2023, 3, 31, 12, 0),
+            utc(2023, 5, 31, 12, 0),
+        ]
+    );
+}
+
+#[test]
+fn yearly_bymonth_ordinal_byday() {
+    let events = ics::parse_events(
+        include_str!("fixtures/yearly.ics"),
+        utc(2023, 1, 1, 0, 0),
+        utc(2025, 1, 1, 0, 0),
+    )
+    .unwrap();
+
+    let days: Vec<_> = events
+        .iter()
+        .filter(|e| e.title == "Thanksgiving")
+        .map(|e| (e.start.unwrap(), e.end.unwrap()))
+        .collect();
+    assert_eq!(
+        days,
+        [
+            (date(2023, 11, 23), date(2023, 11, 24)),
+            (date(2024, 11, 28), date(2024, 11, 29)),
+        ]
+    );
+}
+
+#[test]
+fn yearly_skips_invalid_dates() {
+    let events = ics::parse_events(
+        include_str!("fixtures/yearly.ics"),
+        utc(2020, 1, 1, 0, 0),
+        utc(2030, 1, 1, 0, 0),
+    )
+    .unwrap();
+
+    assert_eq!(
+        starts(&events, "Leap day"),
+        [
+            utc(2020, 2, 29, 10, 0),
+            utc(2024, 2, 29, 10, 0),
+            utc(2028, 2, 29, 10, 0),
+        ]
+    );
+}
+
+#[test]
+fn rrule_parse_errors() {
+    assert!("INTERVAL=2".parse::<ics::rrule::RRule>().is_err());
+    assert!("FREQ=FORTNIGHTLY".parse::<ics::rrule::RRule>().is_err());
+    assert!("FREQ=WEEKLY;BYDAY=XX".parse::<ics::rrule::RRule>().is_err());
+    assert!("FREQ=WEEKLY;INTERVAL=0"
+        .parse::<ics::rrule::RRule>()
+        .is_err());
+}
+
+#[test]
+fn overrides_exdate_rdate() {
+    let events = ics::parse_events(
+        include_str!("fixtures/overrides.ics"),
+        utc(2023, 9, 1, 0, 0),
+        utc(2024, 9, 1, 0, 0),
+    )
+    .unwrap();
+
+    // The sequence 0 master is replaced entirely by sequence 1
+    assert!(starts(&events, "Seminar (old)").is_empty());
+
+    assert_eq!(
+        starts(&events, "Seminar"),
+        [
+            utc(2023, 10, 2, 15, 0),
+            utc(2023, 10, 12, 15, 0),
+            utc(2023, 10, 13, 15, 0),
+            utc(2023, 10, 23, 15, 0),
+        ]
+    );
+
+    let period = events
+        .iter()
+        .find(|e| e.start == Some(utc(2023, 10, 13, 15, 0).into()))
+        .unwrap();
+    assert_eq!(period.end, Some(utc(2023, 10, 13, 17, 0).into()));
+
+    let extra = events
+        .iter()
+        .find(|e| e.start == Some(utc(2023, 10, 12, 15, 0).into()))
+        .unwrap();
+    assert_eq!(extra.end, Some(utc(2023, 10, 12, 16, 0).into()));
+
+    assert_eq!(
+        starts(&events, "Seminar (moved)"),
+        [utc(2023, 10, 17, 18, 0)]
+    );
+}
+
+#[test]
+fn occurrence_ids() {
+    let events = ics::parse_events(
+        include_str!("fixtures/overrides.ics"),
+        utc(2023, 9, 1, 0, 0),
+        utc(2024, 9, 1, 0, 0),
+    )
+    .unwrap();
+
+    let mut ids: Vec<_> = events.iter().map(|e| e.id.as_deref().unwrap()).collect();
+    ids.sort();
+
+    // The moved occurrence keeps the id of the one it replaces
+    assert_eq!(
+        ids,
+        [
+            "seminar@example.com/20231002T150000Z",
+            "seminar@example.com/20231012T150000Z",
+            "seminar@example.com/20231013T150000Z",
+            "seminar@example.com/20231016T150000Z",
+            "seminar@example.com/20231023T150000Z",
+        ]
+    );
+
+    // Events that don't recur keep their bare UID
+    let single = ics::parse_events(
+        include_str!("fixtures/unknown_properties.ics"),
+        utc(2023, 1, 1, 0, 0),
+        utc(2024, 1, 1, 0, 0),
+    )
+    .unwrap();
+    assert_eq!(single[0].id.as_deref(), Some("meeting@example.com"));
+    assert_eq!(
+        single[0].url.as_deref(),
+        Some("https://example.com/meeting")
+    );
+    assert_eq!(
+        single[0].last_modified,
+        Some(Utc.with_ymd_and_hms(2023, 8, 15, 12, 0, 0).unwrap())
+    );
+}
+
+#[test]
+fn duration_instead_of_dtend() {
+    let events = ics::parse_events(
+        "BEGIN:VCALENDAR\r
+BEGIN:VEVENT\r
+UID:talk@example.com\r
+DTSTART:20240304T140000Z\r
+DURATION:PT1H30M\r
+RRULE:FREQ=DAILY;COUNT=2\r
+SUMMARY:Talk\r
+END:VEVENT\r
+BEGIN:VEVENT\r
+UID:retreat@example.com\r
+DTSTART;VALUE=DATE:20240310\r
+DURATION:P1W\r
+SUMMARY:Retreat\r
+END:VEVENT\r
+BEGIN:VEVENT\r
+UID:lunch@example.com\r
+DTSTART:20240306T120000Z\r
+DURATION:one hour\r
+SUMMARY:Lunch\r
+END:VEVENT\r
+END:VCALENDAR\r
+",
+        utc(2024, 3, 1, 0, 0),
+        utc(2024, 4, 1, 0, 0),
+    )
+    .unwrap();
+
+    let ends: Vec<_> = events
+        .iter()
+        .map(|e| (e.title.as_str(), e.start.unwrap(), e.end))
+        .collect();
+    assert_eq!(
+        ends,
+        [
+            (
+                "Talk",
+                utc(2024, 3, 4, 14, 0).into(),
+                Some(utc(2024, 3, 4, 15, 30).into())
+            ),
+            (
+                "Talk",
+                utc(2024, 3, 5, 14, 0).into(),
+                Some(utc(2024, 3, 5, 15, 30).into())
+            ),
+            ("Lunch", utc(2024, 3, 6, 12, 0).into(), None),
+            ("Retreat", date(2024, 3, 10), Some(date(2024, 3, 17))),
+        ]
+    );
+}
+
+#[test]
+fn overrides_moved_out_of_the_window() {
+    let body = "BEGIN:VCALENDAR\r
+BEGIN:VEVENT\r
+UID:standup@example.com\r
+DTSTART:20240304T090000Z\r
+DTEND:20240304T091500Z\r
+RRULE:FREQ=DAILY;COUNT=3\r
+SUMMARY:Standup\r
+END:VEVENT\r
+BEGIN:VEVENT\r
+UID:standup@example.com\r
+RECURRENCE-ID:20240305T090000Z\r
+DTSTART:20240405T090000Z\r
+DTEND:20240405T091500Z\r
+SUMMARY:Standup (moved)\r
+END:VEVENT\r
+BEGIN:VEVENT\r
+UID:standup@example.com\r
+RECURRENCE-ID:20240306T090000Z\r
+DTSTART:20240201T090000Z\r
+DTEND:20240201T091500Z\r
+SUMMARY:Standup (early)\r
+END:VEVENT\r
+END:VCALENDAR\r
+";
+
+    let events = ics::parse_events(body, utc(2024, 3, 1, 0, 0), utc(2024, 4, 1, 0, 0)).unwrap();
+    assert_eq!(starts(&events, "Standup"), [utc(2024, 3, 4, 9, 0)]);
+    assert!(starts(&events, "Standup (moved)").is_empty());
+    assert!(starts(&events, "Standup (early)").is_empty());
+
+    let later = ics::parse_events(body, utc(2024, 3, 1, 0, 0), utc(2024, 5, 1, 0, 0)).unwrap();
+    assert_eq!(starts(&later, "Standup (moved)"), [utc(2024, 4, 5, 9, 0)]);
+}
@@ -0,0 +1,78 @@
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::TcpListener,
+    sync::mpsc,
+    thread,
+};
+
+use chrono::{Local, TimeZone};
+
+use event_list::{lists::remoteical::RemoteList, EventList};
+
+/// Serves `responses` in order, one per connection, and sends back each request's headers
+fn serve(responses: Vec<String>) -> (String, mpsc::Receiver<Vec<String>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/feed.ics", listener.local_addr().unwrap());
+    let (send, recv) = mpsc::channel();
+
+    thread::spawn(move || {
+        for response in responses {
+            let (mut stream, _) = listener.accept().unwrap();
+            let headers = BufReader::new(&stream)
+                .lines()
+                .map(Result::unwrap)
+                .take_while(|l| !l.is_empty())
+                .map(|l| l.to_lowercase())
+                .collect();
+            send.send(headers).unwrap();
+            stream.write_all(response.as_bytes()).unwrap();
+        }
+    });
+
+    (url, recv)
+}
+
+fn ok(headers: &str) -> String {
+    let body = include_str!("fixtures/weekly.ics");
+    format!(
+        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n{headers}\r\n{body}",
+        body.len()
+    )
+}
+
+const NOT_MODIFIED: &str = "HTTP/1.1 304 Not Modified\r\nConnection: close\r\n\r\n";
+
+#[tokio::test]
+async fn unchanged_feed_is_revalidated() {
+    let (url, requests) = serve(vec![
+        ok("ETag: \"v1\"\r\nLast-Modified: Mon, 01 May 2023 00:00:00 GMT\r\n"),
+        NOT_MODIFIED.to_string(),
+    ]);
+    let list = RemoteList::new(&url);
+    let after = Local.with_ymd_and_hms(2023, 1, 1, 0, 0, 0).unwrap();
+
+    let first = list.events(after).await.unwrap();
+    let second = list.events(after).await.unwrap();
+
+    assert!(!first.is_empty());
+    assert_eq!(first.len(), second.len());
+
+    requests.recv().unwrap();
+    let revalidation = requests.recv().unwrap();
+    assert!(revalidation.contains(&"if-none-match: \"v1\"".to_string()));
+    assert!(revalidation.contains(&"if-modified-since: mon, 01 may 2023 00:00:00 gmt".to_string()));
+}
+
+#[tokio::test]
+async fn fresh_feed_is_not_refetched() {
+    let (url, requests) = serve(vec![ok("Cache-Control: public, max-age=3600\r\n")]);
+    let list = RemoteList::new(&url);
+    let after = Local.with_ymd_and_hms(2023, 1, 1, 0, 0, 0).unwrap();
+
+    let first = list.events(after).await.unwrap();
+    let second = list.events(after).await.unwrap();
+
+    assert_eq!(first.len(), second.len());
+    requests.recv().unwrap();
+    assert!(requests.try_recv().is_err());
+}
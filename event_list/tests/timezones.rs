@@ -0,0 +1,120 @@
+use chrono::{DateTime, Local, NaiveDate, TimeZone, Utc};
+
+use event_list::{
+    ics::{
+        self,
+        tz::{TimeZones, Zone},
+    },
+    Event,
+};
+
+fn utc(y: i32, m: u32, d: u32, h: u32, min: u32) -> DateTime<Utc> {
+    Utc.with_ymd_and_hms(y, m, d, h, min, 0).unwrap()
+}
+
+fn fixture() -> Vec<Event> {
+    ics::parse_events(
+        include_str!("fixtures/timezones.ics"),
+        Utc.with_ymd_and_hms(2023, 1, 1, 0, 0, 0)
+            .unwrap()
+            .with_timezone(&Local),
+        Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0)
+            .unwrap()
+            .with_timezone(&Local),
+    )
+    .unwrap()
+}
+
+fn starts(events: &[Event], title: &str) -> Vec<DateTime<Utc>> {
+    events
+        .iter()
+        .filter(|e| e.title == title)
+        .map(|e| e.start.unwrap().instant().with_timezone(&Utc))
+        .collect()
+}
+
+#[test]
+fn iana_tzid() {
+    let events = fixture();
+
+    assert_eq!(starts(&events, "Phoenix"), [utc(2023, 10, 2, 16, 0)]);
+    assert_eq!(starts(&events, "Prefixed"), [utc(2023, 10, 3, 16, 0)]);
+}
+
+#[test]
+fn utc_suffix() {
+    assert_eq!(starts(&fixture(), "Utc"), [utc(2023, 10, 2, 9, 0)]);
+}
+
+#[test]
+fn floating_is_local() {
+    let events = fixture();
+    let floating = events.iter().find(|e| e.title == "Floating").unwrap();
+
+    assert_eq!(
+        floating.start.unwrap().instant().naive_local(),
+        NaiveDate::from_ymd_opt(2023, 10, 2)
+            .unwrap()
+            .and_hms_opt(9, 0, 0)
+            .unwrap()
+    );
+}
+
+#[test]
+fn floating_override() {
+    let events = ics::parse_events_in(
+        include_str!("fixtures/timezones.ics"),
+        Utc.with_ymd_and_hms(2023, 1, 1, 0, 0, 0)
+            .unwrap()
+            .with_timezone(&Local),
+        Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0)
+            .unwrap()
+            .with_timezone(&Local),
+        Some(chrono_tz::Asia::Tokyo),
+    )
+    .unwrap();
+
+    assert_eq!(starts(&events, "Floating"), [utc(2023, 10, 2, 0, 0)]);
+    assert_eq!(starts(&events, "Phoenix"), [utc(2023, 10, 2, 16, 0)]);
+}
+
+#[test]
+fn embedded_vtimezone() {
+    let events = fixture();
+
+    assert_eq!(starts(&events, "Custom summer"), [utc(2023, 7, 3, 15, 0)]);
+    assert_eq!(starts(&events, "Custom winter"), [utc(2023, 12, 4, 16, 0)]);
+}
+
+#[test]
+fn recurrence_keeps_wall_clock() {
+    assert_eq!(
+        starts(&fixture(), "Standup"),
+        [utc(2023, 11, 1, 13, 0), utc(2023, 11, 6, 14, 0)]
+    );
+}
+
+#[test]
+fn parse_values() {
+    let zones = TimeZones::default();
+
+    assert_eq!(
+        ics::parse_from_iso8601("20230615T120000", &zones.resolve("Europe/Berlin")).unwrap(),
+        utc(2023, 6, 15, 10, 0)
+    );
+    assert_eq!(
+        ics::parse_from_iso8601("20230615T120000Z", &Zone::Utc).unwrap(),
+        utc(2023, 6, 15, 12, 0)
+    );
+    assert_eq!(
+        ics::parse_from_iso8601("20230615", &Zone::Floating)
+            .unwrap()
+            .naive_local(),
+        NaiveDate::from_ymd_opt(2023, 6, 15)
+            .unwrap()
+            .and_hms_opt(0, 0, 0)
+            .unwrap()
+    );
+    assert!(ics::parse_from_iso8601("2023-06-15", &Zone::Utc).is_err());
+    assert!(matches!(zones.resolve("Not/A_Zone"), Zone::Floating));
+}
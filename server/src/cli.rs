@@ -0,0 +1,36 @@
+use std::net::SocketAddr;
+
+use clap::{Parser, Subcommand};
+
+/// Serves events from remote calendars as json and iCalendar
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    /// Config file listing the sources
+    #[arg(long, default_value = ".manager_config.toml")]
+    pub config: String,
+    /// Address and port to serve on
+    #[arg(long, default_value = "127.0.0.1:3001")]
+    pub bind: SocketAddr,
+    /// Most detailed log messages to keep: off, error, warn, info, debug or trace
+    #[arg(long, default_value = "info", value_parser = parse_level)]
+    pub log_level: log::LevelFilter,
+    /// Run without the tray icon, stopping on Ctrl-C or SIGTERM
+    #[arg(long)]
+    pub no_tray: bool,
+    /// Fetch every source once, print the events as json and exit
+    #[arg(long)]
+    pub once: bool,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+fn parse_level(s: &str) -> Result<log::LevelFilter, String> {
+    s.parse().map_err(|_| format!("unknown log level {s}"))
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Validate the config file and try each source, failing if any of them fails
+    CheckConfig,
+}
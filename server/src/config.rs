@@ -1,14 +1,588 @@
-use serde::{Deserialize, Serialize};
+use std::{collections::HashSet, fmt::Display};
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
-pub struct CalendarConfig {
-    pub auth: bool,
+use chrono_tz::Tz;
+use event_list::dedup::DedupOptions;
+use serde::Deserialize;
+use toml::{value::Table, Value};
+
+/// How often sources are refetched when they don't set their own interval
+const DEFAULT_REFRESH_MINUTES: u64 = 60;
+
+/// How often local files are checked for changes when they don't set their own interval
+const FILE_REFRESH_MINUTES: u64 = 1;
+
+/// Keys every source accepts, whatever its kind
+const COMMON_KEYS: [&str; 7] = [
+    "name",
+    "refresh_minutes",
+    "color",
+    "class",
+    "timezone",
+    "enabled",
+    "precedence",
+];
+
+/// Reasons a config file can be rejected
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The file isn't valid toml or has bad top level keys
+    Invalid(String),
+    /// A source has a missing, unknown or bad key
+    Source { name: String, error: String },
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Invalid(e) => write!(f, "Invalid config: {e}"),
+            ConfigError::Source { name, error } => write!(f, "Source {name}: {error}"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Where a source gets its events from, and the settings that only make sense for that kind
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(tag = "kind", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum SourceKind {
+    /// An iCalendar feed at `url`
+    IcalUrl { url: String },
+    /// A local `.ics` file, or a directory of them
+    IcalFile { path: String },
+    /// The event calendars of a CalDAV account, or a single CalDAV calendar
+    Caldav {
+        url: String,
+        username: Option<String>,
+        password: Option<String>,
+    },
+    /// Google calendars, signed in with the tokens kept in `token_store`.
+    /// The OAuth client is `client_id` and `client_secret`, or read from `credentials_file`,
+    /// or from the environment if neither is set.
+    Google {
+        #[serde(default)]
+        token_store: TokenStoreKind,
+        /// Where the `file` store keeps the tokens
+        token_file: Option<String>,
+        /// Encrypts `token_file`, falling back to the `GOOGLE_TOKEN_PASSPHRASE` variable
+        token_passphrase: Option<String>,
+        client_id: Option<String>,
+        client_secret: Option<String>,
+        /// Client secret JSON file downloaded from the Google Cloud console
+        credentials_file: Option<String>,
+        /// Ignore the saved tokens and wait for the user to sign in again
+        #[serde(default)]
+        auth: bool,
+        /// Ids or names of the calendars to show, those shown in Google Calendar if empty
+        #[serde(default)]
+        calendars: Vec<String>,
+    },
+}
+
+/// Where a Google source keeps its tokens
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TokenStoreKind {
+    /// `token_file`, only readable by its owner
+    #[default]
+    File,
+    /// The keyring of the OS, with the `keyring` feature
+    Keyring,
+}
+
+/// The settings every source has, whatever its kind
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CommonConfig {
+    name: String,
+    refresh_minutes: Option<u64>,
+    color: Option<String>,
+    class: Option<String>,
+    timezone: Option<String>,
+    #[serde(default = "default_enabled")]
+    enabled: bool,
+    #[serde(default)]
+    precedence: i32,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+/// The `[dedup]` table, on how events that show up in more than one source are merged
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DedupConfig {
     pub enabled: bool,
-    pub token_file: String,
+    /// Events with the same id are the same
+    pub match_id: bool,
+    /// Events with similar titles at overlapping times are the same
+    pub fuzzy: bool,
+    /// How similar titles have to be, from 0 to 1
+    pub title_similarity: f64,
+}
+
+impl Default for DedupConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            match_id: true,
+            fuzzy: true,
+            title_similarity: 0.8,
+        }
+    }
+}
+
+impl DedupConfig {
+    /// The options to dedup with, `None` if it is turned off
+    pub fn options(&self) -> Option<DedupOptions> {
+        self.enabled.then(|| DedupOptions {
+            by_id: self.match_id,
+            title_similarity: self.fuzzy.then_some(self.title_similarity),
+        })
+    }
+}
+
+/// One `[[sources]]` entry
+#[derive(Clone, Debug, PartialEq)]
+pub struct SourceConfig {
+    /// Shown in `/sources` and used by `?source=`, unique among sources
+    pub name: String,
+    pub kind: SourceKind,
+    /// Minutes between refetches, see `refresh_minutes_or`
+    pub refresh_minutes: Option<u64>,
+    /// Display color of the events, e.g. `#3366cc`
+    pub color: Option<String>,
+    /// Class given to every event of this source, instead of the one it came with
+    pub class: Option<String>,
+    /// Zone of times that don't name one, local time if not set
+    pub timezone: Option<Tz>,
+    pub enabled: bool,
+    /// Which version of an event in several sources is kept, the highest wins.
+    /// Ties go to the source listed first.
+    pub precedence: i32,
+}
+
+impl SourceConfig {
+    /// Minutes between refetches, falling back to `default` or, for local files, one minute
+    pub fn refresh_minutes_or(&self, default: u64) -> u64 {
+        match (self.refresh_minutes, &self.kind) {
+            (Some(minutes), _) => minutes,
+            (None, SourceKind::IcalFile { .. }) => FILE_REFRESH_MINUTES,
+            (None, _) => default,
+        }
+    }
+
+    /// Parses the `index`th source, naming it in errors by its name or position
+    fn from_table(index: usize, mut table: Table) -> Result<Self, ConfigError> {
+        let name = match table.get("name") {
+            Some(Value::String(name)) => name.clone(),
+            _ => format!("#{}", index + 1),
+        };
+        let error = |error: String| ConfigError::Source {
+            name: name.clone(),
+            error,
+        };
+
+        let common: Table = COMMON_KEYS
+            .iter()
+            .filter_map(|&k| Some((k.to_string(), table.remove(k)?)))
+            .collect();
+        let common: CommonConfig = Value::Table(common)
+            .try_into()
+            .map_err(|e| error(e.to_string()))?;
+        let kind: SourceKind = Value::Table(table).try_into().map_err(|e| {
+            let e = e.to_string();
+            // The common keys were taken out already, so a typo in one is unknown to the kind
+            if e.starts_with("unknown field") {
+                error(format!("{e}, or one of {}", COMMON_KEYS.join(", ")))
+            } else {
+                error(e)
+            }
+        })?;
+
+        if let SourceKind::IcalUrl { url } | SourceKind::Caldav { url, .. } = &kind {
+            if !url.starts_with("http://") && !url.starts_with("https://") {
+                return Err(error("url must start with http:// or https://".to_string()));
+            }
+        }
+        if let SourceKind::Google {
+            token_store,
+            token_file,
+            token_passphrase,
+            client_id,
+            client_secret,
+            credentials_file,
+            ..
+        } = &kind
+        {
+            match token_store {
+                TokenStoreKind::File if token_file.is_none() => {
+                    return Err(error(
+                        "token_file is needed unless token_store is keyring".to_string(),
+                    ));
+                }
+                TokenStoreKind::Keyring if token_file.is_some() || token_passphrase.is_some() => {
+                    return Err(error(
+                        "token_file and token_passphrase can't be used with the keyring"
+                            .to_string(),
+                    ));
+                }
+                _ => {}
+            }
+            if client_id.is_some() != client_secret.is_some() {
+                return Err(error(
+                    "client_id and client_secret must be set together".to_string(),
+                ));
+            }
+            if client_id.is_some() && credentials_file.is_some() {
+                return Err(error(
+                    "credentials_file can't be used with client_id".to_string(),
+                ));
+            }
+        }
+        if common.name.trim().is_empty() {
+            return Err(error("name can't be empty".to_string()));
+        }
+        if common.refresh_minutes == Some(0) {
+            return Err(error("refresh_minutes must be at least 1".to_string()));
+        }
+        let timezone = common
+            .timezone
+            .map(|tz| tz.parse::<Tz>())
+            .transpose()
+            .map_err(|_| {
+                error("timezone must be an IANA name, e.g. America/Phoenix".to_string())
+            })?;
+
+        Ok(Self {
+            name: common.name,
+            kind,
+            refresh_minutes: common.refresh_minutes,
+            color: common.color,
+            class: common.class,
+            timezone,
+            enabled: common.enabled,
+            precedence: common.precedence,
+        })
+    }
+}
+
+/// `google_calendar` table of the old format
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LegacyCalendarConfig {
+    auth: bool,
+    enabled: bool,
+    token_file: String,
+    refresh_minutes: Option<u64>,
+}
+
+/// `remotes` entry of the old format, either just its url or a table with its settings
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum LegacyRemoteConfig {
+    Url(String),
+    Detailed {
+        url: String,
+        refresh_minutes: Option<u64>,
+    },
+}
+
+/// The config file as written, in either format
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawConfig {
+    refresh_minutes: Option<u64>,
+    dedup: Option<DedupConfig>,
+    sources: Option<Vec<Table>>,
+    remotes: Option<Vec<LegacyRemoteConfig>>,
+    google_calendar: Option<LegacyCalendarConfig>,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct ManagerConfig {
-    pub remotes: Vec<String>,
-    pub google_calendar: CalendarConfig,
+    /// Minutes between refetches of sources that don't set their own
+    pub refresh_minutes: u64,
+    pub dedup: DedupConfig,
+    pub sources: Vec<SourceConfig>,
+    /// Whether the file used the old `remotes`/`google_calendar` format
+    pub migrated: bool,
+}
+
+impl ManagerConfig {
+    /// Parses and validates a config file, converting the old format to `[[sources]]`
+    pub fn from_toml(s: &str) -> Result<Self, ConfigError> {
+        let raw: RawConfig = toml::from_str(s).map_err(|e| ConfigError::Invalid(e.to_string()))?;
+        let legacy = raw.remotes.is_some() || raw.google_calendar.is_some();
+
+        let refresh_minutes = raw.refresh_minutes.unwrap_or(DEFAULT_REFRESH_MINUTES);
+        if refresh_minutes == 0 {
+            return Err(ConfigError::Invalid(
+                "refresh_minutes must be at least 1".to_string(),
+            ));
+        }
+
+        let dedup = raw.dedup.unwrap_or_default();
+        if !(0.0..=1.0).contains(&dedup.title_similarity) {
+            return Err(ConfigError::Invalid(
+                "dedup.title_similarity must be between 0 and 1".to_string(),
+            ));
+        }
+
+        let sources = match raw.sources {
+            Some(_) if legacy => {
+                return Err(ConfigError::Invalid(
+                    "sources can't be mixed with remotes or google_calendar".to_string(),
+                ))
+            }
+            Some(sources) => sources
+                .into_iter()
+                .enumerate()
+                .map(|(i, t)| SourceConfig::from_table(i, t))
+                .collect::<Result<Vec<_>, _>>()?,
+            None => migrate(raw.remotes.unwrap_or_default(), raw.google_calendar),
+        };
+
+        let mut names = HashSet::new();
+        for s in &sources {
+            if !names.insert(s.name.to_lowercase()) {
+                return Err(ConfigError::Source {
+                    name: s.name.clone(),
+                    error: "another source has the same name".to_string(),
+                });
+            }
+        }
+
+        Ok(Self {
+            refresh_minutes,
+            dedup,
+            sources,
+            migrated: legacy,
+        })
+    }
+}
+
+/// Converts the sources of the old format
+fn migrate(
+    remotes: Vec<LegacyRemoteConfig>,
+    google: Option<LegacyCalendarConfig>,
+) -> Vec<SourceConfig> {
+    let source = |name: String, kind, refresh_minutes, enabled| SourceConfig {
+        name,
+        kind,
+        refresh_minutes,
+        color: None,
+        class: None,
+        timezone: None,
+        enabled,
+        precedence: 0,
+    };
+
+    let mut out: Vec<_> = remotes
+        .into_iter()
+        .map(|r| {
+            let (url, refresh_minutes) = match r {
+                LegacyRemoteConfig::Url(url) => (url, None),
+                LegacyRemoteConfig::Detailed {
+                    url,
+                    refresh_minutes,
+                } => (url, refresh_minutes),
+            };
+            // Same name the feed had before, without the access token in the query
+            let name = url.split('?').next().unwrap_or(&url).to_string();
+
+            source(name, SourceKind::IcalUrl { url }, refresh_minutes, true)
+        })
+        .collect();
+
+    if let Some(google) = google {
+        out.push(source(
+            "Google Calendar".to_string(),
+            SourceKind::Google {
+                token_store: TokenStoreKind::File,
+                token_file: Some(google.token_file),
+                token_passphrase: None,
+                client_id: None,
+                client_secret: None,
+                credentials_file: None,
+                auth: google.auth,
+                calendars: vec!["primary".to_string()],
+            },
+            google.refresh_minutes,
+            google.auth || google.enabled,
+        ));
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source_error(config: &str) -> String {
+        match ManagerConfig::from_toml(config) {
+            Err(ConfigError::Source { error, .. }) => error,
+            other => panic!("expected a source error, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn parses_sources() {
+        let config = ManagerConfig::from_toml(
+            r##"
+            refresh_minutes = 30
+
+            [[sources]]
+            name = "School"
+            kind = "ical-url"
+            url = "https://example.com/feed.ics"
+            color = "#3366cc"
+            timezone = "America/Phoenix"
+
+            [[sources]]
+            name = "Notes"
+            kind = "ical-file"
+            path = "notes.ics"
+            enabled = false
+            "##,
+        )
+        .unwrap();
+
+        assert!(!config.migrated);
+        assert_eq!(config.refresh_minutes, 30);
+        assert_eq!(config.sources.len(), 2);
+        assert_eq!(config.sources[0].color.as_deref(), Some("#3366cc"));
+        assert_eq!(
+            config.sources[0].timezone,
+            Some(chrono_tz::America::Phoenix)
+        );
+        assert_eq!(config.sources[0].refresh_minutes_or(30), 30);
+        assert!(!config.sources[1].enabled);
+        assert_eq!(
+            config.sources[1].refresh_minutes_or(30),
+            FILE_REFRESH_MINUTES
+        );
+    }
+
+    #[test]
+    fn migrates_the_old_format() {
+        let config = ManagerConfig::from_toml(
+            r#"
+            remotes = [
+                "https://example.com/a.ics?token=abc",
+                { url = "https://example.com/b.ics", refresh_minutes = 5 },
+            ]
+
+            [google_calendar]
+            auth = false
+            enabled = true
+            token_file = "cal.token"
+            "#,
+        )
+        .unwrap();
+
+        assert!(config.migrated);
+        let names: Vec<_> = config.sources.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "https://example.com/a.ics",
+                "https://example.com/b.ics",
+                "Google Calendar"
+            ]
+        );
+        assert_eq!(
+            config.sources[0].kind,
+            SourceKind::IcalUrl {
+                url: "https://example.com/a.ics?token=abc".to_string()
+            }
+        );
+        assert_eq!(config.sources[1].refresh_minutes, Some(5));
+        assert!(matches!(
+            &config.sources[2].kind,
+            SourceKind::Google { token_file: Some(file), calendars, .. }
+                if file == "cal.token" && calendars == &["primary"]
+        ));
+    }
+
+    #[test]
+    fn rejects_mixed_formats() {
+        let result = ManagerConfig::from_toml(
+            r#"
+            remotes = ["https://example.com/a.ics"]
+
+            [[sources]]
+            name = "School"
+            kind = "ical-url"
+            url = "https://example.com/feed.ics"
+            "#,
+        );
+
+        assert!(matches!(result, Err(ConfigError::Invalid(_))));
+    }
+
+    #[test]
+    fn rejects_duplicate_names() {
+        let error = source_error(
+            r#"
+            [[sources]]
+            name = "School"
+            kind = "ical-file"
+            path = "a.ics"
+
+            [[sources]]
+            name = "school"
+            kind = "ical-file"
+            path = "b.ics"
+            "#,
+        );
+
+        assert_eq!(error, "another source has the same name");
+    }
+
+    #[test]
+    fn unknown_keys_name_the_common_ones() {
+        let error = source_error(
+            r##"
+            [[sources]]
+            name = "School"
+            kind = "ical-url"
+            url = "https://example.com/feed.ics"
+            colour = "#fff"
+            "##,
+        );
+
+        assert!(error.contains("unknown field `colour`"), "{error}");
+        assert!(error.contains("color"), "{error}");
+    }
+
+    #[test]
+    fn checks_google_keys() {
+        let google =
+            |keys: &str| format!("[[sources]]\nname = \"Google\"\nkind = \"google\"\n{keys}");
+
+        assert!(ManagerConfig::from_toml(&google("token_file = \"cal.token\"")).is_ok());
+        assert!(ManagerConfig::from_toml(&google("token_store = \"keyring\"")).is_ok());
+
+        for (keys, expected) in [
+            ("", "token_file is needed unless token_store is keyring"),
+            (
+                "token_file = \"t\"\nclient_id = \"id\"",
+                "client_id and client_secret must be set together",
+            ),
+            (
+                "token_file = \"t\"\nclient_id = \"id\"\nclient_secret = \"s\"\n\
+                 credentials_file = \"c.json\"",
+                "credentials_file can't be used with client_id",
+            ),
+            (
+                "token_store = \"keyring\"\ntoken_file = \"t\"",
+                "token_file and token_passphrase can't be used with the keyring",
+            ),
+        ] {
+            assert_eq!(source_error(&google(keys)), expected, "{keys}");
+        }
+    }
 }
@@ -1,86 +1,1116 @@
-use chrono::{DateTime, Local, Utc};
+use chrono::{DateTime, Duration, Local, Utc};
 
 use crate::{
-    config::ManagerConfig,
+    config::{ManagerConfig, SourceConfig, SourceKind},
+    query::EventQuery,
 };
 
-use event_list::{lists::{google::GoogleList, remoteical::RemoteList}, Days, Event, EventList};
+use event_list::{
+    dedup::{self, DedupOptions},
+    ics,
+    lists::{caldav::CalDavList, localical::FileList, remoteical::RemoteList},
+    Days, Event, EventList, EventListError, SignIn,
+};
+
+#[cfg(feature = "google")]
+use crate::config::TokenStoreKind;
+#[cfg(feature = "keyring")]
+use event_list::lists::token_store::KeyringStore;
+#[cfg(feature = "google")]
+use event_list::lists::{
+    google::{GoogleCredentials, GoogleList},
+    token_store::{FileStore, TokenStore},
+};
+
+use serde::Serialize;
+
+use std::{collections::HashMap, error::Error, fmt::Display, sync::Arc, time::Instant};
+
+use tokio::sync::{mpsc, oneshot, watch};
+
+/// A change to the events of a source
+#[derive(Clone, Debug)]
+pub enum Write {
+    Create(Event),
+    /// Replace the event with the id
+    Update(String, Event),
+    Delete(String),
+}
+
+/// Reasons a `Write` can fail
+#[derive(Debug)]
+pub enum WriteError {
+    /// No source has the given name
+    UnknownSource(String),
+    /// The request didn't name a source and more than one can be written to
+    NoSource,
+    /// The source can't be written to
+    ReadOnly(String),
+    /// The source rejected the write or couldn't be reached
+    Source(EventListError),
+    /// The manager has stopped
+    Stopped,
+}
+
+impl Display for WriteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WriteError::UnknownSource(name) => write!(f, "No source named {name}"),
+            WriteError::NoSource => write!(f, "Name the source to write to with ?source="),
+            WriteError::ReadOnly(name) => write!(f, "Source {name} is read-only"),
+            WriteError::Source(e) => write!(f, "{e}"),
+            WriteError::Stopped => write!(f, "The event manager isn't running"),
+        }
+    }
+}
+
+/// Requests a running manager answers between refreshes
+pub enum Command {
+    /// Reread the config file, answered with what changed or why the new config was rejected
+    Reload(oneshot::Sender<Result<String, String>>),
+    /// Make a change in the source named `source`, answered with the id of the event.
+    /// Without a name, ids go to the source that has the event and new events to the only
+    /// source that can be written to.
+    Write {
+        source: Option<String>,
+        write: Box<Write>,
+        reply: oneshot::Sender<Result<String, WriteError>>,
+    },
+    /// List the sources the user signs in to, by name
+    SignIns(oneshot::Sender<Vec<(String, Arc<dyn SignIn>)>>),
+}
+
+/// Sends `Command`s to a running `EventManager`
+#[derive(Clone)]
+pub struct ManagerHandle(mpsc::Sender<Command>);
+
+impl ManagerHandle {
+    /// Creates a `ManagerHandle` and the receiver to hand to `EventManager::run`
+    pub fn new() -> (Self, mpsc::Receiver<Command>) {
+        let (send, recv) = mpsc::channel(4);
+        (Self(send), recv)
+    }
+
+    /// Reloads the config, returning what changed or why the new config was rejected
+    pub async fn reload(&self) -> Result<String, String> {
+        let (send, recv) = oneshot::channel();
+        self.0
+            .send(Command::Reload(send))
+            .await
+            .map_err(|_| "The event manager isn't running".to_string())?;
+
+        recv.await
+            .map_err(|_| "The event manager stopped".to_string())?
+    }
+
+    /// Makes `write` in a source, returning the id of the event
+    pub async fn write(&self, source: Option<String>, write: Write) -> Result<String, WriteError> {
+        let (reply, recv) = oneshot::channel();
+        self.0
+            .send(Command::Write {
+                source,
+                write: Box::new(write),
+                reply,
+            })
+            .await
+            .map_err(|_| WriteError::Stopped)?;
+
+        recv.await.map_err(|_| WriteError::Stopped)?
+    }
+
+    /// The sources the user signs in to, by name, empty if the manager stopped
+    pub async fn sign_ins(&self) -> Vec<(String, Arc<dyn SignIn>)> {
+        let (reply, recv) = oneshot::channel();
+        if self.0.send(Command::SignIns(reply)).await.is_err() {
+            return Vec::new();
+        }
+
+        recv.await.unwrap_or_default()
+    }
+}
+
+/// How long to wait before retrying a source that failed, unless it refreshes sooner anyway
+const RETRY_MINUTES: i64 = 5;
+
+/// How often a source waiting for the user to sign in checks whether they have
+const SIGN_IN_CHECK_SECONDS: i64 = 15;
+
+/// How a source is doing, as of its last query
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SourceState {
+    Ok,
+    Failed,
+    /// Waiting for the user to sign in
+    Unauthenticated,
+}
+
+/// Health of a single source, as of its last query
+#[derive(Clone, Debug, Serialize)]
+pub struct SourceStatus {
+    pub name: String,
+    /// Display color of the source's events
+    pub color: Option<String>,
+    /// Whether the last query succeeded
+    pub ok: bool,
+    pub state: SourceState,
+    pub last_success: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+    pub last_error_time: Option<DateTime<Utc>>,
+    /// Number of events returned by the last successful query
+    pub event_count: usize,
+    /// How long the last query took, in milliseconds
+    pub latency_ms: Option<u128>,
+    /// When the source will be queried again
+    pub next_refresh: DateTime<Utc>,
+}
+
+/// Everything needed to answer requests, as of the last refresh
+#[derive(Clone, Debug, Default)]
+pub struct Snapshot {
+    /// Whether every source has been queried at least once
+    pub loaded: bool,
+    /// Last events of each source that has any, by source name
+    events: Vec<(String, Arc<Vec<Event>>)>,
+    sources: Vec<SourceStatus>,
+    /// First error of the last refresh, only kept when no source has events
+    error: Option<EventListError>,
+}
+
+impl Snapshot {
+    /// The status of every source
+    pub fn sources(&self) -> &[SourceStatus] {
+        &self.sources
+    }
+
+    /// The events that pass every filter of `query`
+    fn query_events(&self, query: &EventQuery) -> Result<Vec<Event>, EventListError> {
+        if let Some(e) = &self.error {
+            return Err(e.clone());
+        }
+
+        Ok(self
+            .events
+            .iter()
+            .flat_map(|(source, events)| {
+                events
+                    .iter()
+                    .filter(move |e| query.matches(source, e))
+                    .cloned()
+            })
+            .collect())
+    }
+
+    /// Filters the events with `query` and turns them into a json list of `Days`
+    pub fn query_json(&self, query: &EventQuery) -> Result<String, EventListError> {
+        let events = self.query_events(query)?;
+
+        Ok(Days::from_slice(&events).to_json()?)
+    }
 
-use std::error::Error;
+    /// Filters the events with `query` and turns them into an iCalendar file
+    pub fn query_ics(&self, query: &EventQuery) -> Result<String, EventListError> {
+        let events = self.query_events(query)?;
+
+        Ok(ics::write::to_ics(&events))
+    }
+}
+
+/// How the manager treats a source
+#[derive(Clone, Debug)]
+pub struct SourceSettings {
+    /// Shown in the status and used to filter by source
+    pub name: String,
+    /// How long the events of the source are kept before refetching them
+    pub refresh: Duration,
+    pub color: Option<String>,
+    /// Class given to every event of the source
+    pub class: Option<String>,
+    /// Which version of a duplicated event is kept, the highest wins
+    pub precedence: i32,
+}
+
+/// Stands in for a source whose list couldn't be created, so it is reported as failing
+struct Unavailable(EventListError);
+
+#[async_trait::async_trait]
+impl EventList for Unavailable {
+    async fn init(&mut self) {}
+
+    fn name(&self) -> &str {
+        "Unavailable source"
+    }
+
+    async fn events(&self, _after: DateTime<Local>) -> Result<Vec<Event>, EventListError> {
+        Err(self.0.clone())
+    }
+}
+
+/// Where the Google source `name` keeps its tokens
+#[cfg(feature = "google")]
+fn google_token_store(
+    #[cfg_attr(not(feature = "keyring"), allow(unused_variables))] name: &str,
+    kind: TokenStoreKind,
+    token_file: Option<&str>,
+    passphrase: Option<&str>,
+) -> Result<Box<dyn TokenStore>, EventListError> {
+    match kind {
+        TokenStoreKind::File => {
+            let mut store = FileStore::new(token_file.unwrap_or_default());
+            let env = std::env::var("GOOGLE_TOKEN_PASSPHRASE").ok();
+            if let Some(passphrase) = passphrase.or(env.as_deref()).filter(|p| !p.is_empty()) {
+                store = store.with_passphrase(passphrase);
+            }
+            Ok(Box::new(store))
+        }
+        #[cfg(feature = "keyring")]
+        TokenStoreKind::Keyring => Ok(Box::new(KeyringStore::new("event_server", name)?)),
+        #[cfg(not(feature = "keyring"))]
+        TokenStoreKind::Keyring => Err(EventListError::Parse(
+            "this build can't use the keyring, enable the keyring feature".to_string(),
+        )),
+    }
+}
+
+/// A source and everything the manager keeps about it
+struct Source {
+    /// Tells the source apart from the one a reload replaced it with
+    id: u64,
+    list: Arc<dyn EventList + Sync + Send>,
+    settings: SourceSettings,
+    status: SourceStatus,
+    /// Last events, `None` until the source first succeeds
+    cached: Option<Arc<Vec<Event>>>,
+    /// Error of the last query, `None` if it succeeded
+    error: Option<EventListError>,
+    /// Whether a query of the source is running in the background
+    refreshing: bool,
+    /// The config the source was built from, to tell whether a reload changed it
+    config: Option<SourceConfig>,
+}
+
+/// The outcome of a query of a source, sent back by the task running it
+struct Refreshed {
+    /// `Source::id` of the source, which a reload may have removed meanwhile
+    id: u64,
+    /// When the query started, which the next one is scheduled from
+    started: DateTime<Utc>,
+    result: Result<Vec<Event>, EventListError>,
+    latency: std::time::Duration,
+}
+
+/// Queries `list` for the events after `after`, tagging them with the source and its class.
+/// Returns the result and how long it took.
+async fn query(
+    list: &(dyn EventList + Sync + Send),
+    settings: &SourceSettings,
+    after: DateTime<Local>,
+) -> (Result<Vec<Event>, EventListError>, std::time::Duration) {
+    let start = Instant::now();
+    let result = list.events(after).await.map(|mut events| {
+        for e in &mut events {
+            e.source = Some(settings.name.clone());
+            if let Some(class) = &settings.class {
+                e.class = Some(class.clone());
+            }
+        }
+        events
+    });
+    (result, start.elapsed())
+}
 
 pub struct EventManager {
-    streams: Vec<Box<dyn EventList + Sync + Send>>,
-    cached: Option<String>,
-    cached_time: DateTime<Utc>,
+    sources: Vec<Source>,
+    /// Config file to reread on reload, if the manager was created from one
+    file: Option<String>,
+    /// How duplicates across sources are found, `None` keeps them all
+    dedup: Option<DedupOptions>,
+    /// Lowercased name of the source each event created through the manager went to, by id
+    owners: HashMap<String, String>,
+    /// `Source::id` of the next source added
+    next_id: u64,
 }
 
 impl EventManager {
     /// Creates an `EventManager` with no sources
-    pub const fn new() -> Self {
+    pub fn new() -> Self {
         Self {
-            streams: Vec::new(),
-            cached: None,
-            cached_time: DateTime::<Utc>::MIN_UTC,
+            sources: Vec::new(),
+            file: None,
+            dedup: Some(DedupOptions::default()),
+            owners: HashMap::new(),
+            next_id: 0,
         }
     }
 
     /// Adds a new source to this manager
-    pub fn add<T: EventList + Sync + Send + 'static>(&mut self, list: T) {
-        self.streams.push(Box::new(list));
+    pub fn add<T: EventList + Sync + Send + 'static>(&mut self, list: T, settings: SourceSettings) {
+        self.next_id += 1;
+        self.sources.push(Source {
+            id: self.next_id,
+            list: Arc::new(list),
+            status: SourceStatus {
+                name: settings.name.clone(),
+                color: settings.color.clone(),
+                ok: true,
+                state: SourceState::Ok,
+                last_success: None,
+                last_error: None,
+                last_error_time: None,
+                event_count: 0,
+                latency_ms: None,
+                next_refresh: Utc::now(),
+            },
+            settings,
+            cached: None,
+            error: None,
+            refreshing: false,
+            config: None,
+        });
+    }
+
+    /// Adds a source that couldn't be created, failing with `error` every time it is queried
+    fn add_unavailable(&mut self, error: EventListError, settings: SourceSettings) {
+        log::error!("Source {}: {error}", settings.name);
+        self.add(Unavailable(error), settings);
+    }
+
+    /// Reads and parses the config `file`, logging if it is in the old format
+    fn read_config(file: &str) -> Result<ManagerConfig, Box<dyn Error>> {
+        let config = ManagerConfig::from_toml(&std::fs::read_to_string(file)?)?;
+
+        if config.migrated {
+            log::warn!("{file} uses the old remotes/google_calendar format, consider [[sources]]");
+        }
+
+        Ok(config)
     }
 
     /// Creates a new EventManager from the config `file`
     /// Returns an error if the file read fails or the config file couldn't be parsed
     pub async fn from_config(file: &str) -> Result<Self, Box<dyn Error>> {
+        let config = Self::read_config(file)?;
+
+        let mut out = Self::from_manager_config(&config).await;
+        out.file = Some(file.to_string());
+
+        Ok(out)
+    }
+
+    /// Creates a new EventManager with the enabled sources of `config`
+    pub async fn from_manager_config(config: &ManagerConfig) -> Self {
         let mut out = EventManager::new();
+        out.dedup = config.dedup.options();
+        for source in config.sources.iter().filter(|s| s.enabled) {
+            out.add_source(source, config.refresh_minutes).await;
+        }
 
-        let config = toml::from_slice::<ManagerConfig>(&std::fs::read(file)?)?;
+        out
+    }
+
+    /// Creates the list described by `config` and adds it
+    async fn add_source(&mut self, config: &SourceConfig, default_refresh_minutes: u64) {
+        let settings = SourceSettings {
+            name: config.name.clone(),
+            refresh: Duration::minutes(config.refresh_minutes_or(default_refresh_minutes) as i64),
+            color: config.color.clone(),
+            class: config.class.clone(),
+            precedence: config.precedence,
+        };
 
-        for r in config.remotes {
-            out.add(RemoteList::new(&r));
+        match &config.kind {
+            SourceKind::IcalUrl { url } => {
+                let mut list = RemoteList::new(url);
+                if let Some(tz) = config.timezone {
+                    list = list.with_timezone(tz);
+                }
+                self.add(list, settings);
+            }
+            SourceKind::IcalFile { path } => {
+                let mut list = FileList::new(path);
+                if let Some(tz) = config.timezone {
+                    list = list.with_timezone(tz);
+                }
+                self.add(list, settings);
+            }
+            SourceKind::Caldav {
+                url,
+                username,
+                password,
+            } => match CalDavList::new(url) {
+                Ok(mut list) => {
+                    if let Some(username) = username {
+                        list = list.with_basic_auth(username, password.as_deref());
+                    }
+                    if let Some(tz) = config.timezone {
+                        list = list.with_timezone(tz);
+                    }
+                    self.add(list, settings);
+                }
+                Err(e) => self.add_unavailable(e, settings),
+            },
+            #[cfg(feature = "google")]
+            SourceKind::Google {
+                token_store,
+                token_file,
+                token_passphrase,
+                client_id,
+                client_secret,
+                credentials_file,
+                auth,
+                calendars,
+            } => {
+                if config.timezone.is_some() {
+                    log::warn!("Source {}: timezone has no effect on Google", config.name);
+                }
+                let credentials = match (client_id, client_secret, credentials_file) {
+                    (Some(client_id), Some(client_secret), _) => Ok(GoogleCredentials {
+                        client_id: client_id.clone(),
+                        client_secret: client_secret.clone(),
+                    }),
+                    (_, _, Some(file)) => GoogleCredentials::from_json_file(file),
+                    _ => GoogleCredentials::from_env().ok_or_else(|| {
+                        EventListError::Auth(
+                            "No Google OAuth client, set client_id and client_secret or \
+                             credentials_file, or GOOGLE_CLIENT_ID and GOOGLE_CLIENT_SECRET"
+                                .to_string(),
+                        )
+                    }),
+                };
+                let store = google_token_store(
+                    &config.name,
+                    *token_store,
+                    token_file.as_deref(),
+                    token_passphrase.as_deref(),
+                );
+                match credentials.and_then(|c| Ok((c, store?))) {
+                    Ok((credentials, store)) => {
+                        let list = if *auth {
+                            GoogleList::new(store, &credentials)
+                        } else {
+                            GoogleList::from_store(store, &credentials).await
+                        };
+                        self.add(list.with_calendars(calendars.clone()), settings);
+                    }
+                    Err(e) => self.add_unavailable(e, settings),
+                }
+            }
+            #[cfg(not(feature = "google"))]
+            SourceKind::Google { .. } => self.add_unavailable(
+                EventListError::Parse(
+                    "this build has no Google support, enable the google feature".to_string(),
+                ),
+                settings,
+            ),
         }
 
-        if config.google_calendar.auth {
-            out.add(GoogleList::new(&config.google_calendar.token_file).await);
-        } else if config.google_calendar.enabled {
-            out.add(GoogleList::from_file(&config.google_calendar.token_file).await);
+        if let Some(source) = self.sources.last_mut() {
+            source.config = Some(config.clone());
         }
+    }
 
-        Ok(out)
+    /// Rereads the config file and rebuilds the sources that changed.
+    /// Unchanged sources keep their events and schedule.
+    /// If the file can't be read or parsed, the current sources are kept.
+    /// Returns a summary of what changed.
+    async fn reload(&mut self) -> Result<String, String> {
+        let Some(file) = self.file.clone() else {
+            return Err("The manager wasn't created from a config file".to_string());
+        };
+
+        let config = Self::read_config(&file).map_err(|e| {
+            log::error!("Keeping the current config, {file} is invalid: {e}");
+            e.to_string()
+        })?;
+
+        self.dedup = config.dedup.options();
+
+        let mut old: HashMap<_, _> = std::mem::take(&mut self.sources)
+            .into_iter()
+            .map(|s| (s.settings.name.to_lowercase(), s))
+            .collect();
+        let (mut kept, mut added, mut changed) = (0, Vec::new(), Vec::new());
+
+        for source in config.sources.iter().filter(|s| s.enabled) {
+            let refresh =
+                Duration::minutes(source.refresh_minutes_or(config.refresh_minutes) as i64);
+
+            match old.remove(&source.name.to_lowercase()) {
+                Some(existing)
+                    if existing.config.as_ref() == Some(source)
+                        && existing.settings.refresh == refresh =>
+                {
+                    kept += 1;
+                    self.sources.push(existing);
+                }
+                Some(_) => {
+                    changed.push(source.name.clone());
+                    self.add_source(source, config.refresh_minutes).await;
+                }
+                None => {
+                    added.push(source.name.clone());
+                    self.add_source(source, config.refresh_minutes).await;
+                }
+            }
+        }
+
+        let removed: Vec<_> = old.into_values().map(|s| s.settings.name).collect();
+        let summary = format!(
+            "Reloaded {file}: {kept} unchanged, added [{}], changed [{}], removed [{}]",
+            added.join(", "),
+            changed.join(", "),
+            removed.join(", ")
+        );
+        log::info!("{summary}");
+
+        Ok(summary)
+    }
+
+    /// Makes `write` in the named source, or the one found as described on `Command::Write`.
+    /// The source is refreshed right after, so the change shows up in the next snapshot.
+    async fn write(&mut self, source: Option<String>, write: Write) -> Result<String, WriteError> {
+        let id = match &write {
+            Write::Create(_) => None,
+            Write::Update(id, _) | Write::Delete(id) => Some(id.clone()),
+        };
+
+        let owner = id
+            .as_ref()
+            .and_then(|id| self.owners.get(id).cloned().or_else(|| self.owner(id)));
+
+        let index = match source.or(owner) {
+            Some(name) => self
+                .sources
+                .iter()
+                .position(|s| s.settings.name.eq_ignore_ascii_case(&name))
+                .ok_or(WriteError::UnknownSource(name))?,
+            None if id.is_none() => {
+                let mut writable =
+                    (0..self.sources.len()).filter(|&i| self.sources[i].list.sink().is_some());
+                match (writable.next(), writable.next()) {
+                    (Some(i), None) => i,
+                    _ => return Err(WriteError::NoSource),
+                }
+            }
+            None => {
+                return Err(WriteError::Source(EventListError::NotFound(
+                    id.unwrap_or_default(),
+                )))
+            }
+        };
+
+        let source = &mut self.sources[index];
+        let sink = source
+            .list
+            .sink()
+            .ok_or_else(|| WriteError::ReadOnly(source.settings.name.clone()))?;
+
+        let deleted = matches!(write, Write::Delete(_));
+        let result = match write {
+            Write::Create(event) => sink.create(&event).await,
+            Write::Update(id, event) => sink.update(&id, &event).await.map(|_| id),
+            Write::Delete(id) => sink.delete(&id).await.map(|_| id),
+        };
+        let id = result.map_err(|e| {
+            log::error!("Writing to {} failed: {e}", source.settings.name);
+            WriteError::Source(e)
+        })?;
+
+        log::info!("Wrote event {id} to {}", source.settings.name);
+        source.status.next_refresh = Utc::now();
+        if deleted {
+            self.owners.remove(&id);
+        } else {
+            self.owners
+                .insert(id.clone(), source.settings.name.to_lowercase());
+        }
+
+        Ok(id)
+    }
+
+    /// Name of the source whose last events include the one with `id`, or a series with it
+    fn owner(&self, id: &str) -> Option<String> {
+        self.sources
+            .iter()
+            .find(|s| {
+                s.cached.iter().any(|events| {
+                    events
+                        .iter()
+                        .any(|e| e.id.as_deref() == Some(id) || e.series_id.as_deref() == Some(id))
+                })
+            })
+            .map(|s| s.settings.name.clone())
+    }
+
+    /// Queries the sources at `indices` at the same time, tagging the events with their source
+    /// and its class. Returns the result of each source with its index and how long it took.
+    async fn query_sources(
+        &self,
+        after: DateTime<Local>,
+        indices: &[usize],
+    ) -> Vec<(
+        usize,
+        Result<Vec<Event>, EventListError>,
+        std::time::Duration,
+    )> {
+        futures::future::join_all(indices.iter().map(|&i| async move {
+            let source = &self.sources[i];
+            let (result, latency) = query(&*source.list, &source.settings, after).await;
+            (i, result, latency)
+        }))
+        .await
+    }
+
+    /// The sources the user signs in to, by name
+    fn sign_ins(&self) -> Vec<(String, Arc<dyn SignIn>)> {
+        self.sources
+            .iter()
+            .filter_map(|s| Some((s.settings.name.clone(), s.list.sign_in()?)))
+            .collect()
+    }
+
+    /// Queries every source once, returning each source's name and result
+    pub async fn check_sources(&self) -> Vec<(&str, Result<Vec<Event>, EventListError>)> {
+        let all: Vec<_> = (0..self.sources.len()).collect();
+
+        self.query_sources(Local::now(), &all)
+            .await
+            .into_iter()
+            .map(|(i, result, _)| (self.sources[i].settings.name.as_str(), result))
+            .collect()
     }
 
-    /// Queries all the sources and turns it into json string that is a list of `Days`
-    async fn events_json(&self) -> Result<String, serde_json::Error> {
-        Days::from_slice(&self.events(Utc::now().with_timezone(&Local)).await).to_json()
+    /// Starts querying the sources that are due, each in a task of its own that sends the
+    /// result to `done`, so slow sources don't hold back the others or any command
+    fn start_due(&mut self, done: &mpsc::UnboundedSender<Refreshed>) {
+        let now = Utc::now();
+
+        for source in &mut self.sources {
+            if source.refreshing || source.status.next_refresh > now {
+                continue;
+            }
+            source.refreshing = true;
+            // Failures and writes meanwhile move this forward once the query is done
+            source.status.next_refresh = now + source.settings.refresh;
+
+            let (id, list, settings, done) = (
+                source.id,
+                source.list.clone(),
+                source.settings.clone(),
+                done.clone(),
+            );
+            tokio::spawn(async move {
+                let (result, latency) = query(&*list, &settings, now.with_timezone(&Local)).await;
+                let _ = done.send(Refreshed {
+                    id,
+                    started: now,
+                    result,
+                    latency,
+                });
+            });
+        }
+    }
+
+    /// Records the outcome of a query of a source, unless a reload removed the source since.
+    /// Sources that fail keep their last events and are retried sooner.
+    fn record(&mut self, refreshed: Refreshed) {
+        let Some(source) = self.sources.iter_mut().find(|s| s.id == refreshed.id) else {
+            return;
+        };
+        let now = refreshed.started;
+        let status = &mut source.status;
+        source.refreshing = false;
+        status.latency_ms = Some(refreshed.latency.as_millis());
+
+        let next = match refreshed.result {
+            Ok(events) => {
+                status.ok = true;
+                status.state = SourceState::Ok;
+                status.last_success = Some(Utc::now());
+                status.event_count = events.len();
+                source.cached = Some(Arc::new(events));
+                source.error = None;
+                now + source.settings.refresh
+            }
+            Err(e @ EventListError::Unauthenticated(_)) => {
+                if status.state != SourceState::Unauthenticated {
+                    log::warn!("Source {}: {e}", status.name);
+                }
+                status.ok = false;
+                status.state = SourceState::Unauthenticated;
+                status.last_error = Some(e.to_string());
+                status.last_error_time = Some(Utc::now());
+                source.error = Some(e);
+                now + Duration::seconds(SIGN_IN_CHECK_SECONDS)
+            }
+            Err(e) => {
+                log::error!("Source {} failed: {e}", status.name);
+                status.ok = false;
+                status.state = SourceState::Failed;
+                status.last_error = Some(e.to_string());
+                status.last_error_time = Some(Utc::now());
+                source.error = Some(e);
+                now + source
+                    .settings
+                    .refresh
+                    .min(Duration::minutes(RETRY_MINUTES))
+            }
+        };
+        // A write while the query ran asks for another one right away
+        status.next_refresh = status.next_refresh.min(next);
     }
 
-    /// Does the same as `events_json` but caches the result for an hour
-    pub async fn cached_json(&mut self) -> Result<&str, serde_json::Error> {
-        if Utc::now().signed_duration_since(self.cached_time) > chrono::Duration::hours(1) {
-            self.cached = Some(self.events_json().await?);
-            self.cached_time = Utc::now();
+    /// Refetches the sources that are due and waits for them, as `run` does in the background.
+    /// Returns the first error, if any source failed.
+    #[cfg(test)]
+    async fn refresh_due(&mut self) -> Option<EventListError> {
+        let (done, mut results) = mpsc::unbounded_channel();
+        self.start_due(&done);
+        drop(done);
+
+        let mut failed = None;
+        while let Some(refreshed) = results.recv().await {
+            if let Err(e) = &refreshed.result {
+                failed.get_or_insert(e.clone());
+            }
+            self.record(refreshed);
+        }
+        failed
+    }
+
+    /// Drops events that show up in more than one of `lists`, each the events of the source
+    /// at its index. The version from the source with the highest precedence is kept.
+    fn dedup(&self, mut lists: Vec<(usize, Arc<Vec<Event>>)>) -> Vec<(usize, Arc<Vec<Event>>)> {
+        let Some(options) = &self.dedup else {
+            return lists;
+        };
+
+        // Stable, so ties go to the source listed first
+        let mut order: Vec<_> = (0..lists.len()).collect();
+        order.sort_by_key(|&i| std::cmp::Reverse(self.sources[lists[i].0].settings.precedence));
+
+        let slices: Vec<_> = order.iter().map(|&i| lists[i].1.as_slice()).collect();
+        let deduped = dedup::dedup(&slices, options);
+
+        for (&i, events) in order.iter().zip(deduped) {
+            lists[i].1 = Arc::new(events);
         }
 
-        if self.cached.is_none() {
-            self.cached = Some(self.events_json().await?);
-            self.cached_time = Utc::now();
+        lists
+    }
+
+    /// The current events and status of every source. The first error of the sources is only
+    /// kept if no source has events to serve instead.
+    fn snapshot(&self) -> Snapshot {
+        let cached = (0..self.sources.len())
+            .filter_map(|i| Some((i, self.sources[i].cached.clone()?)))
+            .collect();
+        let events: Vec<_> = self
+            .dedup(cached)
+            .into_iter()
+            .map(|(i, events)| (self.sources[i].settings.name.clone(), events))
+            .collect();
+
+        // Sources still on their first query don't hold back those that have answered
+        let loaded =
+            !events.is_empty() || self.sources.iter().all(|s| s.status.latency_ms.is_some());
+        let error = self.sources.iter().find_map(|s| s.error.clone());
+
+        Snapshot {
+            loaded,
+            error: error.filter(|_| events.is_empty()),
+            events,
+            sources: self.sources.iter().map(|s| s.status.clone()).collect(),
         }
+    }
+
+    /// Refreshes each source on its own schedule, publishing a new `Snapshot` after every
+    /// refresh, reload or write. Returns once nothing is listening for snapshots anymore.
+    pub async fn run(
+        mut self,
+        snapshots: watch::Sender<Arc<Snapshot>>,
+        mut commands: mpsc::Receiver<Command>,
+    ) {
+        let (done, mut refreshed) = mpsc::unbounded_channel();
+
+        loop {
+            self.start_due(&done);
 
-        Ok(self.cached.as_ref().expect("Something is cached"))
+            if snapshots.send(Arc::new(self.snapshot())).is_err() {
+                return;
+            }
+
+            // Without sources there is nothing to refresh until a reload adds some
+            let wait = match self
+                .sources
+                .iter()
+                .filter(|s| !s.refreshing)
+                .map(|s| s.status.next_refresh)
+                .min()
+            {
+                Some(next) => next
+                    .signed_duration_since(Utc::now())
+                    .to_std()
+                    .unwrap_or_default(),
+                None => std::time::Duration::from_secs(24 * 60 * 60),
+            };
+
+            tokio::select! {
+                _ = tokio::time::sleep(wait) => {}
+                Some(result) = refreshed.recv() => self.record(result),
+                Some(command) = commands.recv() => match command {
+                    Command::Reload(reply) => {
+                        let _ = reply.send(self.reload().await);
+                    }
+                    Command::Write { source, write, reply } => {
+                        let _ = reply.send(self.write(source, *write).await);
+                    }
+                    Command::SignIns(reply) => {
+                        let _ = reply.send(self.sign_ins());
+                    }
+                },
+                _ = snapshots.closed() => return,
+            }
+        }
     }
 }
 
 #[async_trait::async_trait]
 impl EventList for EventManager {
     async fn init(&mut self) {
-        futures::future::join_all(self.streams.iter_mut().map(|s| s.init())).await;
+        // Sources are only shared with their refresh tasks once the manager runs
+        futures::future::join_all(
+            self.sources
+                .iter_mut()
+                .filter_map(|s| Arc::get_mut(&mut s.list))
+                .map(|list| list.init()),
+        )
+        .await;
     }
 
-    async fn events(&self, after: chrono::DateTime<chrono::Local>) -> Vec<Event> {
-        futures::future::join_all(self.streams.iter().map(|s| s.events(after)))
+    fn name(&self) -> &str {
+        "EventManager"
+    }
+
+    /// Queries all the sources, ignoring the cache.
+    /// Only if every source failed is the first error returned.
+    async fn events(
+        &self,
+        after: chrono::DateTime<chrono::Local>,
+    ) -> Result<Vec<Event>, EventListError> {
+        let all: Vec<_> = (0..self.sources.len()).collect();
+        let mut out = Vec::new();
+        let mut failed = None;
+
+        for (i, result, _) in self.query_sources(after, &all).await {
+            match result {
+                Ok(events) => out.push((i, Arc::new(events))),
+                Err(e) => {
+                    failed.get_or_insert(e);
+                }
+            }
+        }
+
+        match failed {
+            Some(e) if out.is_empty() => Err(e),
+            _ => Ok(self
+                .dedup(out)
+                .iter()
+                .flat_map(|(_, events)| events.iter().cloned())
+                .collect()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(name: &str) -> SourceSettings {
+        SourceSettings {
+            name: name.to_string(),
+            refresh: Duration::minutes(60),
+            color: None,
+            class: None,
+            precedence: 0,
+        }
+    }
+
+    /// Always returns the same events
+    struct Fixed(Vec<Event>);
+
+    #[async_trait::async_trait]
+    impl EventList for Fixed {
+        async fn init(&mut self) {}
+
+        fn name(&self) -> &str {
+            "Fixed"
+        }
+
+        async fn events(&self, _after: DateTime<Local>) -> Result<Vec<Event>, EventListError> {
+            Ok(self.0.clone())
+        }
+    }
+
+    /// Doesn't answer for an hour
+    struct Slow;
+
+    #[async_trait::async_trait]
+    impl EventList for Slow {
+        async fn init(&mut self) {}
+
+        fn name(&self) -> &str {
+            "Slow"
+        }
+
+        async fn events(&self, _after: DateTime<Local>) -> Result<Vec<Event>, EventListError> {
+            tokio::time::sleep(std::time::Duration::from_secs(60 * 60)).await;
+            Ok(Vec::new())
+        }
+    }
+
+    #[tokio::test]
+    async fn slow_sources_hold_back_nothing() {
+        let event = Event {
+            title: "Exam".to_string(),
+            start: Some(Local::now().into()),
+            ..Default::default()
+        };
+        let mut manager = EventManager::new();
+        manager.add(Slow, settings("Slow"));
+        manager.add(Fixed(vec![event]), settings("School"));
+
+        let (snapshots, mut snapshot) = watch::channel(Arc::new(Snapshot::default()));
+        let (handle, commands) = ManagerHandle::new();
+        tokio::spawn(manager.run(snapshots, commands));
+        let second = std::time::Duration::from_secs(1);
+
+        // The other source is served while the slow one is still being queried
+        let served = tokio::time::timeout(second, snapshot.wait_for(|s| s.loaded))
             .await
-            .into_iter()
-            .flatten()
-            .collect::<Vec<_>>()
+            .unwrap()
+            .unwrap()
+            .clone();
+        let names: Vec<_> = served
+            .events
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect();
+        assert_eq!(names, ["School"]);
+        assert!(served.sources[0].latency_ms.is_none());
+
+        // Commands are answered meanwhile too
+        let sign_ins = tokio::time::timeout(second, handle.sign_ins()).await;
+        assert!(sign_ins.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn class_is_set_however_events_are_queried() {
+        let event = Event {
+            title: "Exam".to_string(),
+            class: Some("imported".to_string()),
+            start: Some(Local::now().into()),
+            ..Default::default()
+        };
+        let mut manager = EventManager::new();
+        manager.add(
+            Fixed(vec![event]),
+            SourceSettings {
+                class: Some("school".to_string()),
+                ..settings("School")
+            },
+        );
+
+        // As for --once
+        let events = manager.events(Local::now()).await.unwrap();
+        assert_eq!(events[0].class.as_deref(), Some("school"));
+        assert_eq!(events[0].source.as_deref(), Some("School"));
+
+        // As for the served snapshot
+        manager.refresh_due().await;
+        let cached = manager.sources[0].cached.as_ref().unwrap();
+        assert_eq!(cached[0].class.as_deref(), Some("school"));
+    }
+
+    /// A config of three local files, the second with `color`
+    fn config_with(color: &str) -> String {
+        let fixtures = concat!(env!("CARGO_MANIFEST_DIR"), "/../event_list/tests/fixtures");
+        ["Weekly", "Monthly", "Yearly"]
+            .iter()
+            .map(|name| {
+                let color = if *name == "Monthly" { color } else { "#000000" };
+                format!(
+                    "[[sources]]\nname = \"{name}\"\nkind = \"ical-file\"\n\
+                     path = \"{fixtures}/{}.ics\"\ncolor = \"{color}\"\n",
+                    name.to_lowercase()
+                )
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn reload_only_rebuilds_changed_sources() {
+        let file = std::env::temp_dir().join(format!("event_manager_{}.toml", std::process::id()));
+        let file = file.to_string_lossy().into_owned();
+        std::fs::write(&file, config_with("#ff0000")).unwrap();
+
+        let mut manager = EventManager::from_config(&file).await.unwrap();
+        manager.refresh_due().await;
+        let cached: Vec<_> = manager
+            .sources
+            .iter()
+            .map(|s| s.cached.clone().unwrap())
+            .collect();
+
+        std::fs::write(&file, config_with("#00ff00")).unwrap();
+        let summary = manager.reload().await.unwrap();
+        assert!(summary.ends_with("2 unchanged, added [], changed [Monthly], removed []"));
+
+        let sources = &manager.sources;
+        assert!(Arc::ptr_eq(sources[0].cached.as_ref().unwrap(), &cached[0]));
+        assert!(sources[1].cached.is_none());
+        assert_eq!(sources[1].settings.color.as_deref(), Some("#00ff00"));
+        assert!(Arc::ptr_eq(sources[2].cached.as_ref().unwrap(), &cached[2]));
+
+        // An invalid file keeps every source as it was
+        std::fs::write(&file, "[[sources]]\nname = \"Broken\"").unwrap();
+        let result = manager.reload().await;
+        std::fs::remove_file(&file).unwrap();
+
+        assert!(result.is_err());
+        let names: Vec<_> = manager
+            .sources
+            .iter()
+            .map(|s| s.settings.name.as_str())
+            .collect();
+        assert_eq!(names, ["Weekly", "Monthly", "Yearly"]);
+        assert_eq!(
+            manager.sources[1].settings.color.as_deref(),
+            Some("#00ff00")
+        );
+        assert!(Arc::ptr_eq(
+            manager.sources[0].cached.as_ref().unwrap(),
+            &cached[0]
+        ));
+    }
+
+    #[tokio::test]
+    async fn errors_hide_feed_tokens() {
+        // Nothing listens on the port once the listener is dropped
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let url = format!("http://127.0.0.1:{port}/feed.ics?token=SECRET123");
+
+        let mut manager = EventManager::new();
+        manager.add(RemoteList::new(&url), settings("Feed"));
+        let error = manager.refresh_due().await.unwrap();
+
+        let status = &manager.sources[0].status;
+        assert_eq!(status.state, SourceState::Failed);
+        assert!(!status.last_error.as_ref().unwrap().contains("SECRET123"));
+        assert!(!error.to_string().contains("SECRET123"));
     }
 }
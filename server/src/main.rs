@@ -1,26 +1,37 @@
-#![windows_subsystem = "windows"]
+#![cfg_attr(all(windows, feature = "tray"), windows_subsystem = "windows")]
 
+#[cfg(all(windows, feature = "tray"))]
 mod tray_icon;
 
-use std::{convert::Infallible, net::SocketAddr, thread};
+use std::{collections::HashMap, convert::Infallible, net::SocketAddr, sync::Arc};
+
+mod cli;
+use clap::Parser;
+use cli::{Cli, Command};
 
 mod event_manager;
-use event_manager::EventManager;
+use event_manager::{EventManager, ManagerHandle, Snapshot, Write, WriteError};
+use event_list::{Days, Event, EventList, EventListError};
 
 mod config;
+use config::ManagerConfig;
+
+mod query;
+use query::EventQuery;
+
+mod reload;
+
+use percent_encoding::{percent_decode_str, utf8_percent_encode, NON_ALPHANUMERIC};
 
 use hyper::{
+    header::{CONTENT_TYPE, HOST},
     service::{make_service_fn, service_fn},
-    Body, Request, Response, Server,
+    Body, Method, Request, Response, Server,
 };
-use tokio::sync::Mutex;
-
-static MANAGER: Mutex<EventManager> = Mutex::const_new(EventManager::new());
+use tokio::sync::{oneshot, watch, Mutex};
 
 static LOG_LIST: Mutex<Vec<String>> = Mutex::const_new(Vec::new());
 
-static CONFIG_FILE: &str = ".manager_config.toml";
-
 use log::info;
 
 struct Logger;
@@ -47,28 +58,139 @@ impl log::Log for Logger {
 
 #[tokio::main(worker_threads = 2)]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    log::set_max_level(log::LevelFilter::Info);
+    let cli = Cli::parse();
+
+    log::set_max_level(cli.log_level);
     match log::set_logger(&Logger) {
         Ok(_) => {}
         Err(e) => {eprintln!("Failed to start logging. Error: {e:?}")}
     };
 
-    *MANAGER.lock().await = EventManager::from_config(CONFIG_FILE).await?;
+    if let Some(Command::CheckConfig) = cli.command {
+        check_config(&cli.config).await;
+    }
+
+    let manager = EventManager::from_config(&cli.config).await?;
+
+    if cli.once {
+        let events = manager.events(chrono::Local::now()).await?;
+        println!("{}", Days::from_slice(&events).to_json()?);
+        return Ok(());
+    }
+
+    let (snapshot_send, snapshot_recv) = watch::channel(Arc::new(Snapshot::default()));
+    let (handle, command_recv) = ManagerHandle::new();
+    tokio::spawn(manager.run(snapshot_send, command_recv));
 
-    let (shutdown_send, shutdown_recv) = tokio::sync::oneshot::channel();
+    let _watcher = reload::watch_file(&cli.config, handle.clone())
+        .map_err(|e| log::error!("Not watching {} for changes: {e}", cli.config))
+        .ok();
+    #[cfg(unix)]
+    tokio::spawn(reload::reload_on_hangup(handle.clone()));
 
-    thread::spawn(|| tray_icon::start_icon(shutdown_send));
+    let (shutdown_send, shutdown_recv) = oneshot::channel();
 
-    run_server(shutdown_recv).await?;
+    #[cfg(all(windows, feature = "tray"))]
+    if !cli.no_tray {
+        let addr = cli.bind;
+        std::thread::spawn(move || tray_icon::start_icon(shutdown_send, addr));
+    } else {
+        tokio::spawn(wait_for_signal(shutdown_send));
+    }
+    #[cfg(not(all(windows, feature = "tray")))]
+    tokio::spawn(wait_for_signal(shutdown_send));
+
+    run_server(cli.bind, snapshot_recv, handle, shutdown_recv).await?;
 
     Ok(())
 }
 
-/// Starts the server and awaits it
-async fn run_server(shutdown_recv: tokio::sync::oneshot::Receiver<()>) -> Result<(), hyper::Error> {
-    info!("Started server");
-    let addr = SocketAddr::from(([127, 0, 0, 1], 3001));
-    let service = make_service_fn(|_| async { Ok::<_, Infallible>(service_fn(serve_events)) });
+/// Parses the config at `file` and queries each source, printing the result of each.
+/// Exits with an error code if the config is invalid or any source fails.
+async fn check_config(file: &str) -> ! {
+    let config = match std::fs::read_to_string(file) {
+        Ok(s) => ManagerConfig::from_toml(&s).map_err(|e| e.to_string()),
+        Err(e) => Err(e.to_string()),
+    };
+    let config = match config {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{file}: {e}");
+            std::process::exit(1);
+        }
+    };
+
+    if config.migrated {
+        println!("note: {file} uses the old remotes/google_calendar format, consider [[sources]]");
+    }
+    for source in config.sources.iter().filter(|s| !s.enabled) {
+        println!("off     {}", source.name);
+    }
+
+    let manager = EventManager::from_manager_config(&config).await;
+
+    let mut failed = false;
+    for (name, result) in manager.check_sources().await {
+        match result {
+            Ok(events) => println!("ok      {name} ({} events)", events.len()),
+            Err(e) => {
+                println!("failed  {name}: {e}");
+                failed = true;
+            }
+        }
+    }
+
+    std::process::exit(if failed { 1 } else { 0 });
+}
+
+/// Sends on `shutdown_send` once the process is asked to stop with Ctrl-C or SIGTERM
+async fn wait_for_signal(shutdown_send: oneshot::Sender<()>) {
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                log::error!("Failed to listen for SIGTERM. Error: {e:?}");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => {
+            if let Err(e) = result {
+                log::error!("Failed to listen for Ctrl-C. Error: {e:?}");
+                std::future::pending::<()>().await;
+            }
+        }
+        _ = terminate => {}
+    }
+
+    info!("Received shutdown signal");
+    let _ = shutdown_send.send(());
+}
+
+/// Starts the server and awaits it, answering requests from the latest snapshot
+async fn run_server(
+    addr: SocketAddr,
+    snapshots: watch::Receiver<Arc<Snapshot>>,
+    manager: ManagerHandle,
+    shutdown_recv: oneshot::Receiver<()>,
+) -> Result<(), hyper::Error> {
+    info!("Started server on {addr}");
+    let service = make_service_fn(move |_| {
+        let snapshots = snapshots.clone();
+        let manager = manager.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                serve_events(req, snapshots.borrow().clone(), manager.clone())
+            }))
+        }
+    });
 
     Server::bind(&addr)
         .serve(service)
@@ -81,31 +203,316 @@ async fn run_server(shutdown_recv: tokio::sync::oneshot::Receiver<()>) -> Result
     Ok(())
 }
 
-async fn serve_events(req: Request<Body>) -> Result<Response<Body>, Infallible> {
+/// Answers a request for events with `render`, or the reason there are none
+fn events_response(
+    snapshot: &Snapshot,
+    query: Option<&str>,
+    content_type: &str,
+    render: fn(&Snapshot, &EventQuery) -> Result<String, event_list::EventListError>,
+) -> Response<Body> {
+    let (status, content_type, body) = match EventQuery::parse(query) {
+        Err(e) => (400, "text/plain", e),
+        Ok(_) if !snapshot.loaded => (503, "text/plain", "Events are still loading".to_string()),
+        Ok(query) => match render(snapshot, &query) {
+            Ok(body) => (200, content_type, body),
+            Err(e) => (502, "text/plain", e.to_string()),
+        },
+    };
+
+    Response::builder()
+        .status(status)
+        .header("Access-Control-Allow-Origin", "*")
+        .header("Content-Type", content_type)
+        .body(Body::from(body))
+        .unwrap()
+}
+
+/// Header that requests changing anything have to send. Browsers only send it to another site
+/// after a CORS preflight, which this server never allows, so web pages can't make changes.
+const CHANGE_HEADER: &str = "X-Requested-With";
+
+/// The 403 for a request that changes something without `CHANGE_HEADER`, `None` if it has it
+fn reject_cross_site(req: &Request<Body>) -> Option<Response<Body>> {
+    if req.headers().contains_key(CHANGE_HEADER) {
+        return None;
+    }
+
+    Some(
+        Response::builder()
+            .status(403)
+            .body(Body::from(format!(
+                "Missing {CHANGE_HEADER} header, changes need it to tell them from cross-site \
+                 requests"
+            )))
+            .unwrap(),
+    )
+}
+
+/// Reads the json `Event` in the body of `req`, or the status and reason it can't be read
+async fn read_event(req: Request<Body>) -> Result<Event, (u16, String)> {
+    let json = req
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|t| t.to_str().ok())
+        .and_then(|t| t.split(';').next())
+        .is_some_and(|t| t.trim().eq_ignore_ascii_case("application/json"));
+    if !json {
+        return Err((415, "Events must be sent as application/json".to_string()));
+    }
+
+    let body = hyper::body::to_bytes(req.into_body())
+        .await
+        .map_err(|e| (400, e.to_string()))?;
+    let event: Event =
+        serde_json::from_slice(&body).map_err(|e| (400, format!("Invalid event: {e}")))?;
+
+    if event.start.is_none() {
+        return Err((400, "Invalid event: missing field `start`".to_string()));
+    }
+
+    Ok(event)
+}
+
+/// Creates, replaces or deletes an event with `POST /events`, `PUT /events/{id}` or
+/// `DELETE /events/{id}`. `?source=` names the source to write to.
+async fn write_response(req: Request<Body>, manager: &ManagerHandle) -> Response<Body> {
+    if let Some(rejected) = reject_cross_site(&req) {
+        return rejected;
+    }
+
+    let id = req
+        .uri()
+        .path()
+        .strip_prefix("/events/")
+        .filter(|id| !id.is_empty())
+        .map(|id| percent_decode_str(id).decode_utf8_lossy().into_owned());
+    let source = form_urlencoded::parse(req.uri().query().unwrap_or_default().as_bytes())
+        .find(|(key, _)| key == "source")
+        .map(|(_, value)| value.into_owned());
+
+    let method = req.method().clone();
+    let write = match (&method, id) {
+        (&Method::POST, None) => read_event(req).await.map(Write::Create),
+        (&Method::PUT, Some(id)) => read_event(req).await.map(|e| Write::Update(id, e)),
+        (&Method::DELETE, Some(id)) => Ok(Write::Delete(id)),
+        (_, id) => {
+            return Response::builder()
+                .status(405)
+                .header("Allow", if id.is_some() { "PUT, DELETE" } else { "POST" })
+                .body(Body::from(""))
+                .unwrap()
+        }
+    };
+    let write = match write {
+        Ok(write) => write,
+        Err((status, e)) => {
+            return Response::builder()
+                .status(status)
+                .body(Body::from(e))
+                .unwrap()
+        }
+    };
+
+    match manager.write(source, write).await {
+        Ok(id) if method == Method::POST => Response::builder()
+            .status(201)
+            .header("Content-Type", "application/json")
+            .header(
+                "Location",
+                format!("/events/{}", utf8_percent_encode(&id, NON_ALPHANUMERIC)),
+            )
+            .body(Body::from(serde_json::json!({ "id": id }).to_string()))
+            .unwrap(),
+        Ok(_) => Response::builder()
+            .status(204)
+            .body(Body::from(""))
+            .unwrap(),
+        Err(e) => {
+            let status = match &e {
+                WriteError::NoSource => 400,
+                WriteError::UnknownSource(_) | WriteError::Source(EventListError::NotFound(_)) => {
+                    404
+                }
+                WriteError::ReadOnly(_) => 405,
+                WriteError::Source(EventListError::Unauthenticated(_)) => 403,
+                WriteError::Source(EventListError::Timeout) => 504,
+                WriteError::Source(EventListError::Io(_)) => 500,
+                WriteError::Source(_) => 502,
+                WriteError::Stopped => 503,
+            };
+
+            Response::builder()
+                .status(status)
+                .body(Body::from(e.to_string()))
+                .unwrap()
+        }
+    }
+}
+
+/// Signs in to a source with OAuth. `GET /auth/google/start` sends the user to Google, which
+/// sends them back to `/auth/google/callback`. Without a browser, `GET /auth/google/link` answers
+/// with the address to open on another device instead, and `POST /auth/google/finish` takes the
+/// address Google sent that device back to. `?source=` picks the source if several need signing
+/// in.
+async fn auth_response(req: Request<Body>, manager: &ManagerHandle) -> Response<Body> {
+    let text = |status, body: &str| {
+        Response::builder()
+            .status(status)
+            .header("Content-Type", "text/plain; charset=utf-8")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    };
+    let path = req.uri().path().to_string();
+    // Google sends the user back to the address they reached the server at
+    let host = req
+        .headers()
+        .get(HOST)
+        .and_then(|h| h.to_str().ok())
+        .map(str::to_string);
+    let allowed = match path.as_str() {
+        "/auth/google/start" | "/auth/google/callback" | "/auth/google/link" => Method::GET,
+        "/auth/google/finish" => Method::POST,
+        _ => return text(404, ""),
+    };
+    if req.method() != allowed {
+        return Response::builder()
+            .status(405)
+            .header("Allow", allowed.as_str())
+            .body(Body::from(""))
+            .unwrap();
+    }
+
+    let mut query: HashMap<String, String> =
+        form_urlencoded::parse(req.uri().query().unwrap_or_default().as_bytes())
+            .into_owned()
+            .collect();
+    let sign_ins = manager.sign_ins().await;
+
+    if path == "/auth/google/finish" {
+        if let Some(rejected) = reject_cross_site(&req) {
+            return rejected;
+        }
+        let body = match hyper::body::to_bytes(req.into_body()).await {
+            Ok(body) => body,
+            Err(e) => return text(400, &e.to_string()),
+        };
+        // The whole address the browser was sent to, or only its query
+        let pasted = String::from_utf8_lossy(&body);
+        let pasted = pasted.trim();
+        let pasted = pasted.split_once('?').map_or(pasted, |(_, query)| query);
+        query = form_urlencoded::parse(pasted.as_bytes())
+            .into_owned()
+            .collect();
+    }
+
+    if path == "/auth/google/callback" || path == "/auth/google/finish" {
+        if let Some(error) = query.get("error") {
+            return text(400, &format!("Sign in failed: {error}"));
+        }
+        let (Some(state), Some(code)) = (query.get("state"), query.get("code")) else {
+            return text(400, "Missing state or code");
+        };
+        let Some((name, sign_in)) = sign_ins.iter().find(|(_, s)| s.expects(state)) else {
+            return text(400, "Unknown or expired sign in, start again");
+        };
+
+        return match sign_in.finish(state, code).await {
+            Ok(()) if path == "/auth/google/finish" => text(200, &format!("Signed in to {name}")),
+            Ok(()) => text(200, &format!("Signed in to {name}, you can close this page")),
+            Err(e) => text(502, &format!("Sign in failed: {e}")),
+        };
+    }
+
+    let source = match (query.get("source"), &sign_ins[..]) {
+        (Some(name), _) => sign_ins.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)),
+        (None, [only]) => Some(only),
+        (None, []) => None,
+        (None, _) => return text(400, "Several sources need signing in, pick one with ?source="),
+    };
+    let Some((_, sign_in)) = source else {
+        return text(404, "No source to sign in to");
+    };
+
+    let Some(host) = host else {
+        return text(400, "Missing Host header");
+    };
+    let redirect_uri = format!("http://{host}/auth/google/callback");
+    match sign_in.start(&redirect_uri) {
+        Ok(url) if path == "/auth/google/link" => text(
+            200,
+            &format!(
+                "Open this address in a browser on any device and sign in:\n\n{url}\n\n\
+                 Google then sends the browser to {redirect_uri}, which won't load there. Copy \
+                 the address from its address bar and send it here within 10 minutes:\n\n\
+                 curl -H '{CHANGE_HEADER}: curl' --data-binary '<address>' \
+                 http://{host}/auth/google/finish\n"
+            ),
+        ),
+        Ok(url) => Response::builder()
+            .status(302)
+            .header("Location", url)
+            .body(Body::from(""))
+            .unwrap(),
+        Err(e) => text(500, &e.to_string()),
+    }
+}
+
+async fn serve_events(
+    req: Request<Body>,
+    snapshot: Arc<Snapshot>,
+    manager: ManagerHandle,
+) -> Result<Response<Body>, Infallible> {
     info!("Request: {req:?}");
 
     let resp = match req.uri().path() {
-        "/" => {
-            let body = MANAGER
-                .lock()
-                .await
-                .cached_json()
-                .await
-                .unwrap()
-                .to_string();
-            
+        "/" => events_response(
+            &snapshot,
+            req.uri().query(),
+            "application/json",
+            Snapshot::query_json,
+        ),
+        "/calendar.ics" => events_response(
+            &snapshot,
+            req.uri().query(),
+            "text/calendar; charset=utf-8",
+            Snapshot::query_ics,
+        ),
+        "/sources" => {
             Response::builder()
                 .header("Access-Control-Allow-Origin", "*")
-                .body(Body::from(body))
+                .header("Content-Type", "application/json")
+                .body(Body::from(serde_json::to_string(snapshot.sources()).unwrap()))
                 .unwrap()
         }
+        "/admin/reload" if req.method() != Method::POST => Response::builder()
+            .status(405)
+            .header("Allow", "POST")
+            .body(Body::from(""))
+            .unwrap(),
+        "/admin/reload" => match reject_cross_site(&req) {
+            Some(rejected) => rejected,
+            None => {
+                let (status, body) = match manager.reload().await {
+                    Ok(summary) => (200, summary),
+                    Err(e) => (400, e),
+                };
+
+                Response::builder()
+                    .status(status)
+                    .body(Body::from(body))
+                    .unwrap()
+            }
+        },
+        path if path.starts_with("/auth/") => auth_response(req, &manager).await,
+        "/events" => write_response(req, &manager).await,
+        path if path.starts_with("/events/") => write_response(req, &manager).await,
         "/log" => Response::builder()
             .header("Access-Control-Allow-Origin", "*")
             .body(Body::from(LOG_LIST.lock().await.join("\n")))
             .unwrap(),
         "/viewer" => Response::builder()
             .header("Access-Control-Allow-Origin", "*")
-            .body(Body::from(include_str!("..\\..\\viewer\\index.html")))
+            .body(Body::from(include_str!("../../viewer/index.html")))
             .unwrap(),
         _ => Response::builder()
             .status(404)
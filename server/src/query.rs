@@ -0,0 +1,292 @@
+use chrono::{DateTime, Duration, Local, NaiveDate, TimeZone};
+
+use event_list::Event;
+
+/// Longest range `?days=` may ask for
+const MAX_DAYS: i64 = 366;
+
+/// Filters for the events endpoint, parsed from its query string
+#[derive(Clone, Debug, Default)]
+pub struct EventQuery {
+    /// Only events that end at or after this, defaults to now
+    pub from: Option<DateTime<Local>>,
+    /// Only events that start before this
+    pub to: Option<DateTime<Local>>,
+    /// Only events from these sources, compared case-insensitively
+    pub sources: Vec<String>,
+    /// Only events whose title, description or location contains this, lowercased
+    pub text: Option<String>,
+    /// Only events of this class, lowercased
+    pub class: Option<String>,
+    /// Also events that were cancelled, which are left out otherwise
+    pub cancelled: bool,
+}
+
+/// Parses an RFC 3339 date-time or a `YYYY-MM-DD` date, which means local midnight
+fn parse_time(key: &str, value: &str) -> Result<DateTime<Local>, String> {
+    // An unencoded `+` in the offset arrives as a space
+    let value = match value.rsplit_once(' ') {
+        Some((time, offset))
+            if offset.len() == 5 && offset.chars().filter(char::is_ascii_digit).count() == 4 =>
+        {
+            format!("{time}+{offset}")
+        }
+        _ => value.to_string(),
+    };
+    let value = value.as_str();
+
+    if let Ok(d) = DateTime::parse_from_rfc3339(value) {
+        return Ok(d.with_timezone(&Local));
+    }
+
+    NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .ok()
+        .and_then(|d| {
+            Local
+                .from_local_datetime(&d.and_hms_opt(0, 0, 0).expect("Valid time"))
+                .earliest()
+        })
+        .ok_or_else(|| format!("{key} must be an RFC 3339 date-time or a YYYY-MM-DD date"))
+}
+
+impl EventQuery {
+    /// Parses and validates the query string of a request
+    pub fn parse(query: Option<&str>) -> Result<Self, String> {
+        let mut out = Self::default();
+        let mut days = None;
+
+        for (key, value) in form_urlencoded::parse(query.unwrap_or_default().as_bytes()) {
+            if value.is_empty() {
+                return Err(format!("{key} needs a value"));
+            }
+
+            match key.as_ref() {
+                "from" => out.from = Some(parse_time("from", &value)?),
+                "to" => out.to = Some(parse_time("to", &value)?),
+                "days" => {
+                    days = Some(
+                        value
+                            .parse::<i64>()
+                            .ok()
+                            .filter(|d| (1..=MAX_DAYS).contains(d))
+                            .ok_or_else(|| format!("days must be between 1 and {MAX_DAYS}"))?,
+                    )
+                }
+                "source" => out
+                    .sources
+                    .extend(value.split(',').map(|s| s.trim().to_lowercase())),
+                "q" => out.text = Some(value.to_lowercase()),
+                "class" => out.class = Some(value.to_lowercase()),
+                "cancelled" => {
+                    out.cancelled = value
+                        .parse()
+                        .map_err(|_| "cancelled must be true or false".to_string())?
+                }
+                k => return Err(format!("Unknown parameter {k}")),
+            }
+        }
+
+        if let Some(days) = days {
+            if out.to.is_some() {
+                return Err("to and days can't be used together".to_string());
+            }
+            out.to = Some(out.from.unwrap_or_else(Local::now) + Duration::days(days));
+        }
+
+        if let (Some(from), Some(to)) = (out.from, out.to) {
+            if from > to {
+                return Err("from must be before to".to_string());
+            }
+        }
+
+        Ok(out)
+    }
+
+    /// Whether `event`, which came from the source called `source`, passes every filter
+    pub fn matches(&self, source: &str, event: &Event) -> bool {
+        let from = self.from.unwrap_or_else(Local::now);
+        let Some(end) = event.end.or(event.start) else {
+            return false;
+        };
+
+        if end.instant() < from {
+            return false;
+        }
+
+        if let (Some(to), Some(start)) = (self.to, event.start) {
+            if start.instant() >= to {
+                return false;
+            }
+        }
+
+        if !self.sources.is_empty() && !self.sources.contains(&source.to_lowercase()) {
+            return false;
+        }
+
+        if let Some(text) = &self.text {
+            if !event.title.to_lowercase().contains(text)
+                && !event.desc.to_lowercase().contains(text)
+                && !event
+                    .location
+                    .as_ref()
+                    .is_some_and(|l| l.to_lowercase().contains(text))
+            {
+                return false;
+            }
+        }
+
+        if event.is_cancelled() && !self.cancelled {
+            return false;
+        }
+
+        if let Some(class) = &self.class {
+            if event
+                .class
+                .as_ref()
+                .is_none_or(|c| c.to_lowercase() != *class)
+            {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use event_list::EventStatus;
+
+    fn at(day: u32, hour: u32) -> DateTime<Local> {
+        Local.with_ymd_and_hms(2024, 3, day, hour, 0, 0).unwrap()
+    }
+
+    /// A one hour event starting at `hour` on March `day`
+    fn event(title: &str, day: u32, hour: u32) -> Event {
+        Event {
+            title: title.to_string(),
+            desc: String::new(),
+            start: Some(at(day, hour).into()),
+            end: Some(at(day, hour + 1).into()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn parses_every_filter() {
+        let query = EventQuery::parse(Some(
+            "from=2024-03-01&to=2024-03-08T12:00:00Z&source=School,%20Work&q=Exam&class=Test\
+             &cancelled=true",
+        ))
+        .unwrap();
+
+        assert_eq!(query.from, Some(at(1, 0)));
+        assert_eq!(
+            query.to,
+            Some(
+                DateTime::parse_from_rfc3339("2024-03-08T12:00:00Z")
+                    .unwrap()
+                    .into()
+            )
+        );
+        assert_eq!(query.sources, ["school", "work"]);
+        assert_eq!(query.text.as_deref(), Some("exam"));
+        assert_eq!(query.class.as_deref(), Some("test"));
+        assert!(query.cancelled);
+    }
+
+    #[test]
+    fn accepts_offsets_with_unencoded_plus() {
+        let nine = DateTime::parse_from_rfc3339("2024-05-01T07:00:00Z").unwrap();
+
+        for query in [
+            "from=2024-05-01T09:00:00+02:00",
+            "from=2024-05-01T09:00:00%2B02:00",
+            "from=2024-05-01 09:00:00+02:00",
+        ] {
+            assert_eq!(
+                EventQuery::parse(Some(query)).unwrap().from,
+                Some(nine.into()),
+                "{query}"
+            );
+        }
+    }
+
+    #[test]
+    fn days_counts_from_from() {
+        let query = EventQuery::parse(Some("from=2024-03-01&days=7")).unwrap();
+        assert_eq!(query.to, Some(at(8, 0)));
+    }
+
+    #[test]
+    fn rejects_bad_queries() {
+        for (query, error) in [
+            ("days=7&to=2024-03-08", "to and days can't be used together"),
+            ("from=2024-03-08&to=2024-03-01", "from must be before to"),
+            ("days=0", "days must be between 1 and 366"),
+            ("days=367", "days must be between 1 and 366"),
+            (
+                "from=yesterday",
+                "from must be an RFC 3339 date-time or a YYYY-MM-DD date",
+            ),
+            ("cancelled=yes", "cancelled must be true or false"),
+            ("source=", "source needs a value"),
+            ("limit=10", "Unknown parameter limit"),
+        ] {
+            assert_eq!(
+                EventQuery::parse(Some(query)).unwrap_err(),
+                error,
+                "{query}"
+            );
+        }
+    }
+
+    #[test]
+    fn filters_by_range() {
+        let query = EventQuery {
+            from: Some(at(2, 0)),
+            to: Some(at(3, 0)),
+            ..Default::default()
+        };
+
+        assert!(!query.matches("School", &event("Before", 1, 10)));
+        assert!(query.matches("School", &event("During", 2, 10)));
+        assert!(!query.matches("School", &event("After", 3, 10)));
+        // Still going at `from`
+        let mut overnight = event("Overnight", 1, 22);
+        overnight.end = Some(at(2, 2).into());
+        assert!(query.matches("School", &overnight));
+    }
+
+    #[test]
+    fn filters_by_source_text_and_class() {
+        let query = EventQuery::parse(Some("from=2024-03-01&source=school,work&q=exam")).unwrap();
+        let mut exam = event("Math exam", 2, 10);
+        assert!(query.matches("School", &exam));
+        assert!(query.matches("WORK", &exam));
+        assert!(!query.matches("Home", &exam));
+        assert!(!query.matches("School", &event("Lunch", 2, 12)));
+
+        let mut in_room = event("Lunch", 2, 12);
+        in_room.location = Some("Exam hall".to_string());
+        assert!(query.matches("School", &in_room));
+
+        let query = EventQuery::parse(Some("from=2024-03-01&class=test")).unwrap();
+        assert!(!query.matches("School", &exam));
+        exam.class = Some("Test".to_string());
+        assert!(query.matches("School", &exam));
+    }
+
+    #[test]
+    fn hides_cancelled_events() {
+        let mut cancelled = event("Trip", 2, 10);
+        cancelled.status = Some(EventStatus::Cancelled);
+
+        let query = EventQuery::parse(Some("from=2024-03-01")).unwrap();
+        assert!(!query.matches("School", &cancelled));
+
+        let query = EventQuery::parse(Some("from=2024-03-01&cancelled=true")).unwrap();
+        assert!(query.matches("School", &cancelled));
+    }
+}
@@ -0,0 +1,83 @@
+use std::{path::Path, time::Duration};
+
+use log::{error, info};
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use tokio::sync::mpsc;
+
+use crate::event_manager::ManagerHandle;
+
+/// How long to wait for more changes before reloading, editors often save in several steps
+const DEBOUNCE: Duration = Duration::from_millis(300);
+
+/// Reloads and logs the outcome, the manager already logs the details
+async fn reload_logged(manager: &ManagerHandle, reason: &str) {
+    info!("Reloading config after {reason}");
+    if let Err(e) = manager.reload().await {
+        error!("Reload failed: {e}");
+    }
+}
+
+/// Reloads whenever `file` is written, created or replaced.
+/// Watching stops when the returned watcher is dropped.
+pub fn watch_file(file: &str, manager: ManagerHandle) -> notify::Result<RecommendedWatcher> {
+    let path = std::fs::canonicalize(file)?;
+    // Watch the directory, editors often replace the file instead of writing to it
+    let dir = path.parent().unwrap_or(Path::new("/")).to_path_buf();
+    let name = path.file_name().map(|n| n.to_os_string());
+
+    // notify calls back on a thread of its own, so even errors are logged from the task
+    let (send, mut recv) = mpsc::unbounded_channel();
+    let mut watcher =
+        notify::recommended_watcher(move |event: notify::Result<notify::Event>| match event {
+            Ok(event)
+                if matches!(
+                    event.kind,
+                    EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)
+                ) && event.paths.iter().any(|p| p.file_name() == name.as_deref()) =>
+            {
+                let _ = send.send(Ok(()));
+            }
+            Ok(_) => {}
+            Err(e) => {
+                let _ = send.send(Err(e));
+            }
+        })?;
+    watcher.watch(&dir, RecursiveMode::NonRecursive)?;
+
+    tokio::spawn(async move {
+        while let Some(change) = recv.recv().await {
+            if let Err(e) = change {
+                error!("Config watch error: {e}");
+                continue;
+            }
+            tokio::time::sleep(DEBOUNCE).await;
+            while let Ok(change) = recv.try_recv() {
+                if let Err(e) = change {
+                    error!("Config watch error: {e}");
+                }
+            }
+
+            reload_logged(&manager, "a config file change").await;
+        }
+    });
+
+    Ok(watcher)
+}
+
+/// Reloads every time the process gets SIGHUP
+#[cfg(unix)]
+pub async fn reload_on_hangup(manager: ManagerHandle) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Failed to listen for SIGHUP. Error: {e:?}");
+            return;
+        }
+    };
+
+    while hangup.recv().await.is_some() {
+        reload_logged(&manager, "SIGHUP").await;
+    }
+}
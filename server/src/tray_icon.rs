@@ -1,9 +1,15 @@
 use core::mem::MaybeUninit;
+use std::net::{Ipv4Addr, SocketAddr};
 use tokio::sync::oneshot;
 use trayicon::*;
 use winapi::um::winuser;
 
-pub fn start_icon(shutdown_send: oneshot::Sender<()>) {
+pub fn start_icon(shutdown_send: oneshot::Sender<()>, mut addr: SocketAddr) {
+    if addr.ip().is_unspecified() {
+        addr.set_ip(Ipv4Addr::LOCALHOST.into());
+    }
+    let viewer = format!("http://{addr}/viewer");
+
     #[derive(Copy, Clone, Eq, PartialEq, Debug)]
     enum Events {
         ClickTrayIcon,
@@ -33,7 +39,7 @@ pub fn start_icon(shutdown_send: oneshot::Sender<()>) {
         let shutdown = shutdown_send;
         for m in r { match m {
             Events::ClickTrayIcon => {
-                match open::that("http://127.0.0.1:3001/viewer") {
+                match open::that(&viewer) {
                     Ok(_) => {}
                     Err(e) => {log::error!("Failed to open viewer for reason {e:?}")}
                 };